@@ -1,11 +1,23 @@
+pub use crate::api_commands::{
+    KeyboardValue, PROTOCOL_ALPHA, PROTOCOL_BETA, PROTOCOL_GAMMA, PROTOCOL_V3,
+};
 use crate::api_commands::{
     ViaChannelId, ViaCommandId, ViaLightingValue, ViaQmkAudioValue, ViaQmkBacklightValue,
     ViaQmkLedMatrixValue, ViaQmkRgbMatrixValue, ViaQmkRgblightValue,
 };
-use crate::scan::KeyboardDeviceInfo;
+use crate::protocol::{self, Request};
+use crate::scan::{KeyboardCapabilities, KeyboardDeviceInfo, VIA_USAGE_PAGE};
+use crate::trace::{self, TraceDirection, TraceRecorder};
+use crate::transport::Transport;
+use crate::vial::{self, VialKeyboardId};
 use crate::{utils, Error, Result};
-use hidapi::HidApi;
-use std::str::FromStr;
+use hidapi::{DeviceInfo, HidApi};
+use std::ffi::CString;
+use std::io::Write;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec;
 
 #[cfg(feature = "python")]
@@ -18,10 +30,22 @@ const COMMAND_START: u8 = 0x00;
 pub const RAW_EPSIZE: usize = 32;
 pub const DATA_BUFFER_SIZE: usize = 28;
 
-pub const PROTOCOL_ALPHA: u16 = 7;
-pub const PROTOCOL_BETA: u16 = 8;
-pub const PROTOCOL_GAMMA: u16 = 9;
-pub const PROTOCOL_V3: u16 = 11;
+/// Number of bytes preceding the payload of buffer commands (command id, offset and size).
+const BUFFER_HEADER_SIZE: usize = RAW_EPSIZE - DATA_BUFFER_SIZE;
+/// Smallest supported raw HID report size.
+pub(crate) const MIN_REPORT_SIZE: usize = 8;
+/// Largest supported raw HID report size, limited by the one byte size field of buffer commands.
+pub(crate) const MAX_REPORT_SIZE: usize = u8::MAX as usize + BUFFER_HEADER_SIZE;
+
+/// Default time in milliseconds to wait for the response to a command.
+pub const DEFAULT_TIMEOUT_MS: u32 = 500;
+/// Default number of times a command is repeated after a timeout or an unexpected response.
+pub const DEFAULT_RETRIES: u8 = 2;
+/// Time in milliseconds to wait for a reply to a bootloader jump, which the firmware usually
+/// does not send.
+const BOOTLOADER_JUMP_TIMEOUT_MS: u32 = 100;
+/// Time to wait between two attempts to reopen a disconnected keyboard.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
 
 pub type Layer = u8;
 pub type Row = u8;
@@ -29,66 +53,195 @@ pub type Column = u8;
 
 #[cfg_attr(feature = "python", pyclass(get_all, set_all, from_py_object))]
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MatrixInfo {
     pub rows: u8,
     pub cols: u8,
 }
 
-#[cfg_attr(feature = "python", pyclass(from_py_object))]
+/// Settings controlling how a single command is exchanged with the keyboard.
 #[derive(Clone, Copy, Debug)]
-pub enum KeyboardValue {
-    Uptime = 0x01,
-    LayoutOptions = 0x02,
-    SwitchMatrixState = 0x03,
-    FirmwareVersion = 0x04,
-    DeviceIndication = 0x05,
+struct CommandOptions {
+    report_size: usize,
+    timeout_ms: Option<u32>,
+    retries: u8,
+    reconnect_timeout_ms: Option<u32>,
 }
 
-impl FromStr for KeyboardValue {
-    type Err = &'static str;
-
-    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
-        match s {
-            "Uptime" => Ok(KeyboardValue::Uptime),
-            "LayoutOptions" => Ok(KeyboardValue::LayoutOptions),
-            "SwitchMatrixState" => Ok(KeyboardValue::SwitchMatrixState),
-            "FirmwareVersion" => Ok(KeyboardValue::FirmwareVersion),
-            "DeviceIndication" => Ok(KeyboardValue::DeviceIndication),
-            _ => Err("Invalid KeyboardValue"),
+impl CommandOptions {
+    fn new(report_size: usize) -> Result<Self> {
+        if !(MIN_REPORT_SIZE..=MAX_REPORT_SIZE).contains(&report_size) {
+            return Err(Error::InvalidArgument("report size"));
         }
+        Ok(CommandOptions {
+            report_size,
+            timeout_ms: Some(DEFAULT_TIMEOUT_MS),
+            retries: DEFAULT_RETRIES,
+            reconnect_timeout_ms: None,
+        })
     }
 }
 
+/// Runs `f` with the GIL released when called from Python, so that other Python threads keep
+/// running while waiting for the keyboard.
+#[cfg(feature = "python")]
+fn detach<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    let mut f = Some(f);
+    // Threads the interpreter is not running on do not hold the GIL and run `f` right away
+    Python::try_attach(|py| py.detach(|| f.take().expect("not run yet")()))
+        .unwrap_or_else(|| f.take().expect("not run yet")())
+}
+
+#[cfg(not(feature = "python"))]
+fn detach<T>(f: impl FnOnce() -> T) -> T {
+    f()
+}
+
+/// Sends a command and returns the report accepted by `is_response`.
+///
+/// If `check_unhandled` is set, fails with [`Error::UnsupportedCommand`] if the firmware does not
+/// handle the command. Responses that do not echo the request must not be checked, as their data
+/// may look like an unhandled command.
 fn hid_command_on_device(
-    device: &hidapi::HidDevice,
+    transport: &dyn Transport,
     command: ViaCommandId,
     bytes: Vec<u8>,
+    options: CommandOptions,
+    check_unhandled: bool,
+    is_response: impl Fn(&[u8], &[u8]) -> bool,
+) -> Result<Vec<u8>> {
+    let mut command_bytes = vec![command as u8];
+    command_bytes.extend(&bytes);
+    let is_unhandled = |response: &[u8], command_bytes: &[u8]| {
+        check_unhandled && protocol::is_unhandled_response(response, command_bytes)
+    };
+    let response = hid_exchange_on_device(
+        transport,
+        command,
+        bytes,
+        options,
+        |response, command_bytes| {
+            is_response(response, command_bytes) || is_unhandled(response, command_bytes)
+        },
+    )?;
+    if is_unhandled(&response, &command_bytes) {
+        return Err(Error::UnsupportedCommand {
+            command,
+            channel: None,
+        });
+    }
+    Ok(response)
+}
+
+/// Sends a command and waits for a report accepted by `is_response`, which is passed the report
+/// and the sent command bytes.
+fn hid_exchange_on_device(
+    transport: &dyn Transport,
+    command: ViaCommandId,
+    bytes: Vec<u8>,
+    options: CommandOptions,
+    is_response: impl Fn(&[u8], &[u8]) -> bool,
 ) -> Result<Vec<u8>> {
     let mut command_bytes: Vec<u8> = vec![command as u8];
     command_bytes.extend(bytes);
 
-    hid_send_on_device(device, command_bytes.clone())
-        .map_err(|send_err| Error::SendCommand(command, send_err.to_string()))?;
+    let retries = match command.is_retry_safe() {
+        true => options.retries,
+        false => 0,
+    };
+    let mut result = Err(Error::Timeout(command));
+    for _attempt in 0..=retries {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(?command, attempt = _attempt, "sending command");
+        // Discard reports left over from earlier commands, e.g. late responses to timed out commands
+        hid_drain_on_device(transport, options.report_size)?;
+
+        hid_send_on_device(transport, command_bytes.clone(), options.report_size).map_err(
+            |send_err| match send_err {
+                replay_err @ Error::ReplayMismatch { .. } => replay_err,
+                send_err => Error::SendCommand(command, Box::new(send_err)),
+            },
+        )?;
 
-    let buffer = hid_read_on_device(device)?;
-    if buffer.starts_with(&command_bytes) {
-        Ok(buffer)
-    } else {
+        result =
+            hid_await_response_on_device(transport, command, &command_bytes, options, &is_response);
+        match &result {
+            Err(_err @ (Error::Timeout(_) | Error::BadCommandResponse(_))) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(?command, attempt = _attempt, error = %_err, "no valid response");
+                continue;
+            }
+            _ => break,
+        }
+    }
+    result
+}
+
+/// Reads reports until one is accepted as the response, skipping stale or unrelated reports.
+fn hid_await_response_on_device(
+    transport: &dyn Transport,
+    command: ViaCommandId,
+    command_bytes: &[u8],
+    options: CommandOptions,
+    is_response: &dyn Fn(&[u8], &[u8]) -> bool,
+) -> Result<Vec<u8>> {
+    let deadline = options
+        .timeout_ms
+        .map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms as u64));
+    let mut received_mismatch = false;
+
+    loop {
+        let timeout_ms = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                remaining.as_millis().clamp(1, i32::MAX as u128) as i32
+            }
+            None => -1,
+        };
+
+        let mut buffer = vec![0; options.report_size];
+        if transport.read_report(&mut buffer, timeout_ms)? == 0 {
+            break;
+        }
+        if is_response(&buffer, command_bytes) {
+            return Ok(buffer);
+        }
+        received_mismatch = true;
+    }
+
+    if received_mismatch {
         Err(Error::BadCommandResponse(command))
+    } else {
+        Err(Error::Timeout(command))
     }
 }
 
-fn hid_read_on_device(device: &hidapi::HidDevice) -> Result<Vec<u8>> {
-    let mut buffer = vec![0; RAW_EPSIZE];
-    device.read(&mut buffer)?;
+fn hid_drain_on_device(transport: &dyn Transport, report_size: usize) -> Result<()> {
+    let mut buffer = vec![0; report_size];
+    while transport.read_report(&mut buffer, 0)? > 0 {}
+    Ok(())
+}
+
+fn hid_read_on_device(
+    transport: &dyn Transport,
+    report_size: usize,
+    timeout_ms: Option<u32>,
+) -> Result<Vec<u8>> {
+    let mut buffer = vec![0; report_size];
+    let timeout_ms = timeout_ms.map_or(-1, |timeout_ms| timeout_ms.min(i32::MAX as u32) as i32);
+    let bytes_read = transport.read_report(&mut buffer, timeout_ms)?;
+    buffer.truncate(bytes_read);
     Ok(buffer)
 }
 
-fn hid_send_on_device(device: &hidapi::HidDevice, bytes: Vec<u8>) -> Result<()> {
-    if bytes.len() > RAW_EPSIZE {
+fn hid_send_on_device(transport: &dyn Transport, bytes: Vec<u8>, report_size: usize) -> Result<()> {
+    if bytes.len() > report_size {
         return Err(Error::size_mismatch(
             "send buffer overflow",
-            RAW_EPSIZE,
+            report_size,
             bytes.len(),
         ));
     }
@@ -96,49 +249,215 @@ fn hid_send_on_device(device: &hidapi::HidDevice, bytes: Vec<u8>) -> Result<()>
     let mut command_bytes: Vec<u8> = vec![COMMAND_START];
     command_bytes.extend(bytes);
 
-    let mut padded_array = vec![0; RAW_EPSIZE + 1];
+    let mut padded_array = vec![0; report_size + 1];
     for (idx, &val) in command_bytes.iter().enumerate() {
         padded_array[idx] = val;
     }
 
-    let bytes_written = device.write(&padded_array)?;
-    if bytes_written == RAW_EPSIZE + 1 {
+    let bytes_written = transport.send_report(&padded_array)?;
+    if bytes_written == report_size + 1 {
         return Ok(());
     }
 
     Err(Error::size_mismatch(
         "unexpected number of bytes written",
         bytes_written,
-        RAW_EPSIZE + 1,
+        report_size + 1,
     ))
 }
 
-#[cfg_attr(feature = "python", pyclass(unsendable))]
+/// Remembers which hidapi device a [`KeyboardApi`] was opened from, so it can be opened again.
+#[derive(Clone, Debug)]
+struct DeviceIdentity {
+    vendor_id: u16,
+    product_id: u16,
+    usage_page: u16,
+    serial_number: Option<String>,
+    path: CString,
+}
+
+impl DeviceIdentity {
+    fn of(device: &DeviceInfo) -> Self {
+        DeviceIdentity {
+            vendor_id: device.vendor_id(),
+            product_id: device.product_id(),
+            usage_page: device.usage_page(),
+            serial_number: device.serial_number().map(|s| s.to_string()),
+            path: device.path().to_owned(),
+        }
+    }
+
+    fn matches(&self, device: &DeviceInfo) -> bool {
+        device.vendor_id() == self.vendor_id
+            && device.product_id() == self.product_id
+            && device.usage_page() == self.usage_page
+            && (self.serial_number.is_none()
+                || device.serial_number() == self.serial_number.as_deref())
+    }
+
+    /// Finds the device again, preferring the previous path as the keyboard may have re-enumerated
+    /// under a new one.
+    fn find<'a>(&self, api: &'a HidApi) -> Result<&'a DeviceInfo> {
+        api.device_list()
+            .find(|device| device.path() == self.path.as_c_str() && self.matches(device))
+            .or_else(|| api.device_list().find(|device| self.matches(device)))
+            .ok_or(Error::NoSuchKeyboard {
+                vid: self.vendor_id,
+                pid: self.product_id,
+                usage_page: self.usage_page,
+            })
+    }
+}
+
+/// The transport of a [`KeyboardApi`] together with an optional protocol trace.
+struct Link {
+    transport: Box<dyn Transport>,
+    trace: Mutex<Option<TraceRecorder>>,
+    device: Option<DeviceIdentity>,
+}
+
+impl Link {
+    fn set_trace(&mut self, trace: Option<TraceRecorder>) {
+        *self
+            .trace
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = trace;
+    }
+
+    /// Records a report to the trace. The trace is stopped if it cannot be written, so that it
+    /// never interrupts the communication with the keyboard.
+    fn record(&self, direction: TraceDirection, report: &[u8]) {
+        let mut trace = self
+            .trace
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(Err(_err)) = trace
+            .as_ref()
+            .map(|recorder| recorder.record(direction, report))
+        {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %_err, "stopped protocol trace after a write error");
+            *trace = None;
+        }
+    }
+}
+
+impl Transport for Link {
+    fn send_report(&self, data: &[u8]) -> Result<usize> {
+        // Skip the report id
+        self.record(TraceDirection::Out, data.get(1..).unwrap_or_default());
+        self.transport.send_report(data)
+    }
+
+    fn read_report(&self, buffer: &mut [u8], timeout_ms: i32) -> Result<usize> {
+        let bytes_read = self.transport.read_report(buffer, timeout_ms)?;
+        if bytes_read > 0 {
+            self.record(TraceDirection::In, &buffer[..bytes_read]);
+        }
+        Ok(bytes_read)
+    }
+
+    fn report_size(&self) -> Result<Option<usize>> {
+        self.transport.report_size()
+    }
+}
+
+/// A handle to a VIA keyboard.
+///
+/// The handle is `Send + Sync` and can be shared between threads, e.g. using an `Arc`. Each
+/// request and its response are exchanged while holding an internal lock, so commands issued
+/// from different threads never interleave on the wire.
+///
+/// Keyboards opened from hidapi can optionally be reopened transparently after they were
+/// unplugged or re-enumerated, see [`KeyboardApi::set_reconnect_timeout`].
+#[cfg_attr(feature = "python", pyclass)]
 pub struct KeyboardApi {
-    device: hidapi::HidDevice,
-    protocol_version: u16,
+    link: Mutex<Link>,
+    protocol_version: AtomicU16,
+    options: Mutex<CommandOptions>,
+    /// Vial keyboard id, None until detected and Some(None) if the keyboard does not run Vial
+    vial: Mutex<Option<Option<VialKeyboardId>>>,
 }
 
 #[cfg(feature = "python")]
 #[pymethods]
 impl KeyboardApi {
     #[new]
-    pub fn py_new(vid: u16, pid: u16, usage_page: u16) -> Result<Self> {
-        KeyboardApi::new(vid, pid, usage_page)
+    #[pyo3(signature = (vid, pid, usage_page, report_size=None))]
+    pub fn py_new(
+        py: Python<'_>,
+        vid: u16,
+        pid: u16,
+        usage_page: u16,
+        report_size: Option<usize>,
+    ) -> Result<Self> {
+        let _span = trace::enter_span("new");
+        py.detach(|| KeyboardApi::open(vid, pid, usage_page, report_size))
     }
 
     #[classmethod]
-    #[pyo3(name = "from_device")]
-    pub fn py_from_device(_cls: &Bound<'_, PyType>, device: &KeyboardDeviceInfo) -> Result<Self> {
-        KeyboardApi::from_device(device)
+    #[pyo3(name = "from_device", signature = (device, report_size=None))]
+    pub fn py_from_device(
+        _cls: &Bound<'_, PyType>,
+        py: Python<'_>,
+        device: &KeyboardDeviceInfo,
+        report_size: Option<usize>,
+    ) -> Result<Self> {
+        let _span = trace::enter_span("from_device");
+        py.detach(|| KeyboardApi::open_path(&device.path, report_size))
+    }
+
+    #[classmethod]
+    #[pyo3(name = "from_path", signature = (path, report_size=None))]
+    pub fn py_from_path(
+        _cls: &Bound<'_, PyType>,
+        py: Python<'_>,
+        path: &str,
+        report_size: Option<usize>,
+    ) -> Result<Self> {
+        let _span = trace::enter_span("from_path");
+        py.detach(|| KeyboardApi::open_path(path, report_size))
+    }
+
+    #[classmethod]
+    #[pyo3(name = "from_serial_number", signature = (serial_number, report_size=None))]
+    pub fn py_from_serial_number(
+        _cls: &Bound<'_, PyType>,
+        py: Python<'_>,
+        serial_number: &str,
+        report_size: Option<usize>,
+    ) -> Result<Self> {
+        let _span = trace::enter_span("from_serial_number");
+        py.detach(|| KeyboardApi::open_serial_number(serial_number, report_size))
     }
 }
 
 impl KeyboardApi {
     pub fn new(vid: u16, pid: u16, usage_page: u16) -> Result<KeyboardApi> {
+        let _span = trace::enter_span("new");
+        Self::open(vid, pid, usage_page, None)
+    }
+
+    /// Opens the keyboard using a fixed raw HID report size instead of detecting it from the report descriptor.
+    pub fn new_with_report_size(
+        vid: u16,
+        pid: u16,
+        usage_page: u16,
+        report_size: usize,
+    ) -> Result<KeyboardApi> {
+        let _span = trace::enter_span("new_with_report_size");
+        Self::open(vid, pid, usage_page, Some(report_size))
+    }
+
+    fn open(
+        vid: u16,
+        pid: u16,
+        usage_page: u16,
+        report_size: Option<usize>,
+    ) -> Result<KeyboardApi> {
         let api = HidApi::new()?;
 
-        let device = api
+        let device_info = api
             .device_list()
             .find(|device| {
                 device.vendor_id() == vid
@@ -149,105 +468,589 @@ impl KeyboardApi {
                 vid,
                 pid,
                 usage_page,
-            })?
-            .open_device(&api)?;
+            })?;
+
+        Self::open_device_info(&api, device_info, report_size)
+    }
 
-        let protocol_version = Self::read_protocol_version(&device)?;
+    fn open_path(path: &str, report_size: Option<usize>) -> Result<KeyboardApi> {
+        let api = HidApi::new()?;
+
+        let device_info = api
+            .device_list()
+            .find(|device| device.path().to_string_lossy() == path)
+            .ok_or_else(|| Error::NoMatchingKeyboard(format!("path {}", path)))?;
+
+        Self::open_device_info(&api, device_info, report_size)
+    }
+
+    fn open_serial_number(serial_number: &str, report_size: Option<usize>) -> Result<KeyboardApi> {
+        let api = HidApi::new()?;
+
+        let selector = || format!("serial number {}", serial_number);
+        let matching: Vec<&DeviceInfo> = api
+            .device_list()
+            .filter(|device| {
+                device.usage_page() == VIA_USAGE_PAGE
+                    && device.serial_number() == Some(serial_number)
+            })
+            .collect();
+        let device_info = match matching[..] {
+            [device_info] => device_info,
+            [] => return Err(Error::NoMatchingKeyboard(selector())),
+            _ => {
+                return Err(Error::AmbiguousKeyboard {
+                    selector: selector(),
+                    count: matching.len(),
+                })
+            }
+        };
+
+        Self::open_device_info(&api, device_info, report_size)
+    }
+
+    fn open_device_info(
+        api: &HidApi,
+        device_info: &DeviceInfo,
+        report_size: Option<usize>,
+    ) -> Result<KeyboardApi> {
+        let device = device_info.open_device(api)?;
+
+        let keyboard = match report_size {
+            Some(report_size) => Self::from_transport_with_report_size(device, report_size),
+            None => Self::from_transport(device),
+        }?;
+        keyboard.link().device = Some(DeviceIdentity::of(device_info));
+        Ok(keyboard)
+    }
+
+    /// Opens the given keyboard by its path, so that the right one is opened even if several
+    /// identical keyboards are connected.
+    pub fn from_device(device: &KeyboardDeviceInfo) -> Result<KeyboardApi> {
+        let _span = trace::enter_span("from_device");
+        Self::open_path(&device.path, None)
+    }
+
+    /// Opens the keyboard using a fixed raw HID report size instead of detecting it from the report descriptor.
+    pub fn from_device_with_report_size(
+        device: &KeyboardDeviceInfo,
+        report_size: usize,
+    ) -> Result<KeyboardApi> {
+        let _span = trace::enter_span("from_device_with_report_size");
+        Self::open_path(&device.path, Some(report_size))
+    }
+
+    /// Opens the keyboard with the given platform specific HID path, see [`KeyboardDeviceInfo::path`].
+    pub fn from_path(path: &str) -> Result<KeyboardApi> {
+        let _span = trace::enter_span("from_path");
+        Self::open_path(path, None)
+    }
+
+    /// Opens the keyboard using a fixed raw HID report size instead of detecting it from the report descriptor.
+    pub fn from_path_with_report_size(path: &str, report_size: usize) -> Result<KeyboardApi> {
+        let _span = trace::enter_span("from_path_with_report_size");
+        Self::open_path(path, Some(report_size))
+    }
+
+    /// Opens the VIA keyboard with the given serial number.
+    ///
+    /// Fails with [`Error::AmbiguousKeyboard`] if several connected keyboards share the serial number.
+    pub fn from_serial_number(serial_number: &str) -> Result<KeyboardApi> {
+        let _span = trace::enter_span("from_serial_number");
+        Self::open_serial_number(serial_number, None)
+    }
+
+    /// Opens the keyboard using a fixed raw HID report size instead of detecting it from the report descriptor.
+    pub fn from_serial_number_with_report_size(
+        serial_number: &str,
+        report_size: usize,
+    ) -> Result<KeyboardApi> {
+        let _span = trace::enter_span("from_serial_number_with_report_size");
+        Self::open_serial_number(serial_number, Some(report_size))
+    }
+
+    /// Creates an API handle that communicates over an arbitrary transport instead of a hidapi device.
+    ///
+    /// The raw HID report size is queried from the transport and defaults to [`RAW_EPSIZE`] if unknown.
+    pub fn from_transport<T: Transport + 'static>(transport: T) -> Result<KeyboardApi> {
+        let _span = trace::enter_span("from_transport");
+        let report_size = transport.report_size()?.unwrap_or(RAW_EPSIZE);
+        Self::from_transport_with_report_size(transport, report_size)
+    }
+
+    /// Creates an API handle that communicates over an arbitrary transport using a fixed raw HID report size.
+    pub fn from_transport_with_report_size<T: Transport + 'static>(
+        transport: T,
+        report_size: usize,
+    ) -> Result<KeyboardApi> {
+        let _span = trace::enter_span("from_transport_with_report_size");
+        let options = CommandOptions::new(report_size)?;
+        let protocol_version = Self::read_protocol_version(&transport, options)?;
         Ok(KeyboardApi {
-            device,
-            protocol_version,
+            link: Mutex::new(Link {
+                transport: Box::new(transport),
+                trace: Mutex::new(None),
+                device: None,
+            }),
+            protocol_version: AtomicU16::new(protocol_version),
+            options: Mutex::new(options),
+            vial: Mutex::new(None),
         })
     }
 
-    pub fn from_device(device: &KeyboardDeviceInfo) -> Result<KeyboardApi> {
-        Self::new(device.vendor_id, device.product_id, device.usage_page)
+    /// Starts recording every exchanged report to the given writer. See [`TraceRecorder`] for the format.
+    pub fn start_trace_to_writer<W: Write + Send + 'static>(&self, writer: W) -> Result<()> {
+        let _span = trace::enter_span("start_trace_to_writer");
+        self.start_trace_with_recorder(TraceRecorder::new(writer))
+    }
+
+    fn start_trace_with_recorder(&self, recorder: TraceRecorder) -> Result<()> {
+        recorder.record_session(self.protocol_version(), self.options().report_size)?;
+        self.link().set_trace(Some(recorder));
+        Ok(())
+    }
+
+    fn link(&self) -> MutexGuard<'_, Link> {
+        // A panic while holding the lock leaves no partial state behind that would need recovery
+        self.link
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn options(&self) -> MutexGuard<'_, CommandOptions> {
+        self.options
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn read_protocol_version(transport: &dyn Transport, options: CommandOptions) -> Result<u16> {
+        let request = protocol::GetProtocolVersion;
+        let report = hid_command_on_device(
+            transport,
+            protocol::GetProtocolVersion::COMMAND,
+            request.encode(),
+            options,
+            true,
+            |report, command_bytes| request.is_response(report, command_bytes),
+        )?;
+        let protocol_version = request.decode(&report)?;
+        #[cfg(feature = "tracing")]
+        tracing::info!(protocol_version, "detected VIA protocol version");
+        Ok(protocol_version)
+    }
+
+    fn protocol_version(&self) -> u16 {
+        self.protocol_version.load(Ordering::Relaxed)
+    }
+
+    /// Runs an exchange with the keyboard, reopening the device and running it once more if it
+    /// failed because the keyboard was disconnected.
+    ///
+    /// HID errors are reported as [`Error::Disconnected`] if the keyboard is gone and could not be
+    /// reopened.
+    fn exchange<R: Send>(&self, f: impl Fn(&Link) -> Result<R> + Sync) -> Result<R> {
+        detach(|| self.exchange_attached(&f))
+    }
+
+    fn exchange_attached<R>(&self, f: impl Fn(&Link) -> Result<R>) -> Result<R> {
+        let mut link = self.link();
+        match f(&link) {
+            Err(err @ (Error::Hid(_) | Error::SendCommand(..))) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(error = %err, "keyboard communication failed");
+                if self.can_reconnect(&link) {
+                    match self.reconnect(&mut link) {
+                        Ok(()) => f(&link),
+                        Err(_) => Err(Error::Disconnected(Box::new(err))),
+                    }
+                } else if Self::is_disconnected(&link) {
+                    Err(Error::Disconnected(Box::new(err)))
+                } else {
+                    Err(err)
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Returns whether the keyboard opened from a hidapi device is no longer connected.
+    fn is_disconnected(link: &Link) -> bool {
+        let Some(identity) = &link.device else {
+            return false;
+        };
+        HidApi::new().is_ok_and(|api| {
+            !api.device_list()
+                .any(|device| device.path() == identity.path.as_c_str())
+        })
+    }
+
+    /// Sends a request and decodes its response, see [`crate::protocol`].
+    ///
+    /// Requests that do not [echo](Request::ECHOES) are Vial commands, which fail with an
+    /// unsupported command error unless the keyboard runs Vial.
+    pub fn request<R: Request + Sync>(&self, request: &R) -> Result<R::Response> {
+        let _call = trace::enter_call("request");
+        let options = *self.options();
+        self.request_with_options(request, options)
     }
 
-    fn read_protocol_version(device: &hidapi::HidDevice) -> Result<u16> {
-        let buffer = hid_command_on_device(device, ViaCommandId::GetProtocolVersion, vec![])?;
-        Ok(utils::shift_to_16_bit(buffer[1], buffer[2]))
+    /// Sends a request with the given options instead of the configured ones.
+    fn request_with_options<R: Request + Sync>(
+        &self,
+        request: &R,
+        options: CommandOptions,
+    ) -> Result<R::Response> {
+        if !R::ECHOES && self.vial_keyboard_id()?.is_none() {
+            return Err(Error::UnsupportedCommand {
+                command: R::COMMAND,
+                channel: None,
+            });
+        }
+        let report = self.command(
+            R::COMMAND,
+            request.encode(),
+            options,
+            R::ECHOES,
+            |report, command_bytes| request.is_response(report, command_bytes),
+        )?;
+        request.decode(&report)
+    }
+
+    /// Returns the Vial keyboard id, or None if the keyboard does not run Vial.
+    ///
+    /// Vial is detected once through [`vial::GetKeyboardId`], which non-Vial firmware answers like
+    /// an unhandled command. The protocol version in the first bytes of a Vial response never
+    /// looks like one.
+    pub(crate) fn vial_keyboard_id(&self) -> Result<Option<VialKeyboardId>> {
+        if let Some(vial) = *self.vial() {
+            return Ok(vial);
+        }
+        let request = vial::GetKeyboardId;
+        let options = *self.options();
+        let vial = match self.command(
+            vial::GetKeyboardId::COMMAND,
+            request.encode(),
+            options,
+            true,
+            |report, command_bytes| request.is_response(report, command_bytes),
+        ) {
+            Ok(report) => Some(request.decode(&report)?),
+            Err(Error::UnsupportedCommand { .. }) => None,
+            Err(err) => return Err(err),
+        };
+        *self.vial() = Some(vial);
+        Ok(vial)
+    }
+
+    fn vial(&self) -> MutexGuard<'_, Option<Option<VialKeyboardId>>> {
+        self.vial
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Sends a request and decodes its response, or returns None if the firmware does not handle it.
+    fn request_if_handled<R: Request + Sync>(&self, request: &R) -> Result<Option<R::Response>> {
+        match self.request(request) {
+            Err(Error::UnsupportedCommand { .. }) => Ok(None),
+            result => result.map(Some),
+        }
+    }
+
+    /// Sends a command and returns the report accepted by `is_response`, see [`Request::is_response`].
+    fn command(
+        &self,
+        command: ViaCommandId,
+        bytes: Vec<u8>,
+        options: CommandOptions,
+        check_unhandled: bool,
+        is_response: impl Fn(&[u8], &[u8]) -> bool + Sync,
+    ) -> Result<Vec<u8>> {
+        let channel = self.command_channel(command, &bytes);
+        self.exchange(|link| {
+            hid_command_on_device(
+                link,
+                command,
+                bytes.clone(),
+                options,
+                check_unhandled,
+                &is_response,
+            )
+        })
+        .map_err(|err| match err {
+            Error::UnsupportedCommand { command, .. } => {
+                Error::UnsupportedCommand { command, channel }
+            }
+            err => err,
+        })
+    }
+
+    /// Returns the lighting or custom menu channel addressed by a command.
+    fn command_channel(&self, command: ViaCommandId, bytes: &[u8]) -> Option<ViaChannelId> {
+        match command {
+            // Before protocol V3 these commands addressed lighting values without a channel
+            ViaCommandId::CustomMenuSetValue
+            | ViaCommandId::CustomMenuGetValue
+            | ViaCommandId::CustomMenuSave
+                if self.protocol_version() >= PROTOCOL_V3 =>
+            {
+                ViaChannelId::try_from(*bytes.first()?).ok()
+            }
+            _ => None,
+        }
+    }
+
+    fn can_reconnect(&self, link: &Link) -> bool {
+        link.device.is_some() && self.options().reconnect_timeout_ms.is_some()
+    }
+
+    /// Tries to reopen the keyboard until the reconnect timeout expires.
+    fn reconnect(&self, link: &mut Link) -> Result<()> {
+        let timeout_ms = self.options().reconnect_timeout_ms.unwrap_or_default();
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms as u64);
+        loop {
+            match self.reopen(link) {
+                Err(_) if Instant::now() < deadline => thread::sleep(RECONNECT_INTERVAL),
+                result => return result,
+            }
+        }
+    }
+
+    fn reopen(&self, link: &mut Link) -> Result<()> {
+        let Some(identity) = &link.device else {
+            return Err(Error::UnsupportedFeature(
+                "reconnecting without a hidapi device",
+            ));
+        };
+        let api = HidApi::new()?;
+        let device_info = identity.find(&api)?;
+        link.transport = Box::new(device_info.open_device(&api)?);
+        link.device = Some(DeviceIdentity::of(device_info));
+
+        // The keyboard may have been flashed with a different firmware in the meantime
+        let protocol_version = Self::read_protocol_version(&*link, *self.options())?;
+        self.protocol_version
+            .store(protocol_version, Ordering::Relaxed);
+        *self.vial() = None;
+        Ok(())
     }
 }
 
 #[cfg_attr(feature = "python", pymethods)]
 impl KeyboardApi {
+    /// Returns the time in milliseconds to wait for the response to a command, or None if waiting indefinitely.
+    pub fn get_timeout(&self) -> Option<u32> {
+        let _span = trace::enter_span("get_timeout");
+        self.options().timeout_ms
+    }
+
+    /// Sets the time in milliseconds to wait for the response to a command. None waits indefinitely.
+    pub fn set_timeout(&self, timeout_ms: Option<u32>) {
+        let _span = trace::enter_span("set_timeout");
+        self.options().timeout_ms = timeout_ms;
+    }
+
+    /// Returns how often a command is repeated after a timeout or an unexpected response.
+    pub fn get_retries(&self) -> u8 {
+        let _span = trace::enter_span("get_retries");
+        self.options().retries
+    }
+
+    /// Sets how often a command is repeated after a timeout or an unexpected response. Commands
+    /// that are not [retry safe](ViaCommandId::is_retry_safe) are never repeated.
+    pub fn set_retries(&self, retries: u8) {
+        let _span = trace::enter_span("set_retries");
+        self.options().retries = retries;
+    }
+
+    /// Returns the time in milliseconds to spend reopening a disconnected keyboard, or None if disabled.
+    pub fn get_reconnect_timeout(&self) -> Option<u32> {
+        let _span = trace::enter_span("get_reconnect_timeout");
+        self.options().reconnect_timeout_ms
+    }
+
+    /// Enables reopening the keyboard when it was disconnected, e.g. after being unplugged or reset.
+    ///
+    /// A command failing with a HID error causes the keyboard to be looked up again by vendor id,
+    /// product id, usage page and serial number for up to `timeout_ms` milliseconds. Once reopened,
+    /// the protocol version is read again and the command is repeated. None disables reconnecting.
+    /// Only keyboards opened from hidapi can be reconnected.
+    pub fn set_reconnect_timeout(&self, timeout_ms: Option<u32>) {
+        let _span = trace::enter_span("set_reconnect_timeout");
+        self.options().reconnect_timeout_ms = timeout_ms;
+    }
+
+    /// Starts recording every exchanged report to the given file as JSON lines, replacing any existing content.
+    pub fn start_trace(&self, path: &str) -> Result<()> {
+        let _span = trace::enter_span("start_trace");
+        self.start_trace_with_recorder(TraceRecorder::create(path)?)
+    }
+
+    /// Stops recording reports.
+    pub fn stop_trace(&self) {
+        let _span = trace::enter_span("stop_trace");
+        self.link().set_trace(None);
+    }
+
     /// Sends a raw HID command prefixed with the command byte and returns the response if successful.
     pub fn hid_command(&self, command: ViaCommandId, bytes: Vec<u8>) -> Result<Vec<u8>> {
-        hid_command_on_device(&self.device, command, bytes)
+        let _call = trace::enter_call("hid_command");
+        let options = *self.options();
+        self.command(command, bytes, options, true, |response, command_bytes| {
+            response.starts_with(command_bytes)
+        })
     }
 
-    /// Reads from the HID device. Returns None if the read fails.
+    /// Reads from the HID device. Returns an empty buffer if no report arrived within the timeout.
     pub fn hid_read(&self) -> Result<Vec<u8>> {
-        hid_read_on_device(&self.device)
+        let _call = trace::enter_call("hid_read");
+        let options = *self.options();
+        self.exchange(|link| hid_read_on_device(link, options.report_size, options.timeout_ms))
     }
 
     /// Sends a raw HID command prefixed with the command byte. Returns None if the send fails.
     pub fn hid_send(&self, bytes: Vec<u8>) -> Result<()> {
-        hid_send_on_device(&self.device, bytes)
+        let _call = trace::enter_call("hid_send");
+        let report_size = self.get_report_size();
+        self.exchange(|link| hid_send_on_device(link, bytes.clone(), report_size))
+    }
+
+    /// Returns the size of raw HID reports exchanged with the keyboard in bytes.
+    pub fn get_report_size(&self) -> usize {
+        let _span = trace::enter_span("get_report_size");
+        self.options().report_size
     }
 
     /// Returns the protocol version of the keyboard.
     pub fn get_protocol_version(&self) -> Result<u16> {
-        Ok(self.protocol_version)
+        let _span = trace::enter_span("get_protocol_version");
+        Ok(self.protocol_version())
+    }
+
+    /// Queries which features the keyboard supports.
+    ///
+    /// Lighting channels and the firmware version can only be queried from protocol V3 on.
+    pub fn get_capabilities(&self) -> Result<KeyboardCapabilities> {
+        let _call = trace::enter_call("get_capabilities");
+        let protocol_version = self.protocol_version();
+
+        let macro_count = self
+            .request_if_handled(&protocol::DynamicKeymapMacroGetCount)?
+            .unwrap_or(0);
+        let macro_buffer_size = self
+            .request_if_handled(&protocol::DynamicKeymapMacroGetBufferSize)?
+            .unwrap_or(0);
+        let has_encoders = self
+            .request_if_handled(&protocol::DynamicKeymapGetEncoder {
+                layer: 0,
+                id: 0,
+                clockwise: false,
+            })?
+            .is_some();
+
+        let mut lighting_channels = Vec::new();
+        let mut firmware_version = None;
+        if protocol_version >= PROTOCOL_V3 {
+            for channel in [
+                ViaChannelId::IdQmkBacklightChannel,
+                ViaChannelId::IdQmkRgblightChannel,
+                ViaChannelId::IdQmkRgbMatrixChannel,
+                ViaChannelId::IdQmkAudioChannel,
+                ViaChannelId::IdQmkLedMatrixChannel,
+            ] {
+                // Every channel has a value with id 1
+                let request = protocol::CustomMenuGetValue {
+                    bytes: vec![channel as u8, 1],
+                    value_length: 1,
+                };
+                if self.request_if_handled(&request)?.is_some() {
+                    lighting_channels.push(channel);
+                }
+            }
+            firmware_version = self
+                .request_if_handled(&protocol::GetKeyboardValue {
+                    value: KeyboardValue::FirmwareVersion,
+                    parameters: vec![],
+                    result_length: 4,
+                })?
+                .map(|val| u32::from_be_bytes([val[0], val[1], val[2], val[3]]));
+        }
+
+        let vial = self.vial_keyboard_id()?.is_some();
+
+        Ok(KeyboardCapabilities {
+            protocol_version,
+            layer_count: self.get_layer_count()?,
+            macro_count,
+            macro_buffer_size,
+            has_encoders,
+            lighting_channels,
+            vial,
+            firmware_version,
+        })
     }
 
     /// Returns the number of layers on the keyboard.
     pub fn get_layer_count(&self) -> Result<u8> {
-        match self.protocol_version {
-            version if version >= PROTOCOL_BETA => self
-                .hid_command(ViaCommandId::DynamicKeymapGetLayerCount, vec![])
-                .map(|val| val[1]),
+        let _call = trace::enter_call("get_layer_count");
+        match self.protocol_version() {
+            version if version >= PROTOCOL_BETA => {
+                self.request(&protocol::DynamicKeymapGetLayerCount)
+            }
             _ => Ok(4),
         }
     }
 
     /// Returns the keycode at the given layer, row, and column.
     pub fn get_key(&self, layer: Layer, row: Row, col: Column) -> Result<u16> {
-        self.hid_command(ViaCommandId::DynamicKeymapGetKeycode, vec![layer, row, col])
-            .map(|val| utils::shift_to_16_bit(val[4], val[5]))
+        let _call = trace::enter_call("get_key");
+        self.request(&protocol::DynamicKeymapGetKeycode { layer, row, col })
     }
 
     /// Sets the keycode at the given layer, row, and column.
     pub fn set_key(&self, layer: Layer, row: Row, column: Column, val: u16) -> Result<u16> {
-        let val_bytes = utils::shift_from_16_bit(val);
-        let bytes = vec![layer, row, column, val_bytes.0, val_bytes.1];
-        self.hid_command(ViaCommandId::DynamicKeymapSetKeycode, bytes)
-            .map(|val| utils::shift_to_16_bit(val[4], val[5]))
+        let _call = trace::enter_call("set_key");
+        self.request(&protocol::DynamicKeymapSetKeycode {
+            layer,
+            row,
+            col: column,
+            keycode: val,
+        })
     }
 
     /// Returns the keycodes for the given matrix info (number of rows and columns) and layer.
     pub fn read_raw_matrix(&self, matrix_info: MatrixInfo, layer: Layer) -> Result<Vec<u16>> {
-        match self.protocol_version {
+        let _call = trace::enter_call("read_raw_matrix");
+        match self.protocol_version() {
             version if version >= PROTOCOL_BETA => self.fast_read_raw_matrix(matrix_info, layer),
             version if version == PROTOCOL_ALPHA => self.slow_read_raw_matrix(matrix_info, layer),
             version => Err(Error::UnsupportedProtocol(version)),
         }
     }
 
+    /// Returns the number of payload bytes transferred by a single buffer command.
+    fn data_buffer_size(&self) -> usize {
+        self.options().report_size - BUFFER_HEADER_SIZE
+    }
+
     fn get_keymap_buffer(&self, offset: u16, size: u8) -> Result<Vec<u8>> {
-        if size > DATA_BUFFER_SIZE as u8 {
+        if size as usize > self.data_buffer_size() {
             return Err(Error::size_mismatch(
                 "read data size too large",
-                DATA_BUFFER_SIZE,
+                self.data_buffer_size(),
                 size as usize,
             ));
         }
-        let offset_bytes = utils::shift_from_16_bit(offset);
-        self.hid_command(
-            ViaCommandId::DynamicKeymapGetBuffer,
-            vec![offset_bytes.0, offset_bytes.1, size],
-        )
-        .map(|val| val[4..(size as usize + 4)].to_vec())
+        self.request(&protocol::DynamicKeymapGetBuffer { offset, size })
     }
 
     fn fast_read_raw_matrix(&self, matrix_info: MatrixInfo, layer: Layer) -> Result<Vec<u16>> {
-        const MAX_KEYCODES_PARTIAL: usize = 14;
+        let max_keycodes_partial = self.data_buffer_size() / 2;
         let length = matrix_info.rows as usize * matrix_info.cols as usize;
-        let buffer_len = length.div_ceil(MAX_KEYCODES_PARTIAL);
+        let buffer_len = length.div_ceil(max_keycodes_partial);
         let mut remaining = length;
         let mut result = Vec::new();
         for _ in 0..buffer_len {
-            if remaining < MAX_KEYCODES_PARTIAL {
+            if remaining < max_keycodes_partial {
                 let val = self.get_keymap_buffer(
                     layer as u16 * length as u16 * 2 + 2 * (length - remaining) as u16,
                     (remaining * 2) as u8,
@@ -257,10 +1060,10 @@ impl KeyboardApi {
             } else {
                 let val = self.get_keymap_buffer(
                     layer as u16 * length as u16 * 2 + 2 * (length - remaining) as u16,
-                    (MAX_KEYCODES_PARTIAL * 2) as u8,
+                    (max_keycodes_partial * 2) as u8,
                 )?;
                 result.extend(val);
-                remaining -= MAX_KEYCODES_PARTIAL;
+                remaining -= max_keycodes_partial;
             }
         }
         Ok(utils::shift_buffer_to_16_bit(&result))
@@ -279,7 +1082,8 @@ impl KeyboardApi {
 
     /// Writes a keymap to the keyboard for the given matrix info (number of rows and columns).
     pub fn write_raw_matrix(&self, matrix_info: MatrixInfo, keymap: Vec<Vec<u16>>) -> Result<()> {
-        match self.protocol_version {
+        let _call = trace::enter_call("write_raw_matrix");
+        match self.protocol_version() {
             version if version >= PROTOCOL_BETA => self.fast_write_raw_matrix(keymap)?,
             version if version == PROTOCOL_ALPHA => {
                 self.slow_write_raw_matrix(matrix_info, keymap)?
@@ -306,13 +1110,19 @@ impl KeyboardApi {
             .flat_map(|layer| layer.iter().cloned())
             .collect();
         let shifted_data = utils::shift_buffer_from_16_bit(&data);
-        for offset in (0..shifted_data.len()).step_by(DATA_BUFFER_SIZE) {
-            let offset_bytes = utils::shift_from_16_bit(offset as u16);
-            let end = std::cmp::min(offset + DATA_BUFFER_SIZE, shifted_data.len());
-            let buffer = shifted_data[offset..end].to_vec();
-            let mut bytes = vec![offset_bytes.0, offset_bytes.1, buffer.len() as u8];
-            bytes.extend(buffer);
-            self.hid_command(ViaCommandId::DynamicKeymapSetBuffer, bytes)?;
+        let data_buffer_size = self.data_buffer_size();
+        for offset in (0..shifted_data.len()).step_by(data_buffer_size) {
+            let end = std::cmp::min(offset + data_buffer_size, shifted_data.len());
+            self.request(&protocol::DynamicKeymapSetBuffer {
+                offset: offset as u16,
+                data: shifted_data[offset..end].to_vec(),
+            })?;
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                written = end,
+                total = shifted_data.len(),
+                "wrote keymap chunk"
+            );
         }
         Ok(())
     }
@@ -324,44 +1134,47 @@ impl KeyboardApi {
         parameters: Vec<u8>,
         result_length: usize,
     ) -> Result<Vec<u8>> {
+        let _call = trace::enter_call("get_keyboard_value");
         match command {
             KeyboardValue::FirmwareVersion | KeyboardValue::DeviceIndication
-                if self.protocol_version < PROTOCOL_V3 =>
+                if self.protocol_version() < PROTOCOL_V3 =>
             {
-                return Err(Error::UnsupportedProtocol(self.protocol_version));
+                return Err(Error::UnsupportedProtocol(self.protocol_version()));
             }
             _ => {}
         }
-        let parameters_length = parameters.len();
-        let mut bytes = vec![command as u8];
-        bytes.extend(parameters);
-        self.hid_command(ViaCommandId::GetKeyboardValue, bytes)
-            .map(|val| val[2 + parameters_length..2 + parameters_length + result_length].to_vec())
+        self.request(&protocol::GetKeyboardValue {
+            value: command,
+            parameters,
+            result_length,
+        })
     }
 
     /// Sets a keyboard value. This can be used to set keyboard values like layout options or device indication.
     pub fn set_keyboard_value(&self, command: KeyboardValue, parameters: Vec<u8>) -> Result<()> {
+        let _call = trace::enter_call("set_keyboard_value");
         match command {
             KeyboardValue::FirmwareVersion | KeyboardValue::DeviceIndication
-                if self.protocol_version < PROTOCOL_V3 =>
+                if self.protocol_version() < PROTOCOL_V3 =>
             {
-                return Err(Error::UnsupportedProtocol(self.protocol_version));
+                return Err(Error::UnsupportedProtocol(self.protocol_version()));
             }
             _ => {}
         }
-        let mut bytes = vec![command as u8];
-        bytes.extend(parameters);
-        self.hid_command(ViaCommandId::SetKeyboardValue, bytes)
-            .map(|_| ())
+        self.request(&protocol::SetKeyboardValue {
+            value: command,
+            parameters,
+        })
     }
 
     /// Gets the encoder value for the given layer, id, and direction.
     pub fn get_encoder_value(&self, layer: Layer, id: u8, is_clockwise: bool) -> Result<u16> {
-        self.hid_command(
-            ViaCommandId::DynamicKeymapGetEncoder,
-            vec![layer, id, is_clockwise as u8],
-        )
-        .map(|val| utils::shift_to_16_bit(val[4], val[5]))
+        let _call = trace::enter_call("get_encoder_value");
+        self.request(&protocol::DynamicKeymapGetEncoder {
+            layer,
+            id,
+            clockwise: is_clockwise,
+        })
     }
 
     /// Sets the encoder value for the given layer, id, direction, and keycode.
@@ -372,668 +1185,572 @@ impl KeyboardApi {
         is_clockwise: bool,
         keycode: u16,
     ) -> Result<()> {
-        let keycode_bytes = utils::shift_from_16_bit(keycode);
-        let bytes = vec![
+        let _call = trace::enter_call("set_encoder_value");
+        self.request(&protocol::DynamicKeymapSetEncoder {
             layer,
             id,
-            is_clockwise as u8,
-            keycode_bytes.0,
-            keycode_bytes.1,
-        ];
-        self.hid_command(ViaCommandId::DynamicKeymapSetEncoder, bytes)
-            .map(|_| ())
+            clockwise: is_clockwise,
+            keycode,
+        })
     }
 
     /// Get a custom menu value. This is a generic function that can be used to get any value specific to arbitrary keyboard functionalities.
+    ///
+    /// Returns the remainder of the response following the given command bytes.
     pub fn get_custom_menu_value(&self, command_bytes: Vec<u8>) -> Result<Vec<u8>> {
-        if self.protocol_version < PROTOCOL_V3 {
-            return Err(Error::UnsupportedProtocol(self.protocol_version));
+        let _call = trace::enter_call("get_custom_menu_value");
+        if self.protocol_version() < PROTOCOL_V3 {
+            return Err(Error::UnsupportedProtocol(self.protocol_version()));
         }
-        let command_length = command_bytes.len();
-        self.hid_command(ViaCommandId::CustomMenuGetValue, command_bytes)
-            .map(|val| val[0..command_length].to_vec())
+        let value_length = self
+            .get_report_size()
+            .saturating_sub(1 + command_bytes.len());
+        self.get_custom_value(command_bytes, value_length)
     }
 
     /// Set a custom menu value. This is a generic function that can be used to set any value specific to arbitrary keyboard functionalities.
     pub fn set_custom_menu_value(&self, args: Vec<u8>) -> Result<()> {
-        if self.protocol_version < PROTOCOL_V3 {
-            return Err(Error::UnsupportedProtocol(self.protocol_version));
+        let _call = trace::enter_call("set_custom_menu_value");
+        if self.protocol_version() < PROTOCOL_V3 {
+            return Err(Error::UnsupportedProtocol(self.protocol_version()));
         }
-        self.hid_command(ViaCommandId::CustomMenuSetValue, args)
-            .map(|_| ())
+        self.set_custom_value(args)
     }
 
     /// Saves the custom menu values for the given channel id.
     pub fn save_custom_menu(&self, channel: u8) -> Result<()> {
-        if self.protocol_version < PROTOCOL_V3 {
-            return Err(Error::UnsupportedProtocol(self.protocol_version));
+        let _call = trace::enter_call("save_custom_menu");
+        if self.protocol_version() < PROTOCOL_V3 {
+            return Err(Error::UnsupportedProtocol(self.protocol_version()));
         }
-        let bytes = vec![channel];
-        self.hid_command(ViaCommandId::CustomMenuSave, bytes)
-            .map(|_| ())
+        self.request(&protocol::CustomMenuSave {
+            channel: Some(channel),
+        })
     }
 
-    /// Gets the backlight brightness.
-    pub fn get_backlight_brightness(&self) -> Result<u8> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            self.hid_command(
-                ViaCommandId::CustomMenuGetValue,
-                vec![
-                    ViaChannelId::IdQmkBacklightChannel as u8,
-                    ViaQmkBacklightValue::IdQmkBacklightBrightness as u8,
-                ],
-            )
-            .map(|val| val[3])
+    fn get_custom_value(&self, bytes: Vec<u8>, value_length: usize) -> Result<Vec<u8>> {
+        self.request(&protocol::CustomMenuGetValue {
+            bytes,
+            value_length,
+        })
+    }
+
+    fn set_custom_value(&self, bytes: Vec<u8>) -> Result<()> {
+        self.request(&protocol::CustomMenuSetValue { bytes })
+    }
+
+    /// Returns the request bytes addressing a lighting value, which has a channel from protocol V3 on.
+    fn lighting_value(
+        &self,
+        channel: ViaChannelId,
+        value: u8,
+        legacy: ViaLightingValue,
+    ) -> Vec<u8> {
+        if self.protocol_version() >= PROTOCOL_V3 {
+            vec![channel as u8, value]
         } else {
-            self.hid_command(
-                ViaCommandId::CustomMenuGetValue,
-                vec![ViaLightingValue::IdBacklightBrightness as u8],
-            )
-            .map(|val| val[2])
+            vec![legacy as u8]
         }
     }
 
+    /// Gets the backlight brightness.
+    pub fn get_backlight_brightness(&self) -> Result<u8> {
+        let _call = trace::enter_call("get_backlight_brightness");
+        let bytes = self.lighting_value(
+            ViaChannelId::IdQmkBacklightChannel,
+            ViaQmkBacklightValue::IdQmkBacklightBrightness as u8,
+            ViaLightingValue::IdBacklightBrightness,
+        );
+        self.get_custom_value(bytes, 1).map(|val| val[0])
+    }
+
     /// Sets the backlight brightness.
     pub fn set_backlight_brightness(&self, brightness: u8) -> Result<()> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            self.hid_command(
-                ViaCommandId::CustomMenuSetValue,
-                vec![
-                    ViaChannelId::IdQmkBacklightChannel as u8,
-                    ViaQmkBacklightValue::IdQmkBacklightBrightness as u8,
-                    brightness,
-                ],
-            )
-            .map(|_| ())
-        } else {
-            self.hid_command(
-                ViaCommandId::CustomMenuSetValue,
-                vec![ViaLightingValue::IdBacklightBrightness as u8, brightness],
-            )
-            .map(|_| ())
-        }
+        let _call = trace::enter_call("set_backlight_brightness");
+        let mut bytes = self.lighting_value(
+            ViaChannelId::IdQmkBacklightChannel,
+            ViaQmkBacklightValue::IdQmkBacklightBrightness as u8,
+            ViaLightingValue::IdBacklightBrightness,
+        );
+        bytes.push(brightness);
+        self.set_custom_value(bytes)
     }
 
     /// Gets the backlight effect.
     pub fn get_backlight_effect(&self) -> Result<u8> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            self.hid_command(
-                ViaCommandId::CustomMenuGetValue,
-                vec![
-                    ViaChannelId::IdQmkBacklightChannel as u8,
-                    ViaQmkBacklightValue::IdQmkBacklightEffect as u8,
-                ],
-            )
-            .map(|val| val[3])
-        } else {
-            self.hid_command(
-                ViaCommandId::CustomMenuGetValue,
-                vec![ViaLightingValue::IdBacklightEffect as u8],
-            )
-            .map(|val| val[2])
-        }
+        let _call = trace::enter_call("get_backlight_effect");
+        let bytes = self.lighting_value(
+            ViaChannelId::IdQmkBacklightChannel,
+            ViaQmkBacklightValue::IdQmkBacklightEffect as u8,
+            ViaLightingValue::IdBacklightEffect,
+        );
+        self.get_custom_value(bytes, 1).map(|val| val[0])
     }
 
     /// Sets the backlight effect.
     pub fn set_backlight_effect(&self, effect: u8) -> Result<()> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            self.hid_command(
-                ViaCommandId::CustomMenuSetValue,
-                vec![
-                    ViaChannelId::IdQmkBacklightChannel as u8,
-                    ViaQmkBacklightValue::IdQmkBacklightEffect as u8,
-                    effect,
-                ],
-            )
-            .map(|_| ())
-        } else {
-            self.hid_command(
-                ViaCommandId::CustomMenuSetValue,
-                vec![ViaLightingValue::IdBacklightEffect as u8, effect],
-            )
-            .map(|_| ())
-        }
+        let _call = trace::enter_call("set_backlight_effect");
+        let mut bytes = self.lighting_value(
+            ViaChannelId::IdQmkBacklightChannel,
+            ViaQmkBacklightValue::IdQmkBacklightEffect as u8,
+            ViaLightingValue::IdBacklightEffect,
+        );
+        bytes.push(effect);
+        self.set_custom_value(bytes)
     }
 
     /// Gets the RGB light brightness.
     pub fn get_rgblight_brightness(&self) -> Result<u8> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            self.hid_command(
-                ViaCommandId::CustomMenuGetValue,
-                vec![
-                    ViaChannelId::IdQmkRgblightChannel as u8,
-                    ViaQmkRgblightValue::IdQmkRgblightBrightness as u8,
-                ],
-            )
-            .map(|val| val[3])
-        } else {
-            self.hid_command(
-                ViaCommandId::CustomMenuGetValue,
-                vec![ViaLightingValue::IdQmkRgblightBrightness as u8],
-            )
-            .map(|val| val[2])
-        }
+        let _call = trace::enter_call("get_rgblight_brightness");
+        let bytes = self.lighting_value(
+            ViaChannelId::IdQmkRgblightChannel,
+            ViaQmkRgblightValue::IdQmkRgblightBrightness as u8,
+            ViaLightingValue::IdQmkRgblightBrightness,
+        );
+        self.get_custom_value(bytes, 1).map(|val| val[0])
     }
 
     /// Sets the RGB light brightness.
     pub fn set_rgblight_brightness(&self, brightness: u8) -> Result<()> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            self.hid_command(
-                ViaCommandId::CustomMenuSetValue,
-                vec![
-                    ViaChannelId::IdQmkRgblightChannel as u8,
-                    ViaQmkRgblightValue::IdQmkRgblightBrightness as u8,
-                    brightness,
-                ],
-            )
-            .map(|_| ())
-        } else {
-            self.hid_command(
-                ViaCommandId::CustomMenuSetValue,
-                vec![ViaLightingValue::IdQmkRgblightBrightness as u8, brightness],
-            )
-            .map(|_| ())
-        }
+        let _call = trace::enter_call("set_rgblight_brightness");
+        let mut bytes = self.lighting_value(
+            ViaChannelId::IdQmkRgblightChannel,
+            ViaQmkRgblightValue::IdQmkRgblightBrightness as u8,
+            ViaLightingValue::IdQmkRgblightBrightness,
+        );
+        bytes.push(brightness);
+        self.set_custom_value(bytes)
     }
 
     /// Gets the RGB light effect.
     pub fn get_rgblight_effect(&self) -> Result<u8> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            self.hid_command(
-                ViaCommandId::CustomMenuGetValue,
-                vec![
-                    ViaChannelId::IdQmkRgblightChannel as u8,
-                    ViaQmkRgblightValue::IdQmkRgblightEffect as u8,
-                ],
-            )
-            .map(|val| val[3])
-        } else {
-            self.hid_command(
-                ViaCommandId::CustomMenuGetValue,
-                vec![ViaLightingValue::IdQmkRgblightEffect as u8],
-            )
-            .map(|val| val[2])
-        }
+        let _call = trace::enter_call("get_rgblight_effect");
+        let bytes = self.lighting_value(
+            ViaChannelId::IdQmkRgblightChannel,
+            ViaQmkRgblightValue::IdQmkRgblightEffect as u8,
+            ViaLightingValue::IdQmkRgblightEffect,
+        );
+        self.get_custom_value(bytes, 1).map(|val| val[0])
     }
 
     /// Sets the RGB light effect.
     pub fn set_rgblight_effect(&self, effect: u8) -> Result<()> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            self.hid_command(
-                ViaCommandId::CustomMenuSetValue,
-                vec![
-                    ViaChannelId::IdQmkRgblightChannel as u8,
-                    ViaQmkRgblightValue::IdQmkRgblightEffect as u8,
-                    effect,
-                ],
-            )
-            .map(|_| ())
-        } else {
-            self.hid_command(
-                ViaCommandId::CustomMenuSetValue,
-                vec![ViaLightingValue::IdQmkRgblightEffect as u8, effect],
-            )
-            .map(|_| ())
-        }
+        let _call = trace::enter_call("set_rgblight_effect");
+        let mut bytes = self.lighting_value(
+            ViaChannelId::IdQmkRgblightChannel,
+            ViaQmkRgblightValue::IdQmkRgblightEffect as u8,
+            ViaLightingValue::IdQmkRgblightEffect,
+        );
+        bytes.push(effect);
+        self.set_custom_value(bytes)
     }
 
     /// Gets the RGB light effect speed.
     pub fn get_rgblight_effect_speed(&self) -> Result<u8> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            self.hid_command(
-                ViaCommandId::CustomMenuGetValue,
-                vec![
-                    ViaChannelId::IdQmkRgblightChannel as u8,
-                    ViaQmkRgblightValue::IdQmkRgblightEffectSpeed as u8,
-                ],
-            )
-            .map(|val| val[3])
-        } else {
-            self.hid_command(
-                ViaCommandId::CustomMenuGetValue,
-                vec![ViaLightingValue::IdQmkRgblightEffectSpeed as u8],
-            )
-            .map(|val| val[2])
-        }
+        let _call = trace::enter_call("get_rgblight_effect_speed");
+        let bytes = self.lighting_value(
+            ViaChannelId::IdQmkRgblightChannel,
+            ViaQmkRgblightValue::IdQmkRgblightEffectSpeed as u8,
+            ViaLightingValue::IdQmkRgblightEffectSpeed,
+        );
+        self.get_custom_value(bytes, 1).map(|val| val[0])
     }
 
     /// Sets the RGB light effect speed.
     pub fn set_rgblight_effect_speed(&self, speed: u8) -> Result<()> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            self.hid_command(
-                ViaCommandId::CustomMenuSetValue,
-                vec![
-                    ViaChannelId::IdQmkRgblightChannel as u8,
-                    ViaQmkRgblightValue::IdQmkRgblightEffectSpeed as u8,
-                    speed,
-                ],
-            )
-            .map(|_| ())
-        } else {
-            self.hid_command(
-                ViaCommandId::CustomMenuSetValue,
-                vec![ViaLightingValue::IdQmkRgblightEffectSpeed as u8, speed],
-            )
-            .map(|_| ())
-        }
+        let _call = trace::enter_call("set_rgblight_effect_speed");
+        let mut bytes = self.lighting_value(
+            ViaChannelId::IdQmkRgblightChannel,
+            ViaQmkRgblightValue::IdQmkRgblightEffectSpeed as u8,
+            ViaLightingValue::IdQmkRgblightEffectSpeed,
+        );
+        bytes.push(speed);
+        self.set_custom_value(bytes)
     }
 
     /// Gets the RGB light color.
     pub fn get_rgblight_color(&self) -> Result<(u8, u8)> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            return self
-                .hid_command(
-                    ViaCommandId::CustomMenuGetValue,
-                    vec![
-                        ViaChannelId::IdQmkRgblightChannel as u8,
-                        ViaQmkRgblightValue::IdQmkRgblightColor as u8,
-                    ],
-                )
-                .map(|val| (val[3], val[4]));
-        }
-
-        self.hid_command(
-            ViaCommandId::CustomMenuGetValue,
-            vec![ViaLightingValue::IdQmkRgblightColor as u8],
-        )
-        .map(|val| (val[2], val[3]))
+        let _call = trace::enter_call("get_rgblight_color");
+        let bytes = self.lighting_value(
+            ViaChannelId::IdQmkRgblightChannel,
+            ViaQmkRgblightValue::IdQmkRgblightColor as u8,
+            ViaLightingValue::IdQmkRgblightColor,
+        );
+        self.get_custom_value(bytes, 2).map(|val| (val[0], val[1]))
     }
 
     /// Sets the RGB light color.
     pub fn set_rgblight_color(&self, hue: u8, sat: u8) -> Result<()> {
-        if self.protocol_version >= PROTOCOL_V3 {
-            return self
-                .hid_command(
-                    ViaCommandId::CustomMenuSetValue,
-                    vec![
-                        ViaChannelId::IdQmkRgblightChannel as u8,
-                        ViaQmkRgblightValue::IdQmkRgblightColor as u8,
-                        hue,
-                        sat,
-                    ],
-                )
-                .map(|_| ());
-        }
-
-        self.hid_command(
-            ViaCommandId::CustomMenuSetValue,
-            vec![ViaLightingValue::IdQmkRgblightColor as u8, hue, sat],
-        )
-        .map(|_| ())
+        let _call = trace::enter_call("set_rgblight_color");
+        let mut bytes = self.lighting_value(
+            ViaChannelId::IdQmkRgblightChannel,
+            ViaQmkRgblightValue::IdQmkRgblightColor as u8,
+            ViaLightingValue::IdQmkRgblightColor,
+        );
+        bytes.extend([hue, sat]);
+        self.set_custom_value(bytes)
     }
 
     /// Gets the RGB matrix brightness.
     pub fn get_rgb_matrix_brightness(&self) -> Result<u8> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("get_rgb_matrix_brightness");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("RGB matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuGetValue,
+        self.get_custom_value(
             vec![
                 ViaChannelId::IdQmkRgbMatrixChannel as u8,
                 ViaQmkRgbMatrixValue::IdQmkRgbMatrixBrightness as u8,
             ],
+            1,
         )
-        .map(|val| val[3])
+        .map(|val| val[0])
     }
 
     /// Sets the RGB matrix brightness.
     pub fn set_rgb_matrix_brightness(&self, brightness: u8) -> Result<()> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("set_rgb_matrix_brightness");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("RGB matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuSetValue,
-            vec![
-                ViaChannelId::IdQmkRgbMatrixChannel as u8,
-                ViaQmkRgbMatrixValue::IdQmkRgbMatrixBrightness as u8,
-                brightness,
-            ],
-        )
-        .map(|_| ())
+        self.set_custom_value(vec![
+            ViaChannelId::IdQmkRgbMatrixChannel as u8,
+            ViaQmkRgbMatrixValue::IdQmkRgbMatrixBrightness as u8,
+            brightness,
+        ])
     }
 
     /// Gets the RGB matrix effect.
     pub fn get_rgb_matrix_effect(&self) -> Result<u8> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("get_rgb_matrix_effect");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("RGB matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuGetValue,
+        self.get_custom_value(
             vec![
                 ViaChannelId::IdQmkRgbMatrixChannel as u8,
                 ViaQmkRgbMatrixValue::IdQmkRgbMatrixEffect as u8,
             ],
+            1,
         )
-        .map(|val| val[3])
+        .map(|val| val[0])
     }
 
     /// Sets the RGB matrix effect.
     pub fn set_rgb_matrix_effect(&self, effect: u8) -> Result<()> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("set_rgb_matrix_effect");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("RGB matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuSetValue,
-            vec![
-                ViaChannelId::IdQmkRgbMatrixChannel as u8,
-                ViaQmkRgbMatrixValue::IdQmkRgbMatrixEffect as u8,
-                effect,
-            ],
-        )
-        .map(|_| ())
+        self.set_custom_value(vec![
+            ViaChannelId::IdQmkRgbMatrixChannel as u8,
+            ViaQmkRgbMatrixValue::IdQmkRgbMatrixEffect as u8,
+            effect,
+        ])
     }
 
     /// Gets the RGB matrix effect speed.
     pub fn get_rgb_matrix_effect_speed(&self) -> Result<u8> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("get_rgb_matrix_effect_speed");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("RGB matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuGetValue,
+        self.get_custom_value(
             vec![
                 ViaChannelId::IdQmkRgbMatrixChannel as u8,
                 ViaQmkRgbMatrixValue::IdQmkRgbMatrixEffectSpeed as u8,
             ],
+            1,
         )
-        .map(|val| val[3])
+        .map(|val| val[0])
     }
 
     /// Sets the RGB matrix effect speed.
     pub fn set_rgb_matrix_effect_speed(&self, speed: u8) -> Result<()> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("set_rgb_matrix_effect_speed");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("RGB matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuSetValue,
-            vec![
-                ViaChannelId::IdQmkRgbMatrixChannel as u8,
-                ViaQmkRgbMatrixValue::IdQmkRgbMatrixEffectSpeed as u8,
-                speed,
-            ],
-        )
-        .map(|_| ())
+        self.set_custom_value(vec![
+            ViaChannelId::IdQmkRgbMatrixChannel as u8,
+            ViaQmkRgbMatrixValue::IdQmkRgbMatrixEffectSpeed as u8,
+            speed,
+        ])
     }
 
     /// Gets the RGB matrix color.
     pub fn get_rgb_matrix_color(&self) -> Result<(u8, u8)> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("get_rgb_matrix_color");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("RGB matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuGetValue,
+        self.get_custom_value(
             vec![
                 ViaChannelId::IdQmkRgbMatrixChannel as u8,
                 ViaQmkRgbMatrixValue::IdQmkRgbMatrixColor as u8,
             ],
+            2,
         )
-        .map(|val| (val[3], val[4]))
+        .map(|val| (val[0], val[1]))
     }
 
     /// Sets the RGB matrix color.
     pub fn set_rgb_matrix_color(&self, hue: u8, sat: u8) -> Result<()> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("set_rgb_matrix_color");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("RGB matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuSetValue,
-            vec![
-                ViaChannelId::IdQmkRgbMatrixChannel as u8,
-                ViaQmkRgbMatrixValue::IdQmkRgbMatrixColor as u8,
-                hue,
-                sat,
-            ],
-        )
-        .map(|_| ())
+        self.set_custom_value(vec![
+            ViaChannelId::IdQmkRgbMatrixChannel as u8,
+            ViaQmkRgbMatrixValue::IdQmkRgbMatrixColor as u8,
+            hue,
+            sat,
+        ])
     }
 
     /// Gets the LED matrix brightness.
     pub fn get_led_matrix_brightness(&self) -> Result<u8> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("get_led_matrix_brightness");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("LED matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuGetValue,
+        self.get_custom_value(
             vec![
                 ViaChannelId::IdQmkLedMatrixChannel as u8,
                 ViaQmkLedMatrixValue::IdQmkLedMatrixBrightness as u8,
             ],
+            1,
         )
-        .map(|val| val[3])
+        .map(|val| val[0])
     }
 
     /// Sets the LED matrix brightness.
     pub fn set_led_matrix_brightness(&self, brightness: u8) -> Result<()> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("set_led_matrix_brightness");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("LED matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuSetValue,
-            vec![
-                ViaChannelId::IdQmkLedMatrixChannel as u8,
-                ViaQmkLedMatrixValue::IdQmkLedMatrixBrightness as u8,
-                brightness,
-            ],
-        )
-        .map(|_| ())
+        self.set_custom_value(vec![
+            ViaChannelId::IdQmkLedMatrixChannel as u8,
+            ViaQmkLedMatrixValue::IdQmkLedMatrixBrightness as u8,
+            brightness,
+        ])
     }
 
     /// Gets the LED matrix effect.
     pub fn get_led_matrix_effect(&self) -> Result<u8> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("get_led_matrix_effect");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("LED matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuGetValue,
+        self.get_custom_value(
             vec![
                 ViaChannelId::IdQmkLedMatrixChannel as u8,
                 ViaQmkLedMatrixValue::IdQmkLedMatrixEffect as u8,
             ],
+            1,
         )
-        .map(|val| val[3])
+        .map(|val| val[0])
     }
 
     /// Sets the LED matrix effect.
     pub fn set_led_matrix_effect(&self, effect: u8) -> Result<()> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("set_led_matrix_effect");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("LED matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuSetValue,
-            vec![
-                ViaChannelId::IdQmkLedMatrixChannel as u8,
-                ViaQmkLedMatrixValue::IdQmkLedMatrixEffect as u8,
-                effect,
-            ],
-        )
-        .map(|_| ())
+        self.set_custom_value(vec![
+            ViaChannelId::IdQmkLedMatrixChannel as u8,
+            ViaQmkLedMatrixValue::IdQmkLedMatrixEffect as u8,
+            effect,
+        ])
     }
 
     /// Gets the LED matrix effect speed.
     pub fn get_led_matrix_effect_speed(&self) -> Result<u8> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("get_led_matrix_effect_speed");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("LED matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuGetValue,
+        self.get_custom_value(
             vec![
                 ViaChannelId::IdQmkLedMatrixChannel as u8,
                 ViaQmkLedMatrixValue::IdQmkLedMatrixEffectSpeed as u8,
             ],
+            1,
         )
-        .map(|val| val[3])
+        .map(|val| val[0])
     }
 
     /// Sets the LED matrix effect speed.
     pub fn set_led_matrix_effect_speed(&self, speed: u8) -> Result<()> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("set_led_matrix_effect_speed");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("LED matrix"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuSetValue,
-            vec![
-                ViaChannelId::IdQmkLedMatrixChannel as u8,
-                ViaQmkLedMatrixValue::IdQmkLedMatrixEffectSpeed as u8,
-                speed,
-            ],
-        )
-        .map(|_| ())
+        self.set_custom_value(vec![
+            ViaChannelId::IdQmkLedMatrixChannel as u8,
+            ViaQmkLedMatrixValue::IdQmkLedMatrixEffectSpeed as u8,
+            speed,
+        ])
     }
 
     /// Saves the lighting settings.
     pub fn save_lighting(&self) -> Result<()> {
-        self.hid_command(ViaCommandId::CustomMenuSave, vec![])
-            .map(|_| ())
+        let _call = trace::enter_call("save_lighting");
+        self.request(&protocol::CustomMenuSave { channel: None })
     }
 
     /// Gets the audio enabled state.
     pub fn get_audio_enabled(&self) -> Result<bool> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("get_audio_enabled");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("audio"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuGetValue,
+        self.get_custom_value(
             vec![
                 ViaChannelId::IdQmkAudioChannel as u8,
                 ViaQmkAudioValue::IdQmkAudioEnable as u8,
             ],
+            1,
         )
-        .map(|val| val[3] == 1)
+        .map(|val| val[0] == 1)
     }
 
     /// Sets the audio enabled state.
     pub fn set_audio_enabled(&self, enabled: bool) -> Result<()> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("set_audio_enabled");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("audio"));
         }
-        let bytes = vec![
+        self.set_custom_value(vec![
             ViaChannelId::IdQmkAudioChannel as u8,
             ViaQmkAudioValue::IdQmkAudioEnable as u8,
             enabled as u8,
-        ];
-        self.hid_command(ViaCommandId::CustomMenuSetValue, bytes)
-            .map(|_| ())
+        ])
     }
 
     /// Gets the audio clicky enabled state.
     pub fn get_audio_clicky_enabled(&self) -> Result<bool> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("get_audio_clicky_enabled");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("audio"));
         }
-        self.hid_command(
-            ViaCommandId::CustomMenuGetValue,
+        self.get_custom_value(
             vec![
                 ViaChannelId::IdQmkAudioChannel as u8,
                 ViaQmkAudioValue::IdQmkAudioClickyEnable as u8,
             ],
+            1,
         )
-        .map(|val| val[3] == 1)
+        .map(|val| val[0] == 1)
     }
 
     /// Sets the audio clicky enabled state.
     pub fn set_audio_clicky_enabled(&self, enabled: bool) -> Result<()> {
-        if self.protocol_version < PROTOCOL_V3 {
+        let _call = trace::enter_call("set_audio_clicky_enabled");
+        if self.protocol_version() < PROTOCOL_V3 {
             return Err(Error::UnsupportedFeature("audio"));
         }
-        let bytes = vec![
+        self.set_custom_value(vec![
             ViaChannelId::IdQmkAudioChannel as u8,
             ViaQmkAudioValue::IdQmkAudioClickyEnable as u8,
             enabled as u8,
-        ];
-        self.hid_command(ViaCommandId::CustomMenuSetValue, bytes)
-            .map(|_| ())
+        ])
     }
 
     /// Gets the macro count.
     pub fn get_macro_count(&self) -> Result<u8> {
-        let bytes = vec![];
-        self.hid_command(ViaCommandId::DynamicKeymapMacroGetCount, bytes)
-            .map(|val| val[1])
+        let _call = trace::enter_call("get_macro_count");
+        self.request(&protocol::DynamicKeymapMacroGetCount)
     }
 
     fn get_macro_buffer_size(&self) -> Result<u16> {
-        let bytes = vec![];
-        self.hid_command(ViaCommandId::DynamicKeymapMacroGetBufferSize, bytes)
-            .map(|val| utils::shift_to_16_bit(val[1], val[2]))
+        self.request(&protocol::DynamicKeymapMacroGetBufferSize)
     }
 
     /// Gets the macro bytes. All macros are separated by 0x00.
     pub fn get_macro_bytes(&self) -> Result<Vec<u8>> {
+        let _call = trace::enter_call("get_macro_bytes");
         let macro_buffer_size = self.get_macro_buffer_size()? as usize;
+        let data_buffer_size = self.data_buffer_size();
         let mut all_bytes = Vec::new();
-        for offset in (0..macro_buffer_size).step_by(DATA_BUFFER_SIZE) {
-            let offset_bytes = utils::shift_from_16_bit(offset as u16);
+        for offset in (0..macro_buffer_size).step_by(data_buffer_size) {
             let remaining_bytes = macro_buffer_size - offset;
-            let bytes = vec![offset_bytes.0, offset_bytes.1, DATA_BUFFER_SIZE as u8];
-            let val = self.hid_command(ViaCommandId::DynamicKeymapMacroGetBuffer, bytes)?;
-            if remaining_bytes < DATA_BUFFER_SIZE {
-                all_bytes.extend(val[4..(4 + remaining_bytes)].to_vec())
-            } else {
-                all_bytes.extend(val[4..].to_vec())
-            }
+            let mut val = self.request(&protocol::DynamicKeymapMacroGetBuffer {
+                offset: offset as u16,
+                size: data_buffer_size as u8,
+            })?;
+            val.truncate(remaining_bytes);
+            all_bytes.extend(val);
         }
         Ok(all_bytes)
     }
 
     /// Sets the macro bytes.
     pub fn set_macro_bytes(&self, data: Vec<u8>) -> Result<()> {
+        let _call = trace::enter_call("set_macro_bytes");
         let macro_buffer_size = self.get_macro_buffer_size()?;
         let size = data.len();
-        if size > macro_buffer_size as usize {
-            return Err(Error::size_mismatch(
-                "macro data buffer overflow",
-                macro_buffer_size as usize,
-                size,
-            ));
-        }
-
+        // The last byte of the buffer marks a write in progress, so an empty buffer holds nothing
+        let last_offset = match macro_buffer_size.checked_sub(1) {
+            Some(last_offset) if size <= macro_buffer_size as usize => last_offset,
+            _ => {
+                return Err(Error::size_mismatch(
+                    "macro data buffer overflow",
+                    macro_buffer_size as usize,
+                    size,
+                ))
+            }
+        };
         self.reset_macros()?;
-
-        let last_offset = macro_buffer_size - 1;
-        let last_offset_bytes = utils::shift_from_16_bit(last_offset);
-
         // Set last byte in buffer to non-zero (0xFF) to indicate write-in-progress
-        self.hid_command(
-            ViaCommandId::DynamicKeymapMacroSetBuffer,
-            vec![last_offset_bytes.0, last_offset_bytes.1, 1, 0xff],
-        )?;
-
-        for offset in (0..data.len()).step_by(DATA_BUFFER_SIZE) {
-            let offset_bytes = utils::shift_from_16_bit(offset as u16);
-            let end = std::cmp::min(offset + DATA_BUFFER_SIZE, data.len());
-            let buffer = data[offset..end].to_vec();
-            let mut bytes = vec![offset_bytes.0, offset_bytes.1, buffer.len() as u8];
-            bytes.extend(buffer);
-            self.hid_command(ViaCommandId::DynamicKeymapMacroSetBuffer, bytes)?;
+        self.request(&protocol::DynamicKeymapMacroSetBuffer {
+            offset: last_offset,
+            data: vec![0xff],
+        })?;
+        let data_buffer_size = self.data_buffer_size();
+        for offset in (0..data.len()).step_by(data_buffer_size) {
+            let end = std::cmp::min(offset + data_buffer_size, data.len());
+            self.request(&protocol::DynamicKeymapMacroSetBuffer {
+                offset: offset as u16,
+                data: data[offset..end].to_vec(),
+            })?;
+            #[cfg(feature = "tracing")]
+            tracing::debug!(written = end, total = data.len(), "wrote macro chunk");
         }
-
         // Set last byte in buffer to zero to indicate write finished
-        self.hid_command(
-            ViaCommandId::DynamicKeymapMacroSetBuffer,
-            vec![last_offset_bytes.0, last_offset_bytes.1, 1, 0x00],
-        )?;
-
-        Ok(())
+        self.request(&protocol::DynamicKeymapMacroSetBuffer {
+            offset: last_offset,
+            data: vec![0x00],
+        })
     }
 
     /// Resets all saved macros.
     pub fn reset_macros(&self) -> Result<()> {
-        let bytes = vec![];
-        self.hid_command(ViaCommandId::DynamicKeymapMacroReset, bytes)
-            .map(|_| ())
+        let _call = trace::enter_call("reset_macros");
+        self.request(&protocol::DynamicKeymapMacroReset)
     }
 
     /// Resets the EEPROM, clearing all settings like keymaps and macros.
     pub fn reset_eeprom(&self) -> Result<()> {
-        let bytes = vec![];
-        self.hid_command(ViaCommandId::EepromReset, bytes)
-            .map(|_| ())
+        let _call = trace::enter_call("reset_eeprom");
+        self.request(&protocol::EepromReset)
     }
 
     /// Jumps to the bootloader.
+    ///
+    /// The firmware usually jumps without answering, so a missing response counts as success. The
+    /// response is awaited for a short time only, regardless of the configured timeout.
     pub fn jump_to_bootloader(&self) -> Result<()> {
-        let bytes = vec![];
-        self.hid_command(ViaCommandId::BootloaderJump, bytes)
-            .map(|_| ())
+        let _call = trace::enter_call("jump_to_bootloader");
+        let options = CommandOptions {
+            timeout_ms: Some(BOOTLOADER_JUMP_TIMEOUT_MS),
+            ..*self.options()
+        };
+        match self.request_with_options(&protocol::BootloaderJump, options) {
+            Err(Error::Timeout(_)) => Ok(()),
+            result => result,
+        }
     }
 }
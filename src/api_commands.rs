@@ -1,9 +1,20 @@
+use num_enum::TryFromPrimitive;
 #[cfg(feature = "python")]
 use pyo3::prelude::*;
 
+pub const PROTOCOL_ALPHA: u16 = 7;
+pub const PROTOCOL_BETA: u16 = 8;
+pub const PROTOCOL_GAMMA: u16 = 9;
+pub const PROTOCOL_V3: u16 = 11;
+
+/// Command byte QMK answers with when a command or channel is not handled.
+pub const ID_UNHANDLED: u8 = 0xff;
+
 // V3
 #[cfg_attr(feature = "python", pyclass(from_py_object))]
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
 pub enum ViaCommandId {
     GetProtocolVersion = 0x01,
     GetKeyboardValue = 0x02,
@@ -26,10 +37,66 @@ pub enum ViaCommandId {
     DynamicKeymapSetBuffer = 0x13,
     DynamicKeymapGetEncoder = 0x14,
     DynamicKeymapSetEncoder = 0x15,
+    VialPrefix = 0xfe,
+}
+
+impl ViaCommandId {
+    /// Returns whether the command may be sent again when its response is lost. Resets, the
+    /// bootloader jump and partial buffer writes are sent only once.
+    pub fn is_retry_safe(&self) -> bool {
+        !matches!(
+            self,
+            ViaCommandId::DynamicKeymapClearAll
+                | ViaCommandId::EepromReset
+                | ViaCommandId::BootloaderJump
+                | ViaCommandId::DynamicKeymapMacroSetBuffer
+                | ViaCommandId::DynamicKeymapMacroReset
+                | ViaCommandId::DynamicKeymapSetBuffer
+        )
+    }
 }
 
+/// Vial sub-command in the byte following [`ViaCommandId::VialPrefix`].
 #[cfg_attr(feature = "python", pyclass(from_py_object))]
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum VialCommandId {
+    GetKeyboardId = 0x00,
+    GetSize = 0x01,
+    GetDefinition = 0x02,
+    GetEncoder = 0x03,
+    SetEncoder = 0x04,
+    GetUnlockStatus = 0x05,
+    UnlockStart = 0x06,
+    UnlockPoll = 0x07,
+    Lock = 0x08,
+    QmkSettingsQuery = 0x09,
+    QmkSettingsGet = 0x0a,
+    QmkSettingsSet = 0x0b,
+    QmkSettingsReset = 0x0c,
+    DynamicEntryOp = 0x0d,
+}
+
+/// Operation on Vial dynamic entries in the byte following [`VialCommandId::DynamicEntryOp`].
+#[cfg_attr(feature = "python", pyclass(from_py_object))]
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum VialDynamicEntryOp {
+    GetNumberOfEntries = 0x00,
+    TapDanceGet = 0x01,
+    TapDanceSet = 0x02,
+    ComboGet = 0x03,
+    ComboSet = 0x04,
+    KeyOverrideGet = 0x05,
+    KeyOverrideSet = 0x06,
+}
+
+#[cfg_attr(feature = "python", pyclass(from_py_object))]
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
 pub enum ViaChannelId {
     IdCustomChannel = 0,
     IdQmkBacklightChannel = 1,
@@ -41,6 +108,7 @@ pub enum ViaChannelId {
 
 #[cfg_attr(feature = "python", pyclass(from_py_object))]
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ViaQmkBacklightValue {
     IdQmkBacklightBrightness = 1,
     IdQmkBacklightEffect = 2,
@@ -48,6 +116,7 @@ pub enum ViaQmkBacklightValue {
 
 #[cfg_attr(feature = "python", pyclass(from_py_object))]
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ViaQmkRgblightValue {
     IdQmkRgblightBrightness = 1,
     IdQmkRgblightEffect = 2,
@@ -57,6 +126,7 @@ pub enum ViaQmkRgblightValue {
 
 #[cfg_attr(feature = "python", pyclass(from_py_object))]
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ViaQmkRgbMatrixValue {
     IdQmkRgbMatrixBrightness = 1,
     IdQmkRgbMatrixEffect = 2,
@@ -66,6 +136,7 @@ pub enum ViaQmkRgbMatrixValue {
 
 #[cfg_attr(feature = "python", pyclass(from_py_object))]
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ViaQmkLedMatrixValue {
     IdQmkLedMatrixBrightness = 1,
     IdQmkLedMatrixEffect = 2,
@@ -74,6 +145,7 @@ pub enum ViaQmkLedMatrixValue {
 
 #[cfg_attr(feature = "python", pyclass(from_py_object))]
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ViaQmkAudioValue {
     IdQmkAudioEnable = 1,
     IdQmkAudioClickyEnable = 2,
@@ -82,6 +154,7 @@ pub enum ViaQmkAudioValue {
 // V2
 #[cfg_attr(feature = "python", pyclass(from_py_object))]
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ViaLightingValue {
     IdBacklightBrightness = 0x09,
     IdBacklightEffect = 0x0a,
@@ -90,3 +163,30 @@ pub enum ViaLightingValue {
     IdQmkRgblightEffectSpeed = 0x82,
     IdQmkRgblightColor = 0x83,
 }
+
+#[cfg_attr(feature = "python", pyclass(from_py_object))]
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(u8)]
+pub enum KeyboardValue {
+    Uptime = 0x01,
+    LayoutOptions = 0x02,
+    SwitchMatrixState = 0x03,
+    FirmwareVersion = 0x04,
+    DeviceIndication = 0x05,
+}
+
+impl core::str::FromStr for KeyboardValue {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        match s {
+            "Uptime" => Ok(KeyboardValue::Uptime),
+            "LayoutOptions" => Ok(KeyboardValue::LayoutOptions),
+            "SwitchMatrixState" => Ok(KeyboardValue::SwitchMatrixState),
+            "FirmwareVersion" => Ok(KeyboardValue::FirmwareVersion),
+            "DeviceIndication" => Ok(KeyboardValue::DeviceIndication),
+            _ => Err("Invalid KeyboardValue"),
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use crate::api::{Column, KeyboardApi, KeyboardValue, Layer, MatrixInfo, Row};
+use crate::api_commands::ViaCommandId;
+use crate::qmk_settings::{QmkSettingType, QmkSettingValue};
+use crate::scan::{KeyboardCapabilities, KeyboardDeviceInfo};
+use crate::vial::{Combo, KeyOverride, TapDance, VialEntryCounts, VialKeyboardId};
+use crate::{Error, Result};
+use std::sync::mpsc;
+use std::thread;
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce(&KeyboardApi) + Send>;
+
+/// Asynchronous interface to a VIA keyboard.
+///
+/// The underlying [`KeyboardApi`] is owned by a dedicated worker thread which performs all
+/// blocking HID I/O. Every method runs as a single job on that thread, so multi-step operations
+/// like [`AsyncKeyboardApi::set_macro_bytes`] are never interleaved with other commands.
+/// Clones share the same worker thread, which exits once the last clone is dropped.
+#[derive(Clone)]
+pub struct AsyncKeyboardApi {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl AsyncKeyboardApi {
+    /// Opens the keyboard with the given vendor id, product id and usage page.
+    pub async fn new(vid: u16, pid: u16, usage_page: u16) -> Result<AsyncKeyboardApi> {
+        Self::spawn(move || KeyboardApi::new(vid, pid, usage_page)).await
+    }
+
+    /// Opens the given keyboard.
+    pub async fn from_device(device: KeyboardDeviceInfo) -> Result<AsyncKeyboardApi> {
+        Self::spawn(move || KeyboardApi::from_device(&device)).await
+    }
+
+    /// Opens the keyboard with the given platform specific HID path.
+    pub async fn from_path(path: String) -> Result<AsyncKeyboardApi> {
+        Self::spawn(move || KeyboardApi::from_path(&path)).await
+    }
+
+    /// Opens the VIA keyboard with the given serial number.
+    pub async fn from_serial_number(serial_number: String) -> Result<AsyncKeyboardApi> {
+        Self::spawn(move || KeyboardApi::from_serial_number(&serial_number)).await
+    }
+
+    /// Moves an already opened keyboard to a new worker thread.
+    pub fn from_api(api: KeyboardApi) -> AsyncKeyboardApi {
+        let (jobs, receiver) = mpsc::channel();
+        thread::spawn(move || Self::work(api, receiver));
+        AsyncKeyboardApi { jobs }
+    }
+
+    /// Opens a keyboard on a new worker thread, so that opening does not block the runtime either.
+    pub async fn spawn<F>(open: F) -> Result<AsyncKeyboardApi>
+    where
+        F: FnOnce() -> Result<KeyboardApi> + Send + 'static,
+    {
+        let (jobs, receiver) = mpsc::channel();
+        let (opened_sender, opened) = oneshot::channel();
+        thread::spawn(move || match open() {
+            Ok(api) => {
+                if opened_sender.send(Ok(())).is_ok() {
+                    Self::work(api, receiver);
+                }
+            }
+            Err(err) => {
+                let _ = opened_sender.send(Err(err));
+            }
+        });
+        opened.await.map_err(|_| Self::worker_stopped())??;
+        Ok(AsyncKeyboardApi { jobs })
+    }
+
+    fn work(api: KeyboardApi, receiver: mpsc::Receiver<Job>) {
+        for job in receiver {
+            job(&api);
+        }
+    }
+
+    fn worker_stopped() -> Error {
+        Error::Io(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            "keyboard worker thread stopped",
+        ))
+    }
+
+    /// Runs a closure with exclusive access to the underlying [`KeyboardApi`] on the worker thread.
+    ///
+    /// This can be used to perform custom multi-step operations without other commands being
+    /// interleaved.
+    pub async fn call<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&KeyboardApi) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (result_sender, result) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |api| {
+                let _ = result_sender.send(f(api));
+            }))
+            .map_err(|_| Self::worker_stopped())?;
+        result.await.map_err(|_| Self::worker_stopped())?
+    }
+}
+
+macro_rules! async_methods {
+    ($(fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        impl AsyncKeyboardApi {
+            $(
+                #[doc = concat!("Asynchronous version of [`KeyboardApi::", stringify!($name), "`].")]
+                pub async fn $name(&self, $($arg: $ty),*) -> Result<$ret> {
+                    self.call(move |api| api.$name($($arg),*)).await
+                }
+            )*
+        }
+    };
+}
+
+async_methods! {
+    fn hid_command(command: ViaCommandId, bytes: Vec<u8>) -> Vec<u8>;
+    fn hid_read() -> Vec<u8>;
+    fn hid_send(bytes: Vec<u8>) -> ();
+    fn get_protocol_version() -> u16;
+    fn get_capabilities() -> KeyboardCapabilities;
+    fn get_layer_count() -> u8;
+    fn get_key(layer: Layer, row: Row, col: Column) -> u16;
+    fn set_key(layer: Layer, row: Row, column: Column, val: u16) -> u16;
+    fn read_raw_matrix(matrix_info: MatrixInfo, layer: Layer) -> Vec<u16>;
+    fn write_raw_matrix(matrix_info: MatrixInfo, keymap: Vec<Vec<u16>>) -> ();
+    fn get_keyboard_value(command: KeyboardValue, parameters: Vec<u8>, result_length: usize) -> Vec<u8>;
+    fn set_keyboard_value(command: KeyboardValue, parameters: Vec<u8>) -> ();
+    fn get_encoder_value(layer: Layer, id: u8, is_clockwise: bool) -> u16;
+    fn set_encoder_value(layer: Layer, id: u8, is_clockwise: bool, keycode: u16) -> ();
+    fn get_custom_menu_value(command_bytes: Vec<u8>) -> Vec<u8>;
+    fn set_custom_menu_value(args: Vec<u8>) -> ();
+    fn save_custom_menu(channel: u8) -> ();
+    fn get_backlight_brightness() -> u8;
+    fn set_backlight_brightness(brightness: u8) -> ();
+    fn get_backlight_effect() -> u8;
+    fn set_backlight_effect(effect: u8) -> ();
+    fn get_rgblight_brightness() -> u8;
+    fn set_rgblight_brightness(brightness: u8) -> ();
+    fn get_rgblight_effect() -> u8;
+    fn set_rgblight_effect(effect: u8) -> ();
+    fn get_rgblight_effect_speed() -> u8;
+    fn set_rgblight_effect_speed(speed: u8) -> ();
+    fn get_rgblight_color() -> (u8, u8);
+    fn set_rgblight_color(hue: u8, sat: u8) -> ();
+    fn get_rgb_matrix_brightness() -> u8;
+    fn set_rgb_matrix_brightness(brightness: u8) -> ();
+    fn get_rgb_matrix_effect() -> u8;
+    fn set_rgb_matrix_effect(effect: u8) -> ();
+    fn get_rgb_matrix_effect_speed() -> u8;
+    fn set_rgb_matrix_effect_speed(speed: u8) -> ();
+    fn get_rgb_matrix_color() -> (u8, u8);
+    fn set_rgb_matrix_color(hue: u8, sat: u8) -> ();
+    fn get_led_matrix_brightness() -> u8;
+    fn set_led_matrix_brightness(brightness: u8) -> ();
+    fn get_led_matrix_effect() -> u8;
+    fn set_led_matrix_effect(effect: u8) -> ();
+    fn get_led_matrix_effect_speed() -> u8;
+    fn set_led_matrix_effect_speed(speed: u8) -> ();
+    fn save_lighting() -> ();
+    fn get_audio_enabled() -> bool;
+    fn set_audio_enabled(enabled: bool) -> ();
+    fn get_audio_clicky_enabled() -> bool;
+    fn set_audio_clicky_enabled(enabled: bool) -> ();
+    fn get_macro_count() -> u8;
+    fn get_macro_bytes() -> Vec<u8>;
+    fn set_macro_bytes(data: Vec<u8>) -> ();
+    fn reset_macros() -> ();
+    fn reset_eeprom() -> ();
+    fn jump_to_bootloader() -> ();
+    fn get_vial_keyboard_id() -> VialKeyboardId;
+    fn get_vial_definition_compressed() -> Vec<u8>;
+    fn get_vial_entry_counts() -> VialEntryCounts;
+    fn get_tap_dance_count() -> u8;
+    fn get_tap_dance(index: u8) -> TapDance;
+    fn get_tap_dances() -> Vec<TapDance>;
+    fn set_tap_dance(index: u8, tap_dance: TapDance) -> ();
+    fn get_combo_count() -> u8;
+    fn get_combo(index: u8) -> Combo;
+    fn get_combos() -> Vec<Combo>;
+    fn set_combo(index: u8, combo: Combo) -> ();
+    fn clear_combo(index: u8) -> ();
+    fn get_key_override_count() -> u8;
+    fn get_key_override(index: u8) -> KeyOverride;
+    fn get_key_overrides() -> Vec<KeyOverride>;
+    fn set_key_override(index: u8, key_override: KeyOverride) -> ();
+    fn get_qmk_setting_qsids() -> Vec<u16>;
+    fn get_qmk_setting(qsid: u16) -> QmkSettingValue;
+    fn get_qmk_setting_as(qsid: u16, setting_type: QmkSettingType) -> QmkSettingValue;
+    fn set_qmk_setting(qsid: u16, value: QmkSettingValue) -> ();
+    fn reset_qmk_settings() -> ();
+}
+
+impl AsyncKeyboardApi {
+    /// Asynchronous version of [`KeyboardApi::get_qmk_setting_flag`].
+    pub async fn get_qmk_setting_flag(&self, name: String) -> Result<bool> {
+        self.call(move |api| api.get_qmk_setting_flag(&name)).await
+    }
+
+    /// Asynchronous version of [`KeyboardApi::set_qmk_setting_flag`].
+    pub async fn set_qmk_setting_flag(&self, name: String, enabled: bool) -> Result<()> {
+        self.call(move |api| api.set_qmk_setting_flag(&name, enabled))
+            .await
+    }
+}
+
+#[cfg(feature = "vial-definition")]
+async_methods! {
+    fn get_vial_definition() -> serde_json::Value;
+    fn get_vial_matrix_info() -> MatrixInfo;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::VirtualKeyboard;
+    use crate::transport::Transport;
+    use std::time::Duration;
+
+    /// Emulated keyboard reporting on a channel when the worker thread dropped it.
+    struct DropNotifier {
+        keyboard: VirtualKeyboard,
+        dropped: mpsc::Sender<()>,
+    }
+
+    impl Transport for DropNotifier {
+        fn send_report(&self, data: &[u8]) -> Result<usize> {
+            self.keyboard.send_report(data)
+        }
+
+        fn read_report(&self, buffer: &mut [u8], timeout_ms: i32) -> Result<usize> {
+            self.keyboard.read_report(buffer, timeout_ms)
+        }
+
+        fn report_size(&self) -> Result<Option<usize>> {
+            self.keyboard.report_size()
+        }
+    }
+
+    impl Drop for DropNotifier {
+        fn drop(&mut self) {
+            let _ = self.dropped.send(());
+        }
+    }
+
+    fn api() -> (VirtualKeyboard, AsyncKeyboardApi) {
+        let keyboard = VirtualKeyboard::default();
+        let api = KeyboardApi::from_transport(keyboard.clone()).unwrap();
+        (keyboard, AsyncKeyboardApi::from_api(api))
+    }
+
+    #[tokio::test]
+    async fn wrapped_methods() {
+        let (keyboard, api) = api();
+        api.set_key(1, 2, 3, 0x0004).await.unwrap();
+        assert_eq!(api.get_key(1, 2, 3).await.unwrap(), 0x0004);
+        assert_eq!(keyboard.key(1, 2, 3), 0x0004);
+
+        let macros = b"hello\0world\0".to_vec();
+        api.set_macro_bytes(macros.clone()).await.unwrap();
+        assert!(api.get_macro_bytes().await.unwrap().starts_with(&macros));
+        assert!(matches!(
+            api.set_macro_bytes(vec![1; 4096]).await,
+            Err(Error::SizeMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn call_runs_multi_step_jobs() {
+        let (_, api) = api();
+        let other = api.clone();
+        let keys = api
+            .call(|api| {
+                api.set_key(0, 0, 0, 0x0005)?;
+                api.set_key(0, 0, 1, 0x0006)?;
+                Ok([api.get_key(0, 0, 0)?, api.get_key(0, 0, 1)?])
+            })
+            .await
+            .unwrap();
+        assert_eq!(keys, [0x0005, 0x0006]);
+        assert_eq!(other.get_key(0, 0, 1).await.unwrap(), 0x0006);
+
+        let result: Result<()> = api
+            .call(|_| Err(Error::InvalidArgument("job failed")))
+            .await;
+        assert!(matches!(result, Err(Error::InvalidArgument("job failed"))));
+    }
+
+    #[tokio::test]
+    async fn failed_open() {
+        let result = AsyncKeyboardApi::spawn(|| Err(Error::InvalidArgument("no keyboard"))).await;
+        assert!(matches!(result, Err(Error::InvalidArgument("no keyboard"))));
+    }
+
+    #[tokio::test]
+    async fn worker_exits_with_last_clone() {
+        let (dropped, on_dropped) = mpsc::channel();
+        let transport = DropNotifier {
+            keyboard: VirtualKeyboard::default(),
+            dropped,
+        };
+        let api = AsyncKeyboardApi::spawn(move || KeyboardApi::from_transport(transport))
+            .await
+            .unwrap();
+        let other = api.clone();
+        drop(api);
+        assert_eq!(other.get_layer_count().await.unwrap(), 4);
+        assert!(on_dropped.try_recv().is_err());
+
+        drop(other);
+        on_dropped.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stopped_worker() {
+        let (_, api) = api();
+        let result: Result<()> = api.call(|_| panic!("job panicked")).await;
+        assert!(matches!(result, Err(Error::Io(_))));
+        assert!(matches!(api.get_layer_count().await, Err(Error::Io(_))));
+    }
+}
@@ -0,0 +1,893 @@
+//! Conformance checks for VIA implementations.
+//!
+//! [`ConformanceRunner`] exercises every [`ViaCommandId`] through [`KeyboardApi`] and reports
+//! which commands behave as expected. Values that are changed by a check are restored
+//! afterwards, so the runner can be used on real keyboards.
+//!
+//! ```
+//! use qmk_via_api::api::KeyboardApi;
+//! use qmk_via_api::conformance::ConformanceRunner;
+//! use qmk_via_api::emulator::{VirtualKeyboard, VirtualKeyboardConfig};
+//!
+//! let config = VirtualKeyboardConfig::default();
+//! let api = KeyboardApi::from_transport(VirtualKeyboard::new(config.clone())?)?;
+//! let report = ConformanceRunner::new(&api, config.matrix).run();
+//! println!("{}", report);
+//! assert!(report.passed());
+//! # Ok::<(), qmk_via_api::Error>(())
+//! ```
+
+use crate::api::{KeyboardApi, KeyboardValue, MatrixInfo, PROTOCOL_BETA, PROTOCOL_V3};
+use crate::api_commands::{ViaChannelId, ViaCommandId};
+use crate::keycodes::Keycode;
+use crate::protocol;
+use crate::scan::KeyboardCapabilities;
+use crate::{vial, Error};
+
+#[cfg(feature = "python")]
+use pyo3::prelude::*;
+
+/// Outcome of a single check.
+#[cfg_attr(feature = "python", pyclass(from_py_object))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// The check was not run, e.g. because the keyboard does not support the feature
+    Skipped,
+}
+
+/// Result of a single check of a command.
+#[cfg_attr(feature = "python", pyclass(get_all, from_py_object))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConformanceCheck {
+    /// Command under test
+    pub command: ViaCommandId,
+    /// Short description of what was checked
+    pub name: String,
+    pub status: CheckStatus,
+    /// Reason for a failed or skipped check
+    pub message: Option<String>,
+}
+
+/// Results of all checks of a [`ConformanceRunner`] in the order they were run.
+#[cfg_attr(feature = "python", pyclass(get_all, skip_from_py_object))]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConformanceReport {
+    pub checks: Vec<ConformanceCheck>,
+}
+
+impl ConformanceReport {
+    /// Returns whether no check failed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.status != CheckStatus::Failed)
+    }
+
+    /// Returns the combined status of all checks of a command.
+    ///
+    /// A command failed if any of its checks failed and passed if at least one check passed.
+    pub fn command_status(&self, command: ViaCommandId) -> CheckStatus {
+        let statuses = self
+            .checks
+            .iter()
+            .filter(|c| c.command == command)
+            .map(|c| c.status);
+        statuses.fold(CheckStatus::Skipped, |combined, status| {
+            match (combined, status) {
+                (CheckStatus::Failed, _) | (_, CheckStatus::Failed) => CheckStatus::Failed,
+                (CheckStatus::Passed, _) | (_, CheckStatus::Passed) => CheckStatus::Passed,
+                _ => CheckStatus::Skipped,
+            }
+        })
+    }
+}
+
+impl std::fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut commands: Vec<ViaCommandId> = Vec::new();
+        for check in &self.checks {
+            if !commands.contains(&check.command) {
+                commands.push(check.command);
+            }
+        }
+        for command in commands {
+            writeln!(f, "{:?}: {:?}", command, self.command_status(command))?;
+            for check in self.checks.iter().filter(|c| c.command == command) {
+                match &check.message {
+                    Some(message) => {
+                        writeln!(f, "  {:?} {} ({})", check.status, check.name, message)?
+                    }
+                    None => writeln!(f, "  {:?} {}", check.status, check.name)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reason a check did not pass.
+enum Failure {
+    Error(Error),
+    Mismatch(String),
+    Skip(String),
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Self {
+        Failure::Error(err)
+    }
+}
+
+type CheckResult = std::result::Result<(), Failure>;
+
+/// Fails the check if the values differ.
+fn expect_eq<T: PartialEq + std::fmt::Debug>(actual: T, expected: T, what: &str) -> CheckResult {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(Failure::Mismatch(format!(
+            "{}: expected {:?}, got {:?}",
+            what, expected, actual
+        )))
+    }
+}
+
+/// Returns a keycode different from `keycode` to write in round trip checks.
+fn test_keycode(keycode: u16) -> u16 {
+    if keycode == Keycode::KC_A as u16 {
+        Keycode::KC_B as u16
+    } else {
+        Keycode::KC_A as u16
+    }
+}
+
+/// Reads all layers of the keymap.
+fn read_keymap(
+    api: &KeyboardApi,
+    matrix: MatrixInfo,
+    layer_count: u8,
+) -> crate::Result<Vec<Vec<u16>>> {
+    (0..layer_count)
+        .map(|layer| api.read_raw_matrix(matrix, layer))
+        .collect()
+}
+
+/// Runs the conformance checks against a keyboard.
+///
+/// Commands that reset the keyboard are only run if enabled with
+/// [`ConformanceRunner::destructive`]. [`ViaCommandId::BootloaderJump`] is never sent as the
+/// keyboard would disconnect.
+pub struct ConformanceRunner<'a> {
+    api: &'a KeyboardApi,
+    matrix: MatrixInfo,
+    destructive: bool,
+}
+
+impl<'a> ConformanceRunner<'a> {
+    /// Creates a runner for a keyboard with the given switch matrix size.
+    pub fn new(api: &'a KeyboardApi, matrix: MatrixInfo) -> Self {
+        ConformanceRunner {
+            api,
+            matrix,
+            destructive: false,
+        }
+    }
+
+    /// Sets whether the keymap and EEPROM reset commands are checked.
+    ///
+    /// The keymap and macros are restored after the resets, all other settings are lost.
+    pub fn destructive(mut self, destructive: bool) -> Self {
+        self.destructive = destructive;
+        self
+    }
+
+    /// Runs all checks. Checks that cannot complete are reported as failed, the run continues.
+    pub fn run(&self) -> ConformanceReport {
+        let mut run = Run {
+            api: self.api,
+            matrix: self.matrix,
+            report: ConformanceReport::default(),
+        };
+
+        run.check(ViaCommandId::GetProtocolVersion, "read version", |api| {
+            let version = api.get_protocol_version()?;
+            match version {
+                0 => Err(Failure::Mismatch("version is 0".to_string())),
+                _ => Ok(()),
+            }
+        });
+        let capabilities = match self.api.get_capabilities() {
+            Ok(capabilities) => capabilities,
+            Err(err) => {
+                run.record(
+                    ViaCommandId::GetProtocolVersion,
+                    "query capabilities",
+                    Err(err.into()),
+                );
+                return run.report;
+            }
+        };
+
+        run.check_keymap(&capabilities);
+        run.check_keymap_buffer(&capabilities);
+        run.check_encoders(&capabilities);
+        run.check_macros(&capabilities);
+        run.check_lighting(&capabilities);
+        run.check_keyboard_values(&capabilities);
+        run.check_vial(&capabilities);
+        run.check_resets(self.destructive);
+        run.report
+    }
+}
+
+struct Run<'a> {
+    api: &'a KeyboardApi,
+    matrix: MatrixInfo,
+    report: ConformanceReport,
+}
+
+impl Run<'_> {
+    fn record(&mut self, command: ViaCommandId, name: &str, result: CheckResult) {
+        let (status, message) = match result {
+            Ok(()) => (CheckStatus::Passed, None),
+            Err(Failure::Error(err)) => (CheckStatus::Failed, Some(err.to_string())),
+            Err(Failure::Mismatch(message)) => (CheckStatus::Failed, Some(message)),
+            Err(Failure::Skip(reason)) => (CheckStatus::Skipped, Some(reason)),
+        };
+        self.report.checks.push(ConformanceCheck {
+            command,
+            name: name.to_string(),
+            status,
+            message,
+        });
+    }
+
+    fn check(
+        &mut self,
+        command: ViaCommandId,
+        name: &str,
+        f: impl FnOnce(&KeyboardApi) -> CheckResult,
+    ) {
+        let result = f(self.api);
+        self.record(command, name, result);
+    }
+
+    /// Runs a check of a feature that firmware may leave out, which is skipped if unhandled.
+    fn check_optional(
+        &mut self,
+        command: ViaCommandId,
+        name: &str,
+        f: impl FnOnce(&KeyboardApi) -> CheckResult,
+    ) {
+        let result = match f(self.api) {
+            Err(Failure::Error(Error::UnsupportedCommand { .. })) => {
+                Err(Failure::Skip("not handled by the keyboard".to_string()))
+            }
+            result => result,
+        };
+        self.record(command, name, result);
+    }
+
+    fn skip(&mut self, commands: &[ViaCommandId], reason: &str) {
+        for command in commands {
+            self.record(*command, "all", Err(Failure::Skip(reason.to_string())));
+        }
+    }
+
+    /// Returns the positions of the first and the last key of the keymap.
+    fn corner_keys(&self, layer_count: u8) -> [(u8, u8, u8); 2] {
+        [
+            (0, 0, 0),
+            (
+                layer_count.saturating_sub(1),
+                self.matrix.rows.saturating_sub(1),
+                self.matrix.cols.saturating_sub(1),
+            ),
+        ]
+    }
+
+    fn check_keymap(&mut self, capabilities: &KeyboardCapabilities) {
+        if capabilities.protocol_version >= PROTOCOL_BETA {
+            self.check(
+                ViaCommandId::DynamicKeymapGetLayerCount,
+                "read count",
+                |_| match capabilities.layer_count {
+                    0 => Err(Failure::Mismatch("layer count is 0".to_string())),
+                    _ => Ok(()),
+                },
+            );
+        } else {
+            self.skip(
+                &[ViaCommandId::DynamicKeymapGetLayerCount],
+                "requires protocol version 8",
+            );
+        }
+
+        for (layer, row, col) in self.corner_keys(capabilities.layer_count) {
+            let name = format!("layer {} row {} col {}", layer, row, col);
+            let mut original = None;
+            self.check(ViaCommandId::DynamicKeymapGetKeycode, &name, |api| {
+                original = Some(api.get_key(layer, row, col)?);
+                Ok(())
+            });
+            let Some(original) = original else {
+                continue;
+            };
+            self.check(ViaCommandId::DynamicKeymapSetKeycode, &name, |api| {
+                let keycode = test_keycode(original);
+                let write_result = api.set_key(layer, row, col, keycode);
+                let read_result = api.get_key(layer, row, col);
+                api.set_key(layer, row, col, original)?;
+                expect_eq(write_result?, keycode, "echoed keycode")?;
+                expect_eq(read_result?, keycode, "keycode after write")?;
+                expect_eq(api.get_key(layer, row, col)?, original, "restored keycode")
+            });
+        }
+    }
+
+    fn check_keymap_buffer(&mut self, capabilities: &KeyboardCapabilities) {
+        let buffer_commands = [
+            ViaCommandId::DynamicKeymapGetBuffer,
+            ViaCommandId::DynamicKeymapSetBuffer,
+        ];
+        if capabilities.protocol_version < PROTOCOL_BETA {
+            self.skip(&buffer_commands, "requires protocol version 8");
+            return;
+        }
+        let (rows, cols) = (self.matrix.rows, self.matrix.cols);
+        let layer_size = rows as u16 * cols as u16 * 2;
+        let layer_count = capabilities.layer_count as u16;
+        if layer_size == 0 || layer_count == 0 {
+            self.skip(&buffer_commands, "empty keymap");
+            return;
+        }
+
+        // The last key of each layer followed by the first key of the next layer
+        for layer in 1..layer_count {
+            let name = format!("layer {} to {} boundary", layer - 1, layer);
+            self.check(ViaCommandId::DynamicKeymapGetBuffer, &name, |api| {
+                let previous = (layer - 1) as u8;
+                let mut expected = api
+                    .get_key(previous, rows - 1, cols - 1)?
+                    .to_be_bytes()
+                    .to_vec();
+                expected.extend(api.get_key(layer as u8, 0, 0)?.to_be_bytes());
+                let actual = api.request(&protocol::DynamicKeymapGetBuffer {
+                    offset: layer * layer_size - 2,
+                    size: 4,
+                })?;
+                expect_eq(actual, expected, "buffer")
+            });
+        }
+        self.check(
+            ViaCommandId::DynamicKeymapGetBuffer,
+            "end of keymap",
+            |api| {
+                let last_layer = (layer_count - 1) as u8;
+                let expected = api.get_key(last_layer, rows - 1, cols - 1)?.to_be_bytes();
+                let actual = api.request(&protocol::DynamicKeymapGetBuffer {
+                    offset: layer_count * layer_size - 2,
+                    size: 2,
+                })?;
+                expect_eq(actual, expected.to_vec(), "buffer")
+            },
+        );
+
+        let keys = if layer_count > 1 { 2 } else { 1 };
+        let offset = layer_size - 2;
+        self.check(
+            ViaCommandId::DynamicKeymapSetBuffer,
+            "write across layers",
+            |api| {
+                let original = api.request(&protocol::DynamicKeymapGetBuffer {
+                    offset,
+                    size: keys * 2,
+                })?;
+                let keycodes: Vec<u16> = original
+                    .chunks(2)
+                    .map(|keycode| test_keycode(u16::from_be_bytes([keycode[0], keycode[1]])))
+                    .collect();
+                let data: Vec<u8> = keycodes.iter().flat_map(|k| k.to_be_bytes()).collect();
+                let write_result = api.request(&protocol::DynamicKeymapSetBuffer { offset, data });
+                let first = api.get_key(0, rows - 1, cols - 1);
+                let second = match keys {
+                    2 => api.get_key(1, 0, 0).map(Some),
+                    _ => Ok(None),
+                };
+                api.request(&protocol::DynamicKeymapSetBuffer {
+                    offset,
+                    data: original.clone(),
+                })?;
+                write_result?;
+                expect_eq(first?, keycodes[0], "last key of layer 0")?;
+                expect_eq(second?, keycodes.get(1).copied(), "first key of layer 1")?;
+                let restored = api.request(&protocol::DynamicKeymapGetBuffer {
+                    offset,
+                    size: keys * 2,
+                })?;
+                expect_eq(restored, original, "restored buffer")
+            },
+        );
+    }
+
+    fn check_encoders(&mut self, capabilities: &KeyboardCapabilities) {
+        if !capabilities.has_encoders {
+            self.skip(
+                &[
+                    ViaCommandId::DynamicKeymapGetEncoder,
+                    ViaCommandId::DynamicKeymapSetEncoder,
+                ],
+                "keyboard has no encoders",
+            );
+            return;
+        }
+        for clockwise in [true, false] {
+            let name = match clockwise {
+                true => "encoder 0 clockwise",
+                false => "encoder 0 counter-clockwise",
+            };
+            let mut original = None;
+            self.check(ViaCommandId::DynamicKeymapGetEncoder, name, |api| {
+                original = Some(api.get_encoder_value(0, 0, clockwise)?);
+                Ok(())
+            });
+            let Some(original) = original else {
+                continue;
+            };
+            self.check(ViaCommandId::DynamicKeymapSetEncoder, name, |api| {
+                let keycode = test_keycode(original);
+                let write_result = api.set_encoder_value(0, 0, clockwise, keycode);
+                let read_result = api.get_encoder_value(0, 0, clockwise);
+                api.set_encoder_value(0, 0, clockwise, original)?;
+                write_result?;
+                expect_eq(read_result?, keycode, "keycode after write")?;
+                expect_eq(
+                    api.get_encoder_value(0, 0, clockwise)?,
+                    original,
+                    "restored keycode",
+                )
+            });
+        }
+    }
+
+    fn check_macros(&mut self, capabilities: &KeyboardCapabilities) {
+        let macro_commands = [
+            ViaCommandId::DynamicKeymapMacroGetCount,
+            ViaCommandId::DynamicKeymapMacroGetBufferSize,
+            ViaCommandId::DynamicKeymapMacroGetBuffer,
+            ViaCommandId::DynamicKeymapMacroSetBuffer,
+            ViaCommandId::DynamicKeymapMacroReset,
+        ];
+        if capabilities.macro_buffer_size == 0 {
+            self.skip(&macro_commands, "keyboard has no macro buffer");
+            return;
+        }
+        let size = capabilities.macro_buffer_size;
+
+        self.check(
+            ViaCommandId::DynamicKeymapMacroGetCount,
+            "read count",
+            |api| {
+                expect_eq(
+                    api.get_macro_count()?,
+                    capabilities.macro_count,
+                    "macro count",
+                )
+            },
+        );
+        self.check(
+            ViaCommandId::DynamicKeymapMacroGetBufferSize,
+            "read size",
+            |api| {
+                let actual = api.request(&protocol::DynamicKeymapMacroGetBufferSize)?;
+                expect_eq(actual, size, "buffer size")
+            },
+        );
+        let mut original = None;
+        self.check(
+            ViaCommandId::DynamicKeymapMacroGetBuffer,
+            "read buffer",
+            |api| {
+                let bytes = api.get_macro_bytes()?;
+                expect_eq(bytes.len(), size as usize, "buffer length")?;
+                original = Some(bytes);
+                Ok(())
+            },
+        );
+        let Some(original) = original else {
+            self.skip(
+                &macro_commands[3..],
+                "the original macros could not be read",
+            );
+            return;
+        };
+
+        self.check(
+            ViaCommandId::DynamicKeymapMacroSetBuffer,
+            "write-in-progress marker",
+            |api| {
+                api.request(&protocol::DynamicKeymapMacroSetBuffer {
+                    offset: size - 1,
+                    data: vec![0xff],
+                })?;
+                let marker = api.request(&protocol::DynamicKeymapMacroGetBuffer {
+                    offset: size - 1,
+                    size: 1,
+                })?;
+                expect_eq(marker, vec![0xff], "marker")
+            },
+        );
+        self.check(
+            ViaCommandId::DynamicKeymapMacroSetBuffer,
+            "write macros",
+            |api| {
+                let data = b"via\0".to_vec();
+                if data.len() > size as usize {
+                    return Err(Failure::Skip("macro buffer too small".to_string()));
+                }
+                api.set_macro_bytes(data.clone())?;
+                let bytes = api.get_macro_bytes()?;
+                expect_eq(&bytes[..data.len()], &data[..], "macros")?;
+                expect_eq(bytes[size as usize - 1], 0, "marker after write")
+            },
+        );
+        self.check(ViaCommandId::DynamicKeymapMacroReset, "reset", |api| {
+            api.reset_macros()?;
+            let bytes = api.get_macro_bytes()?;
+            expect_eq(bytes.iter().all(|b| *b == 0), true, "buffer cleared")
+        });
+        self.check(
+            ViaCommandId::DynamicKeymapMacroSetBuffer,
+            "restore macros",
+            |api| {
+                api.set_macro_bytes(original.clone())?;
+                expect_eq(api.get_macro_bytes()?, original, "restored macros")
+            },
+        );
+    }
+
+    fn check_lighting(&mut self, capabilities: &KeyboardCapabilities) {
+        let lighting_commands = [
+            ViaCommandId::CustomMenuGetValue,
+            ViaCommandId::CustomMenuSetValue,
+            ViaCommandId::CustomMenuSave,
+        ];
+        if capabilities.protocol_version < PROTOCOL_V3 {
+            self.skip(&lighting_commands, "requires protocol V3");
+            return;
+        }
+        if capabilities.lighting_channels.is_empty() {
+            self.skip(&lighting_commands, "keyboard has no lighting channels");
+            return;
+        }
+
+        type Getter = fn(&KeyboardApi) -> crate::Result<u8>;
+        type Setter = fn(&KeyboardApi, u8) -> crate::Result<()>;
+        for channel in capabilities.lighting_channels.iter().copied() {
+            let (get, set): (Getter, Setter) = match channel {
+                ViaChannelId::IdQmkBacklightChannel => (
+                    KeyboardApi::get_backlight_brightness,
+                    KeyboardApi::set_backlight_brightness,
+                ),
+                ViaChannelId::IdQmkRgblightChannel => (
+                    KeyboardApi::get_rgblight_brightness,
+                    KeyboardApi::set_rgblight_brightness,
+                ),
+                ViaChannelId::IdQmkRgbMatrixChannel => (
+                    KeyboardApi::get_rgb_matrix_brightness,
+                    KeyboardApi::set_rgb_matrix_brightness,
+                ),
+                ViaChannelId::IdQmkLedMatrixChannel => (
+                    KeyboardApi::get_led_matrix_brightness,
+                    KeyboardApi::set_led_matrix_brightness,
+                ),
+                ViaChannelId::IdQmkAudioChannel => (
+                    |api| api.get_audio_enabled().map(u8::from),
+                    |api, enabled| api.set_audio_enabled(enabled != 0),
+                ),
+                ViaChannelId::IdCustomChannel => continue,
+            };
+
+            let name = format!("{:?}", channel);
+            let mut original = None;
+            self.check(ViaCommandId::CustomMenuGetValue, &name, |api| {
+                original = Some(get(api)?);
+                Ok(())
+            });
+            let Some(original) = original else {
+                continue;
+            };
+            self.check(ViaCommandId::CustomMenuSetValue, &name, |api| {
+                let value = if original == 0 { 1 } else { 0 };
+                let write_result = set(api, value);
+                let read_result = get(api);
+                set(api, original)?;
+                write_result?;
+                expect_eq(read_result?, value, "value after write")?;
+                expect_eq(get(api)?, original, "restored value")
+            });
+            self.check(ViaCommandId::CustomMenuSave, &name, |api| {
+                Ok(api.save_custom_menu(channel as u8)?)
+            });
+        }
+    }
+
+    fn check_keyboard_values(&mut self, capabilities: &KeyboardCapabilities) {
+        let v3 = capabilities.protocol_version >= PROTOCOL_V3;
+
+        self.check_optional(ViaCommandId::GetKeyboardValue, "Uptime", |api| {
+            let read = || -> crate::Result<u32> {
+                let bytes = api.get_keyboard_value(KeyboardValue::Uptime, vec![], 4)?;
+                Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            };
+            let first = read()?;
+            let second = read()?;
+            match second >= first {
+                true => Ok(()),
+                false => Err(Failure::Mismatch(format!(
+                    "uptime went backwards from {} to {}",
+                    first, second
+                ))),
+            }
+        });
+        let mut layout_options = None;
+        self.check_optional(ViaCommandId::GetKeyboardValue, "LayoutOptions", |api| {
+            layout_options =
+                Some(api.get_keyboard_value(KeyboardValue::LayoutOptions, vec![], 4)?);
+            Ok(())
+        });
+        self.check_optional(ViaCommandId::GetKeyboardValue, "SwitchMatrixState", |api| {
+            api.get_keyboard_value(KeyboardValue::SwitchMatrixState, vec![], 1)?;
+            Ok(())
+        });
+        if v3 {
+            self.check(ViaCommandId::GetKeyboardValue, "FirmwareVersion", |api| {
+                api.get_keyboard_value(KeyboardValue::FirmwareVersion, vec![], 4)?;
+                Ok(())
+            });
+        }
+
+        match layout_options {
+            Some(layout_options) => {
+                self.check_optional(ViaCommandId::SetKeyboardValue, "LayoutOptions", |api| {
+                    api.set_keyboard_value(KeyboardValue::LayoutOptions, layout_options.clone())?;
+                    let actual = api.get_keyboard_value(KeyboardValue::LayoutOptions, vec![], 4)?;
+                    expect_eq(actual, layout_options, "layout options")
+                })
+            }
+            None => self.record(
+                ViaCommandId::SetKeyboardValue,
+                "LayoutOptions",
+                Err(Failure::Skip(
+                    "the original layout options could not be read".to_string(),
+                )),
+            ),
+        }
+        if v3 {
+            self.check(ViaCommandId::SetKeyboardValue, "DeviceIndication", |api| {
+                Ok(api.set_keyboard_value(KeyboardValue::DeviceIndication, vec![])?)
+            });
+        }
+    }
+
+    fn check_vial(&mut self, capabilities: &KeyboardCapabilities) {
+        if !capabilities.vial {
+            let skip = Err(Failure::Skip("not a Vial keyboard".to_string()));
+            self.record(ViaCommandId::VialPrefix, "keyboard id", skip);
+            return;
+        }
+        self.check(ViaCommandId::VialPrefix, "keyboard id", |api| {
+            let keyboard_id = api.request(&vial::GetKeyboardId)?;
+            expect_eq(keyboard_id, api.get_vial_keyboard_id()?, "keyboard id")
+        });
+    }
+
+    fn check_resets(&mut self, destructive: bool) {
+        self.skip(
+            &[ViaCommandId::BootloaderJump],
+            "the keyboard would disconnect",
+        );
+        if !destructive {
+            self.skip(
+                &[
+                    ViaCommandId::DynamicKeymapClearAll,
+                    ViaCommandId::EepromReset,
+                ],
+                "destructive checks are disabled",
+            );
+            return;
+        }
+
+        let matrix = self.matrix;
+        let keymap = self
+            .api
+            .get_layer_count()
+            .and_then(|layer_count| read_keymap(self.api, matrix, layer_count));
+        let macros = self.api.get_macro_bytes().ok();
+        let keymap = match keymap {
+            Ok(keymap) => keymap,
+            Err(err) => {
+                let reason = format!("the original keymap could not be read: {}", err);
+                self.skip(
+                    &[
+                        ViaCommandId::DynamicKeymapClearAll,
+                        ViaCommandId::EepromReset,
+                    ],
+                    &reason,
+                );
+                return;
+            }
+        };
+
+        // Both resets restore the default keymap, which is unknown but has to be the same
+        let layer_count = keymap.len() as u8;
+        let mut default_keymap = None;
+        self.check_optional(ViaCommandId::DynamicKeymapClearAll, "reset keymap", |api| {
+            if let Some(original) = keymap.first().and_then(|layer| layer.first()) {
+                api.set_key(0, 0, 0, test_keycode(*original))?;
+            }
+            api.request(&protocol::DynamicKeymapClearAll)?;
+            default_keymap = Some(read_keymap(api, matrix, layer_count)?);
+            Ok(())
+        });
+        self.check(ViaCommandId::EepromReset, "reset", |api| {
+            Ok(api.reset_eeprom()?)
+        });
+        if let Some(default_keymap) = default_keymap {
+            self.check(
+                ViaCommandId::DynamicKeymapClearAll,
+                "keymap matches EEPROM reset",
+                |api| {
+                    let keymap = read_keymap(api, matrix, layer_count)?;
+                    expect_eq(default_keymap, keymap, "keymap after keymap reset")
+                },
+            );
+        }
+        self.check(
+            ViaCommandId::EepromReset,
+            "restore keymap and macros",
+            |api| {
+                api.write_raw_matrix(matrix, keymap.clone())?;
+                if let Some(macros) = macros {
+                    api.set_macro_bytes(macros)?;
+                }
+                let layers = read_keymap(api, matrix, keymap.len() as u8)?;
+                expect_eq(layers, keymap, "restored keymap")
+            },
+        );
+    }
+}
+
+/// Runs the conformance checks against a keyboard with the given switch matrix size.
+#[cfg(feature = "python")]
+#[pyfunction]
+#[pyo3(signature = (api, matrix_info, destructive=false))]
+pub fn run_conformance(
+    api: &KeyboardApi,
+    matrix_info: MatrixInfo,
+    destructive: bool,
+) -> ConformanceReport {
+    ConformanceRunner::new(api, matrix_info)
+        .destructive(destructive)
+        .run()
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl ConformanceReport {
+    #[pyo3(name = "passed")]
+    fn py_passed(&self) -> bool {
+        self.passed()
+    }
+
+    #[pyo3(name = "command_status")]
+    fn py_command_status(&self, command: ViaCommandId) -> CheckStatus {
+        self.command_status(command)
+    }
+
+    fn __str__(&self) -> String {
+        self.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{VirtualKeyboard, VirtualKeyboardConfig, VirtualVialConfig};
+    use crate::transport::Transport;
+    use crate::Result;
+
+    /// Emulated keyboard returning corrupted macro buffer contents.
+    struct CorruptMacroReads(VirtualKeyboard);
+
+    impl Transport for CorruptMacroReads {
+        fn send_report(&self, data: &[u8]) -> Result<usize> {
+            self.0.send_report(data)
+        }
+
+        fn read_report(&self, buffer: &mut [u8], timeout_ms: i32) -> Result<usize> {
+            let bytes_read = self.0.read_report(buffer, timeout_ms)?;
+            // Keep the echoed request, corrupt the first data byte
+            if bytes_read > 4 && buffer[0] == ViaCommandId::DynamicKeymapMacroGetBuffer as u8 {
+                buffer[4] ^= 0xff;
+            }
+            Ok(bytes_read)
+        }
+    }
+
+    fn run(config: VirtualKeyboardConfig, destructive: bool) -> ConformanceReport {
+        let keyboard = VirtualKeyboard::new(config.clone()).unwrap();
+        let api = KeyboardApi::from_transport(keyboard).unwrap();
+        ConformanceRunner::new(&api, config.matrix)
+            .destructive(destructive)
+            .run()
+    }
+
+    fn status(report: &ConformanceReport, name: &str) -> Option<CheckStatus> {
+        report
+            .checks
+            .iter()
+            .find(|check| check.name == name)
+            .map(|check| check.status)
+    }
+
+    #[test]
+    fn destructive_run_passes() {
+        let report = run(VirtualKeyboardConfig::default(), true);
+        assert!(report.passed(), "{}", report);
+        assert_eq!(
+            status(&report, "keymap matches EEPROM reset"),
+            Some(CheckStatus::Passed)
+        );
+    }
+
+    #[test]
+    fn small_macro_buffer() {
+        let config = VirtualKeyboardConfig {
+            macro_buffer_size: 2,
+            ..Default::default()
+        };
+        let report = run(config, false);
+        assert!(report.passed(), "{}", report);
+        assert_eq!(status(&report, "write macros"), Some(CheckStatus::Skipped));
+        assert_eq!(status(&report, "restore macros"), Some(CheckStatus::Passed));
+    }
+
+    #[test]
+    fn corrupted_macro_reads_fail() {
+        let config = VirtualKeyboardConfig::default();
+        let keyboard = VirtualKeyboard::new(config.clone()).unwrap();
+        let api = KeyboardApi::from_transport(CorruptMacroReads(keyboard)).unwrap();
+        let report = ConformanceRunner::new(&api, config.matrix).run();
+        assert!(!report.passed(), "{}", report);
+        assert_eq!(
+            report.command_status(ViaCommandId::DynamicKeymapMacroSetBuffer),
+            CheckStatus::Failed
+        );
+        assert_eq!(
+            status(&report, "write-in-progress marker"),
+            Some(CheckStatus::Failed)
+        );
+        assert_eq!(
+            report.command_status(ViaCommandId::DynamicKeymapGetKeycode),
+            CheckStatus::Passed
+        );
+    }
+
+    #[test]
+    fn vial_keyboard_id() {
+        let report = run(VirtualKeyboardConfig::default(), false);
+        assert_eq!(status(&report, "keyboard id"), Some(CheckStatus::Skipped));
+
+        let config = VirtualKeyboardConfig {
+            vial: Some(VirtualVialConfig::default()),
+            ..Default::default()
+        };
+        let report = run(config, false);
+        assert!(report.passed(), "{}", report);
+        assert_eq!(status(&report, "keyboard id"), Some(CheckStatus::Passed));
+    }
+}
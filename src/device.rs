@@ -0,0 +1,752 @@
+//! Device side of the VIA protocol for keyboard firmware written in Rust.
+//!
+//! [`Command::parse`] turns an incoming raw HID report into a typed command and
+//! [`ViaResponder`] answers it from a [`ViaFirmware`] implementation, the same way QMK's `via.c`
+//! does. The module does not allocate and is available without the `host` feature, so it can be
+//! used in `no_std` firmware by enabling only the `device` feature.
+//!
+//! ```
+//! use qmk_via_api::device::{ViaFirmware, ViaResponder};
+//!
+//! struct Firmware {
+//!     keymap: [[[u16; 3]; 2]; 2],
+//! }
+//!
+//! impl ViaFirmware for Firmware {
+//!     fn layer_count(&self) -> u8 {
+//!         2
+//!     }
+//!
+//!     fn matrix_size(&self) -> (u8, u8) {
+//!         (2, 3)
+//!     }
+//!
+//!     fn keycode(&self, layer: u8, row: u8, col: u8) -> u16 {
+//!         self.keymap[layer as usize][row as usize][col as usize]
+//!     }
+//!
+//!     fn set_keycode(&mut self, layer: u8, row: u8, col: u8, keycode: u16) {
+//!         self.keymap[layer as usize][row as usize][col as usize] = keycode;
+//!     }
+//! }
+//!
+//! let mut responder = ViaResponder::new(Firmware { keymap: [[[0; 3]; 2]; 2] });
+//! let mut response = [0; 32];
+//! responder.respond(&[0x05, 1, 0, 2, 0x00, 0x04], &mut response);
+//! responder.respond(&[0x04, 1, 0, 2], &mut response);
+//! assert_eq!(response[..6], [0x04, 1, 0, 2, 0x00, 0x04]);
+//! ```
+
+use crate::api_commands::{KeyboardValue, ViaChannelId, ViaCommandId, ID_UNHANDLED, PROTOCOL_V3};
+
+/// Marker returned by [`ViaFirmware`] methods for features the firmware does not support.
+///
+/// The responder answers such commands with [`ID_UNHANDLED`] in place of the command id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Unhandled;
+
+/// Storage and features of a keyboard, queried by [`ViaResponder`] to answer commands.
+///
+/// Only the keymap is mandatory. All other methods default to [`Unhandled`].
+pub trait ViaFirmware {
+    /// VIA protocol version reported to the host.
+    fn protocol_version(&self) -> u16 {
+        PROTOCOL_V3
+    }
+
+    /// Number of dynamic keymap layers.
+    fn layer_count(&self) -> u8;
+
+    /// Number of rows and columns of the switch matrix.
+    fn matrix_size(&self) -> (u8, u8);
+
+    /// Returns the keycode at a position that is within the layer count and matrix size.
+    fn keycode(&self, layer: u8, row: u8, col: u8) -> u16;
+
+    /// Stores the keycode at a position that is within the layer count and matrix size.
+    fn set_keycode(&mut self, layer: u8, row: u8, col: u8, keycode: u16);
+
+    /// Resets the keymap to its defaults.
+    fn reset_keymap(&mut self) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Returns the keycode an encoder is mapped to.
+    fn encoder(&self, _layer: u8, _id: u8, _clockwise: bool) -> Result<u16, Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Maps an encoder to a keycode.
+    fn set_encoder(
+        &mut self,
+        _layer: u8,
+        _id: u8,
+        _clockwise: bool,
+        _keycode: u16,
+    ) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Fills in a keyboard value.
+    ///
+    /// `data` holds the parameters sent by the host and is overwritten with the value.
+    fn keyboard_value(&mut self, _value: KeyboardValue, _data: &mut [u8]) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Sets a keyboard value from the bytes sent by the host.
+    fn set_keyboard_value(&mut self, _value: KeyboardValue, _data: &[u8]) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Fills in a custom menu value, which includes the lighting values of the QMK channels.
+    ///
+    /// Before protocol V3 the host addresses lighting values without a channel. The channel is
+    /// then [`ViaChannelId::IdCustomChannel`] and the value id a
+    /// [`ViaLightingValue`](crate::api_commands::ViaLightingValue).
+    fn custom_value(
+        &mut self,
+        _channel: u8,
+        _value_id: u8,
+        _data: &mut [u8],
+    ) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Sets a custom menu value from the bytes sent by the host.
+    fn set_custom_value(
+        &mut self,
+        _channel: u8,
+        _value_id: u8,
+        _data: &[u8],
+    ) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Persists the custom menu values of a channel.
+    fn save_custom_values(&mut self, _channel: u8) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Number of dynamic macros.
+    fn macro_count(&self) -> Result<u8, Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Size of the macro buffer in bytes.
+    fn macro_buffer_size(&self) -> Result<u16, Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Reads the macro buffer starting at `offset`. Bytes past the end of the buffer are zero.
+    fn read_macro_buffer(&self, _offset: u16, _data: &mut [u8]) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Writes the macro buffer starting at `offset`. Bytes past the end of the buffer are ignored.
+    fn write_macro_buffer(&mut self, _offset: u16, _data: &[u8]) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Clears all macros.
+    fn reset_macros(&mut self) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Resets all settings stored in the EEPROM.
+    fn reset_eeprom(&mut self) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+
+    /// Requests a jump to the bootloader.
+    ///
+    /// The host does not wait for a response, but firmware that wants to send one anyway should
+    /// defer the jump until the response was sent.
+    fn jump_to_bootloader(&mut self) -> Result<(), Unhandled> {
+        Err(Unhandled)
+    }
+}
+
+/// A command received from the host. Data fields borrow from the received report.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command<'a> {
+    GetProtocolVersion,
+    GetKeyboardValue {
+        value: KeyboardValue,
+    },
+    SetKeyboardValue {
+        value: KeyboardValue,
+        data: &'a [u8],
+    },
+    DynamicKeymapGetKeycode {
+        layer: u8,
+        row: u8,
+        col: u8,
+    },
+    DynamicKeymapSetKeycode {
+        layer: u8,
+        row: u8,
+        col: u8,
+        keycode: u16,
+    },
+    DynamicKeymapClearAll,
+    CustomMenuSetValue {
+        channel: u8,
+        value_id: u8,
+        data: &'a [u8],
+    },
+    CustomMenuGetValue {
+        channel: u8,
+        value_id: u8,
+    },
+    CustomMenuSave {
+        channel: u8,
+    },
+    EepromReset,
+    BootloaderJump,
+    DynamicKeymapMacroGetCount,
+    DynamicKeymapMacroGetBufferSize,
+    DynamicKeymapMacroGetBuffer {
+        offset: u16,
+        size: u8,
+    },
+    DynamicKeymapMacroSetBuffer {
+        offset: u16,
+        data: &'a [u8],
+    },
+    DynamicKeymapMacroReset,
+    DynamicKeymapGetLayerCount,
+    DynamicKeymapGetBuffer {
+        offset: u16,
+        size: u8,
+    },
+    DynamicKeymapSetBuffer {
+        offset: u16,
+        data: &'a [u8],
+    },
+    DynamicKeymapGetEncoder {
+        layer: u8,
+        id: u8,
+        clockwise: bool,
+    },
+    DynamicKeymapSetEncoder {
+        layer: u8,
+        id: u8,
+        clockwise: bool,
+        keycode: u16,
+    },
+}
+
+impl<'a> Command<'a> {
+    /// Parses a report received from the host.
+    ///
+    /// Returns `None` for unknown commands, unknown keyboard values, Vial commands and reports
+    /// that are too short for their command. Custom menu commands are parsed with the channel
+    /// id introduced in protocol V3.
+    pub fn parse(report: &'a [u8]) -> Option<Command<'a>> {
+        Self::parse_for_version(report, PROTOCOL_V3)
+    }
+
+    /// Parses a report received from a host speaking the given protocol version.
+    ///
+    /// Before protocol V3 custom menu commands carry no channel id, they are parsed with
+    /// [`ViaChannelId::IdCustomChannel`] as in QMK's `via.c`.
+    pub fn parse_for_version(report: &'a [u8], protocol_version: u16) -> Option<Command<'a>> {
+        let byte = |index: usize| report.get(index).copied();
+        let word = |index: usize| Some(u16::from_be_bytes([byte(index)?, byte(index + 1)?]));
+        // Buffer commands carry a 16 bit offset and a size followed by the data
+        let buffer = || {
+            let size = byte(3)?;
+            let data = report.get(4..4 + size as usize)?;
+            Some((word(1)?, size, data))
+        };
+        let channel_len = custom_menu_channel_len(protocol_version);
+        let channel = || match channel_len {
+            0 => Some(ViaChannelId::IdCustomChannel as u8),
+            _ => byte(1),
+        };
+
+        let command = match ViaCommandId::try_from(byte(0)?).ok()? {
+            ViaCommandId::GetProtocolVersion => Command::GetProtocolVersion,
+            ViaCommandId::GetKeyboardValue => Command::GetKeyboardValue {
+                value: KeyboardValue::try_from(byte(1)?).ok()?,
+            },
+            ViaCommandId::SetKeyboardValue => Command::SetKeyboardValue {
+                value: KeyboardValue::try_from(byte(1)?).ok()?,
+                data: &report[2..],
+            },
+            ViaCommandId::DynamicKeymapGetKeycode => Command::DynamicKeymapGetKeycode {
+                layer: byte(1)?,
+                row: byte(2)?,
+                col: byte(3)?,
+            },
+            ViaCommandId::DynamicKeymapSetKeycode => Command::DynamicKeymapSetKeycode {
+                layer: byte(1)?,
+                row: byte(2)?,
+                col: byte(3)?,
+                keycode: word(4)?,
+            },
+            ViaCommandId::DynamicKeymapClearAll => Command::DynamicKeymapClearAll,
+            ViaCommandId::CustomMenuSetValue => Command::CustomMenuSetValue {
+                channel: channel()?,
+                value_id: byte(1 + channel_len)?,
+                data: &report[2 + channel_len..],
+            },
+            ViaCommandId::CustomMenuGetValue => Command::CustomMenuGetValue {
+                channel: channel()?,
+                value_id: byte(1 + channel_len)?,
+            },
+            ViaCommandId::CustomMenuSave => Command::CustomMenuSave {
+                channel: channel()?,
+            },
+            ViaCommandId::EepromReset => Command::EepromReset,
+            ViaCommandId::BootloaderJump => Command::BootloaderJump,
+            ViaCommandId::DynamicKeymapMacroGetCount => Command::DynamicKeymapMacroGetCount,
+            ViaCommandId::DynamicKeymapMacroGetBufferSize => {
+                Command::DynamicKeymapMacroGetBufferSize
+            }
+            ViaCommandId::DynamicKeymapMacroGetBuffer => {
+                let (offset, size, _) = buffer()?;
+                Command::DynamicKeymapMacroGetBuffer { offset, size }
+            }
+            ViaCommandId::DynamicKeymapMacroSetBuffer => {
+                let (offset, _, data) = buffer()?;
+                Command::DynamicKeymapMacroSetBuffer { offset, data }
+            }
+            ViaCommandId::DynamicKeymapMacroReset => Command::DynamicKeymapMacroReset,
+            ViaCommandId::DynamicKeymapGetLayerCount => Command::DynamicKeymapGetLayerCount,
+            ViaCommandId::DynamicKeymapGetBuffer => {
+                let (offset, size, _) = buffer()?;
+                Command::DynamicKeymapGetBuffer { offset, size }
+            }
+            ViaCommandId::DynamicKeymapSetBuffer => {
+                let (offset, _, data) = buffer()?;
+                Command::DynamicKeymapSetBuffer { offset, data }
+            }
+            ViaCommandId::DynamicKeymapGetEncoder => Command::DynamicKeymapGetEncoder {
+                layer: byte(1)?,
+                id: byte(2)?,
+                clockwise: byte(3)? != 0,
+            },
+            ViaCommandId::DynamicKeymapSetEncoder => Command::DynamicKeymapSetEncoder {
+                layer: byte(1)?,
+                id: byte(2)?,
+                clockwise: byte(3)? != 0,
+                keycode: word(4)?,
+            },
+            ViaCommandId::VialPrefix => return None,
+        };
+        Some(command)
+    }
+}
+
+/// Answers VIA commands from a [`ViaFirmware`].
+pub struct ViaResponder<F> {
+    firmware: F,
+}
+
+impl<F: ViaFirmware> ViaResponder<F> {
+    pub fn new(firmware: F) -> Self {
+        ViaResponder { firmware }
+    }
+
+    pub fn firmware(&self) -> &F {
+        &self.firmware
+    }
+
+    pub fn firmware_mut(&mut self) -> &mut F {
+        &mut self.firmware
+    }
+
+    pub fn into_inner(self) -> F {
+        self.firmware
+    }
+
+    /// Writes the response to `request` into `response`.
+    ///
+    /// As in QMK, the response is a copy of the request with the requested values filled in, or
+    /// with the command id replaced by [`ID_UNHANDLED`] if the command is not supported.
+    /// `response` should have the size of the raw HID report.
+    pub fn respond(&mut self, request: &[u8], response: &mut [u8]) {
+        let len = request.len().min(response.len());
+        response[..len].copy_from_slice(&request[..len]);
+        response[len..].fill(0);
+
+        let protocol_version = self.firmware.protocol_version();
+        let handled = match Command::parse_for_version(request, protocol_version) {
+            Some(command) => self.handle(command, response),
+            None => Err(Unhandled),
+        };
+        if let (Err(Unhandled), Some(command)) = (handled, response.first_mut()) {
+            *command = ID_UNHANDLED;
+        }
+    }
+
+    fn handle(&mut self, command: Command, response: &mut [u8]) -> Result<(), Unhandled> {
+        match command {
+            Command::GetProtocolVersion => {
+                write(response, 1, &self.firmware.protocol_version().to_be_bytes())
+            }
+            Command::GetKeyboardValue { value } => self
+                .firmware
+                .keyboard_value(value, response.get_mut(2..).ok_or(Unhandled)?),
+            Command::SetKeyboardValue { value, data } => {
+                self.firmware.set_keyboard_value(value, data)
+            }
+            Command::DynamicKeymapGetKeycode { layer, row, col } => {
+                let keycode = self
+                    .key_index(layer, row, col)
+                    .map_or(0, |_| self.firmware.keycode(layer, row, col));
+                write(response, 4, &keycode.to_be_bytes())
+            }
+            Command::DynamicKeymapSetKeycode {
+                layer,
+                row,
+                col,
+                keycode,
+            } => {
+                if self.key_index(layer, row, col).is_some() {
+                    self.firmware.set_keycode(layer, row, col, keycode);
+                }
+                Ok(())
+            }
+            Command::DynamicKeymapClearAll => self.firmware.reset_keymap(),
+            Command::CustomMenuSetValue {
+                channel,
+                value_id,
+                data,
+            } => self.firmware.set_custom_value(channel, value_id, data),
+            Command::CustomMenuGetValue { channel, value_id } => {
+                let start = 2 + custom_menu_channel_len(self.firmware.protocol_version());
+                self.firmware.custom_value(
+                    channel,
+                    value_id,
+                    response.get_mut(start..).ok_or(Unhandled)?,
+                )
+            }
+            Command::CustomMenuSave { channel } => self.firmware.save_custom_values(channel),
+            Command::EepromReset => self.firmware.reset_eeprom(),
+            Command::BootloaderJump => self.firmware.jump_to_bootloader(),
+            Command::DynamicKeymapMacroGetCount => {
+                write(response, 1, &[self.firmware.macro_count()?])
+            }
+            Command::DynamicKeymapMacroGetBufferSize => write(
+                response,
+                1,
+                &self.firmware.macro_buffer_size()?.to_be_bytes(),
+            ),
+            Command::DynamicKeymapMacroGetBuffer { offset, size } => {
+                let data = response.get_mut(4..4 + size as usize).ok_or(Unhandled)?;
+                data.fill(0);
+                self.firmware.read_macro_buffer(offset, data)
+            }
+            Command::DynamicKeymapMacroSetBuffer { offset, data } => {
+                self.firmware.write_macro_buffer(offset, data)
+            }
+            Command::DynamicKeymapMacroReset => self.firmware.reset_macros(),
+            Command::DynamicKeymapGetLayerCount => {
+                write(response, 1, &[self.firmware.layer_count()])
+            }
+            Command::DynamicKeymapGetBuffer { offset, size } => {
+                let data = response.get_mut(4..4 + size as usize).ok_or(Unhandled)?;
+                for (i, byte) in data.iter_mut().enumerate() {
+                    *byte = self.keymap_byte(offset as usize + i).unwrap_or(0);
+                }
+                Ok(())
+            }
+            Command::DynamicKeymapSetBuffer { offset, data } => {
+                for (i, byte) in data.iter().enumerate() {
+                    self.set_keymap_byte(offset as usize + i, *byte);
+                }
+                Ok(())
+            }
+            Command::DynamicKeymapGetEncoder {
+                layer,
+                id,
+                clockwise,
+            } => {
+                let keycode = self.firmware.encoder(layer, id, clockwise)?;
+                write(response, 4, &keycode.to_be_bytes())
+            }
+            Command::DynamicKeymapSetEncoder {
+                layer,
+                id,
+                clockwise,
+                keycode,
+            } => self.firmware.set_encoder(layer, id, clockwise, keycode),
+        }
+    }
+
+    /// Returns the index of a key in the keymap, or `None` if the position does not exist.
+    fn key_index(&self, layer: u8, row: u8, col: u8) -> Option<usize> {
+        let (rows, cols) = self.firmware.matrix_size();
+        if layer >= self.firmware.layer_count() || row >= rows || col >= cols {
+            return None;
+        }
+        Some((layer as usize * rows as usize + row as usize) * cols as usize + col as usize)
+    }
+
+    /// Returns the position of a key from its index in the keymap.
+    fn key_position(&self, index: usize) -> Option<(u8, u8, u8)> {
+        let (rows, cols) = self.firmware.matrix_size();
+        let keys_per_layer = rows as usize * cols as usize;
+        if keys_per_layer == 0 || index >= self.firmware.layer_count() as usize * keys_per_layer {
+            return None;
+        }
+        let key = index % keys_per_layer;
+        Some((
+            (index / keys_per_layer) as u8,
+            (key / cols as usize) as u8,
+            (key % cols as usize) as u8,
+        ))
+    }
+
+    /// Returns a byte of the keymap laid out as big endian keycodes, as read by buffer commands.
+    fn keymap_byte(&self, offset: usize) -> Option<u8> {
+        let (layer, row, col) = self.key_position(offset / 2)?;
+        let keycode = self.firmware.keycode(layer, row, col).to_be_bytes();
+        Some(keycode[offset % 2])
+    }
+
+    fn set_keymap_byte(&mut self, offset: usize, byte: u8) {
+        if let Some((layer, row, col)) = self.key_position(offset / 2) {
+            let mut keycode = self.firmware.keycode(layer, row, col).to_be_bytes();
+            keycode[offset % 2] = byte;
+            self.firmware
+                .set_keycode(layer, row, col, u16::from_be_bytes(keycode));
+        }
+    }
+}
+
+/// Returns the length of the channel id preceding the value id of custom menu commands.
+fn custom_menu_channel_len(protocol_version: u16) -> usize {
+    match protocol_version >= PROTOCOL_V3 {
+        true => 1,
+        false => 0,
+    }
+}
+
+/// Writes `bytes` into the response at `index`, or fails if the response is too short.
+fn write(response: &mut [u8], index: usize, bytes: &[u8]) -> Result<(), Unhandled> {
+    response
+        .get_mut(index..index + bytes.len())
+        .ok_or(Unhandled)?
+        .copy_from_slice(bytes);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_commands::ViaLightingValue;
+
+    #[derive(Default)]
+    struct Firmware {
+        protocol_version: u16,
+        keymap: [[[u16; 3]; 2]; 2],
+        macros: [u8; 8],
+        /// Channel, value id and value of the last set custom value
+        custom: Option<(u8, u8, u8)>,
+        saved: Option<u8>,
+    }
+
+    impl ViaFirmware for Firmware {
+        fn protocol_version(&self) -> u16 {
+            self.protocol_version
+        }
+
+        fn layer_count(&self) -> u8 {
+            2
+        }
+
+        fn matrix_size(&self) -> (u8, u8) {
+            (2, 3)
+        }
+
+        fn keycode(&self, layer: u8, row: u8, col: u8) -> u16 {
+            self.keymap[layer as usize][row as usize][col as usize]
+        }
+
+        fn set_keycode(&mut self, layer: u8, row: u8, col: u8, keycode: u16) {
+            self.keymap[layer as usize][row as usize][col as usize] = keycode;
+        }
+
+        fn custom_value(
+            &mut self,
+            channel: u8,
+            value_id: u8,
+            data: &mut [u8],
+        ) -> Result<(), Unhandled> {
+            match self.custom {
+                Some((c, v, value)) if (c, v) == (channel, value_id) => {
+                    data[0] = value;
+                    Ok(())
+                }
+                _ => Err(Unhandled),
+            }
+        }
+
+        fn set_custom_value(
+            &mut self,
+            channel: u8,
+            value_id: u8,
+            data: &[u8],
+        ) -> Result<(), Unhandled> {
+            self.custom = Some((channel, value_id, data[0]));
+            Ok(())
+        }
+
+        fn save_custom_values(&mut self, channel: u8) -> Result<(), Unhandled> {
+            self.saved = Some(channel);
+            Ok(())
+        }
+
+        fn macro_buffer_size(&self) -> Result<u16, Unhandled> {
+            Ok(self.macros.len() as u16)
+        }
+
+        fn read_macro_buffer(&self, offset: u16, data: &mut [u8]) -> Result<(), Unhandled> {
+            let macros = self.macros.get(offset as usize..).unwrap_or_default();
+            let len = data.len().min(macros.len());
+            data[..len].copy_from_slice(&macros[..len]);
+            Ok(())
+        }
+
+        fn write_macro_buffer(&mut self, offset: u16, data: &[u8]) -> Result<(), Unhandled> {
+            let macros = self.macros.get_mut(offset as usize..).unwrap_or_default();
+            let len = data.len().min(macros.len());
+            macros[..len].copy_from_slice(&data[..len]);
+            Ok(())
+        }
+    }
+
+    fn responder(protocol_version: u16) -> ViaResponder<Firmware> {
+        ViaResponder::new(Firmware {
+            protocol_version,
+            ..Default::default()
+        })
+    }
+
+    /// Sends a request padded to 32 bytes and returns the response.
+    fn respond(responder: &mut ViaResponder<Firmware>, request: &[u8]) -> [u8; 32] {
+        let mut report = [0; 32];
+        report[..request.len()].copy_from_slice(request);
+        let mut response = [0; 32];
+        responder.respond(&report, &mut response);
+        response
+    }
+
+    #[test]
+    fn protocol_version() {
+        let mut responder = responder(PROTOCOL_V3);
+        assert_eq!(respond(&mut responder, &[0x01])[..3], [0x01, 0x00, 0x0b]);
+    }
+
+    #[test]
+    fn keycodes() {
+        let mut responder = responder(PROTOCOL_V3);
+        respond(&mut responder, &[0x05, 1, 1, 2, 0x00, 0x04]);
+        assert_eq!(responder.firmware().keymap[1][1][2], 0x0004);
+        let response = respond(&mut responder, &[0x04, 1, 1, 2]);
+        assert_eq!(response[..6], [0x04, 1, 1, 2, 0x00, 0x04]);
+
+        // Positions outside the matrix read as KC_NO and are not written
+        respond(&mut responder, &[0x05, 2, 0, 0, 0x00, 0x04]);
+        let response = respond(&mut responder, &[0x04, 0, 2, 0]);
+        assert_eq!(response[..6], [0x04, 0, 2, 0, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn keymap_buffer() {
+        let mut responder = responder(PROTOCOL_V3);
+        // The second layer starts at key 6, i.e. byte 12
+        respond(&mut responder, &[0x13, 0x00, 12, 4, 0x00, 0x05, 0x00, 0x06]);
+        assert_eq!(responder.firmware().keymap[1][0][..2], [0x0005, 0x0006]);
+        let response = respond(&mut responder, &[0x12, 0x00, 10, 6]);
+        assert_eq!(response[4..10], [0, 0, 0, 5, 0, 6]);
+
+        // Bytes past the end of the keymap read as zero
+        let response = respond(&mut responder, &[0x12, 0x00, 22, 4]);
+        assert_eq!(response[4..8], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn macro_buffer() {
+        let mut responder = responder(PROTOCOL_V3);
+        let response = respond(&mut responder, &[0x0d]);
+        assert_eq!(response[..3], [0x0d, 0x00, 8]);
+        respond(&mut responder, &[0x0f, 0x00, 6, 3, 0x61, 0x62, 0x63]);
+        assert_eq!(responder.firmware().macros[6..], [0x61, 0x62]);
+        let response = respond(&mut responder, &[0x0e, 0x00, 5, 4]);
+        assert_eq!(response[4..8], [0x00, 0x61, 0x62, 0x00]);
+    }
+
+    #[test]
+    fn unhandled_commands() {
+        let mut responder = responder(PROTOCOL_V3);
+        // Not implemented by the firmware
+        assert_eq!(respond(&mut responder, &[0x0c])[..1], [ID_UNHANDLED]);
+        assert_eq!(respond(&mut responder, &[0x0b])[..1], [ID_UNHANDLED]);
+        // Unknown command and Vial prefix
+        assert_eq!(respond(&mut responder, &[0x42, 1])[..2], [ID_UNHANDLED, 1]);
+        assert_eq!(respond(&mut responder, &[0xfe, 0])[..1], [ID_UNHANDLED]);
+        // Too short for its command
+        let mut response = [0; 4];
+        responder.respond(&[0x04, 0, 0], &mut response);
+        assert_eq!(response, [ID_UNHANDLED, 0, 0, 0]);
+    }
+
+    #[test]
+    fn custom_values_with_channel() {
+        let mut responder = responder(PROTOCOL_V3);
+        let channel = ViaChannelId::IdQmkRgblightChannel as u8;
+        respond(&mut responder, &[0x07, channel, 1, 0x80]);
+        assert_eq!(responder.firmware().custom, Some((channel, 1, 0x80)));
+        let response = respond(&mut responder, &[0x08, channel, 1]);
+        assert_eq!(response[..4], [0x08, channel, 1, 0x80]);
+        respond(&mut responder, &[0x09, channel]);
+        assert_eq!(responder.firmware().saved, Some(channel));
+    }
+
+    #[test]
+    fn custom_values_before_v3() {
+        let mut responder = responder(9);
+        let value_id = ViaLightingValue::IdQmkRgblightBrightness as u8;
+        respond(&mut responder, &[0x07, value_id, 0x80]);
+        let channel = ViaChannelId::IdCustomChannel as u8;
+        assert_eq!(responder.firmware().custom, Some((channel, value_id, 0x80)));
+        let response = respond(&mut responder, &[0x08, value_id]);
+        assert_eq!(response[..3], [0x08, value_id, 0x80]);
+        respond(&mut responder, &[0x09]);
+        assert_eq!(responder.firmware().saved, Some(channel));
+    }
+
+    #[test]
+    fn parse_custom_menu_commands() {
+        let report = [0x07, 3, 1, 0x80];
+        assert_eq!(
+            Command::parse(&report),
+            Some(Command::CustomMenuSetValue {
+                channel: 3,
+                value_id: 1,
+                data: &[0x80]
+            })
+        );
+        assert_eq!(
+            Command::parse_for_version(&report, 9),
+            Some(Command::CustomMenuSetValue {
+                channel: 0,
+                value_id: 3,
+                data: &[1, 0x80]
+            })
+        );
+        assert_eq!(Command::parse(&[0x08, 3]), None);
+        assert_eq!(
+            Command::parse_for_version(&[0x08, 3], 9),
+            Some(Command::CustomMenuGetValue {
+                channel: 0,
+                value_id: 3
+            })
+        );
+    }
+}
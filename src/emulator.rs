@@ -0,0 +1,991 @@
+use crate::api::{
+    KeyboardValue, MatrixInfo, MAX_REPORT_SIZE, MIN_REPORT_SIZE, PROTOCOL_V3, RAW_EPSIZE,
+};
+use crate::api_commands::{ViaChannelId, ViaCommandId, VialCommandId, VialDynamicEntryOp};
+use crate::protocol::ID_UNHANDLED;
+use crate::qmk_settings::QMK_SETTINGS;
+use crate::transport::Transport;
+use crate::vial;
+use crate::{utils, Error, Result};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+/// Configuration of a [`VirtualKeyboard`].
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VirtualKeyboardConfig {
+    /// VIA protocol version reported by the keyboard
+    pub protocol_version: u16,
+    /// Number of dynamic keymap layers
+    pub layer_count: u8,
+    /// Number of rows and columns of the switch matrix
+    pub matrix: MatrixInfo,
+    /// Number of rotary encoders
+    pub encoder_count: u8,
+    /// Number of dynamic macros
+    pub macro_count: u8,
+    /// Size of the dynamic macro buffer in bytes
+    pub macro_buffer_size: u16,
+    /// Firmware version returned for [`KeyboardValue::FirmwareVersion`]
+    pub firmware_version: u32,
+    /// Custom menu channels the keyboard responds to
+    pub channels: Vec<ViaChannelId>,
+    /// Size of raw HID reports in bytes
+    pub report_size: usize,
+    /// Vial firmware extensions, None for plain VIA firmware
+    pub vial: Option<VirtualVialConfig>,
+}
+
+/// Configuration of the Vial extensions of a [`VirtualKeyboard`].
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VirtualVialConfig {
+    /// Vial protocol version reported by the keyboard
+    pub protocol_version: u32,
+    /// Unique keyboard id
+    pub keyboard_id: u64,
+    /// LZMA compressed keyboard definition, as built from `vial.json` by Vial firmware
+    pub definition: Vec<u8>,
+    /// Number of tap dance entries
+    pub tap_dance_count: u8,
+    /// Number of combo entries
+    pub combo_count: u8,
+    /// Number of key override entries
+    pub key_override_count: u8,
+    /// Ids and widths in bytes of the supported QMK settings
+    pub qmk_settings: Vec<(u16, usize)>,
+}
+
+impl Default for VirtualVialConfig {
+    fn default() -> Self {
+        VirtualVialConfig {
+            protocol_version: 6,
+            keyboard_id: 0,
+            definition: vec![],
+            tap_dance_count: 4,
+            combo_count: 4,
+            key_override_count: 4,
+            qmk_settings: QMK_SETTINGS
+                .iter()
+                .map(|setting| (setting.qsid, setting.setting_type.width()))
+                .collect(),
+        }
+    }
+}
+
+impl Default for VirtualKeyboardConfig {
+    fn default() -> Self {
+        VirtualKeyboardConfig {
+            protocol_version: PROTOCOL_V3,
+            layer_count: 4,
+            matrix: MatrixInfo { rows: 5, cols: 15 },
+            encoder_count: 2,
+            macro_count: 16,
+            macro_buffer_size: 1024,
+            firmware_version: 0,
+            channels: vec![
+                ViaChannelId::IdQmkBacklightChannel,
+                ViaChannelId::IdQmkRgblightChannel,
+                ViaChannelId::IdQmkRgbMatrixChannel,
+                ViaChannelId::IdQmkAudioChannel,
+                ViaChannelId::IdQmkLedMatrixChannel,
+            ],
+            report_size: RAW_EPSIZE,
+            vial: None,
+        }
+    }
+}
+
+struct State {
+    config: VirtualKeyboardConfig,
+    started: Instant,
+    keymap: Vec<u16>,
+    encoders: Vec<u16>,
+    macro_buffer: Vec<u8>,
+    layout_options: u32,
+    switch_matrix: Vec<u32>,
+    custom_values: HashMap<(u8, u8), Vec<u8>>,
+    saves: HashMap<u8, usize>,
+    device_indications: usize,
+    bootloader_jumps: usize,
+    tap_dances: Vec<[u8; DYNAMIC_ENTRY_SIZE]>,
+    combos: Vec<[u8; DYNAMIC_ENTRY_SIZE]>,
+    key_overrides: Vec<[u8; DYNAMIC_ENTRY_SIZE]>,
+    qmk_settings: BTreeMap<u16, Vec<u8>>,
+    responses: VecDeque<Vec<u8>>,
+    dropped_responses: usize,
+}
+
+/// Size of a Vial dynamic entry in bytes.
+const DYNAMIC_ENTRY_SIZE: usize = 10;
+
+impl State {
+    fn new(config: VirtualKeyboardConfig) -> Self {
+        let keys =
+            config.layer_count as usize * config.matrix.rows as usize * config.matrix.cols as usize;
+        let encoders = config.layer_count as usize * config.encoder_count as usize * 2;
+        let vial = config.vial.as_ref();
+        State {
+            started: Instant::now(),
+            keymap: vec![0; keys],
+            encoders: vec![0; encoders],
+            macro_buffer: vec![0; config.macro_buffer_size as usize],
+            layout_options: 0,
+            switch_matrix: vec![0; config.matrix.rows as usize],
+            custom_values: HashMap::new(),
+            saves: HashMap::new(),
+            device_indications: 0,
+            bootloader_jumps: 0,
+            tap_dances: vec![
+                [0; DYNAMIC_ENTRY_SIZE];
+                vial.map_or(0, |vial| vial.tap_dance_count as usize)
+            ],
+            combos: vec![[0; DYNAMIC_ENTRY_SIZE]; vial.map_or(0, |vial| vial.combo_count as usize)],
+            key_overrides: vec![
+                [0; DYNAMIC_ENTRY_SIZE];
+                vial.map_or(0, |vial| vial.key_override_count as usize)
+            ],
+            qmk_settings: vial
+                .iter()
+                .flat_map(|vial| &vial.qmk_settings)
+                .map(|(qsid, width)| (*qsid, vec![0; *width]))
+                .collect(),
+            responses: VecDeque::new(),
+            dropped_responses: 0,
+            config,
+        }
+    }
+
+    fn reset_eeprom(&mut self) {
+        let config = self.config.clone();
+        let responses = std::mem::take(&mut self.responses);
+        let bootloader_jumps = self.bootloader_jumps;
+        let dropped_responses = self.dropped_responses;
+        *self = State::new(config);
+        self.responses = responses;
+        self.bootloader_jumps = bootloader_jumps;
+        self.dropped_responses = dropped_responses;
+    }
+
+    fn key_index(&self, layer: u8, row: u8, col: u8) -> Option<usize> {
+        let MatrixInfo { rows, cols } = self.config.matrix;
+        if layer >= self.config.layer_count || row >= rows || col >= cols {
+            return None;
+        }
+        Some((layer as usize * rows as usize + row as usize) * cols as usize + col as usize)
+    }
+
+    fn encoder_index(&self, layer: u8, id: u8, clockwise: bool) -> Option<usize> {
+        if layer >= self.config.layer_count || id >= self.config.encoder_count {
+            return None;
+        }
+        Some(
+            (layer as usize * self.config.encoder_count as usize + id as usize) * 2
+                + clockwise as usize,
+        )
+    }
+
+    fn supports_channel(&self, channel: u8) -> bool {
+        self.config.channels.iter().any(|c| *c as u8 == channel)
+    }
+
+    /// Handles a single request in place, mirroring `raw_hid_receive` in QMK's `via.c`.
+    fn handle(&mut self, data: &mut [u8]) {
+        let command = data[0];
+        match command {
+            c if c == ViaCommandId::GetProtocolVersion as u8 => {
+                let (hi, lo) = utils::shift_from_16_bit(self.config.protocol_version);
+                data[1] = hi;
+                data[2] = lo;
+            }
+            c if c == ViaCommandId::GetKeyboardValue as u8 => self.get_keyboard_value(data),
+            c if c == ViaCommandId::SetKeyboardValue as u8 => self.set_keyboard_value(data),
+            c if c == ViaCommandId::DynamicKeymapGetKeycode as u8 => {
+                let keycode = self
+                    .key_index(data[1], data[2], data[3])
+                    .map_or(0, |idx| self.keymap[idx]);
+                (data[4], data[5]) = utils::shift_from_16_bit(keycode);
+            }
+            c if c == ViaCommandId::DynamicKeymapSetKeycode as u8 => {
+                if let Some(idx) = self.key_index(data[1], data[2], data[3]) {
+                    self.keymap[idx] = utils::shift_to_16_bit(data[4], data[5]);
+                }
+            }
+            c if c == ViaCommandId::DynamicKeymapClearAll as u8 => {
+                self.keymap.fill(0);
+                self.encoders.fill(0);
+            }
+            c if c == ViaCommandId::CustomMenuSetValue as u8
+                || c == ViaCommandId::CustomMenuGetValue as u8
+                || c == ViaCommandId::CustomMenuSave as u8 =>
+            {
+                self.custom_menu(data)
+            }
+            c if c == ViaCommandId::EepromReset as u8 => self.reset_eeprom(),
+            c if c == ViaCommandId::BootloaderJump as u8 => self.bootloader_jumps += 1,
+            c if c == ViaCommandId::DynamicKeymapMacroGetCount as u8 => {
+                data[1] = self.config.macro_count;
+            }
+            c if c == ViaCommandId::DynamicKeymapMacroGetBufferSize as u8 => {
+                (data[1], data[2]) = utils::shift_from_16_bit(self.config.macro_buffer_size);
+            }
+            c if c == ViaCommandId::DynamicKeymapMacroGetBuffer as u8 => {
+                let (offset, size) = buffer_window(data);
+                for i in 0..size {
+                    data[4 + i] = self.macro_buffer.get(offset + i).copied().unwrap_or(0);
+                }
+            }
+            c if c == ViaCommandId::DynamicKeymapMacroSetBuffer as u8 => {
+                let (offset, size) = buffer_window(data);
+                for i in 0..size {
+                    if let Some(byte) = self.macro_buffer.get_mut(offset + i) {
+                        *byte = data[4 + i];
+                    }
+                }
+            }
+            c if c == ViaCommandId::DynamicKeymapMacroReset as u8 => self.macro_buffer.fill(0),
+            c if c == ViaCommandId::DynamicKeymapGetLayerCount as u8 => {
+                data[1] = self.config.layer_count;
+            }
+            c if c == ViaCommandId::DynamicKeymapGetBuffer as u8 => {
+                let (offset, size) = buffer_window(data);
+                let bytes = utils::shift_buffer_from_16_bit(&self.keymap);
+                for i in 0..size {
+                    data[4 + i] = bytes.get(offset + i).copied().unwrap_or(0);
+                }
+            }
+            c if c == ViaCommandId::DynamicKeymapSetBuffer as u8 => {
+                let (offset, size) = buffer_window(data);
+                let mut bytes = utils::shift_buffer_from_16_bit(&self.keymap);
+                for i in 0..size {
+                    if let Some(byte) = bytes.get_mut(offset + i) {
+                        *byte = data[4 + i];
+                    }
+                }
+                self.keymap = utils::shift_buffer_to_16_bit(&bytes);
+            }
+            // Firmware without encoders is built without ENCODER_MAP_ENABLE
+            c if (c == ViaCommandId::DynamicKeymapGetEncoder as u8
+                || c == ViaCommandId::DynamicKeymapSetEncoder as u8)
+                && self.config.encoder_count == 0 =>
+            {
+                data[0] = ID_UNHANDLED
+            }
+            c if c == ViaCommandId::DynamicKeymapGetEncoder as u8 => {
+                let keycode = self
+                    .encoder_index(data[1], data[2], data[3] != 0)
+                    .map_or(0, |idx| self.encoders[idx]);
+                (data[4], data[5]) = utils::shift_from_16_bit(keycode);
+            }
+            c if c == ViaCommandId::DynamicKeymapSetEncoder as u8 => {
+                if let Some(idx) = self.encoder_index(data[1], data[2], data[3] != 0) {
+                    self.encoders[idx] = utils::shift_to_16_bit(data[4], data[5]);
+                }
+            }
+            c if c == ViaCommandId::VialPrefix as u8 && self.config.vial.is_some() => {
+                self.vial(data)
+            }
+            _ => data[0] = ID_UNHANDLED,
+        }
+    }
+
+    /// Handles a Vial sub-command, mirroring `vial_handle_cmd` in Vial's `vial.c`.
+    fn vial(&mut self, data: &mut [u8]) {
+        // Vial only supports reports of the default size
+        let Some(vial) = self
+            .config
+            .vial
+            .as_ref()
+            .filter(|_| data.len() >= RAW_EPSIZE)
+        else {
+            return;
+        };
+        match data[1] {
+            c if c == VialCommandId::GetKeyboardId as u8 => {
+                data[0..4].copy_from_slice(&vial.protocol_version.to_le_bytes());
+                data[4..12].copy_from_slice(&vial.keyboard_id.to_le_bytes());
+            }
+            c if c == VialCommandId::GetSize as u8 => {
+                data[0..4].copy_from_slice(&(vial.definition.len() as u32).to_le_bytes());
+            }
+            c if c == VialCommandId::GetDefinition as u8 => {
+                let page = u16::from_le_bytes([data[2], data[3]]) as usize;
+                let start = page * vial::DEFINITION_PAGE_SIZE;
+                if let Some(chunk) = vial.definition.get(start..) {
+                    let length = chunk.len().min(vial::DEFINITION_PAGE_SIZE);
+                    data[..length].copy_from_slice(&chunk[..length]);
+                }
+            }
+            c if c == VialCommandId::QmkSettingsQuery as u8 => {
+                let after = u16::from_le_bytes([data[2], data[3]]);
+                data.fill(0xff);
+                let qsids = self
+                    .qmk_settings
+                    .range(after.saturating_add(1)..)
+                    .map(|(qsid, _)| qsid);
+                for (chunk, qsid) in data.chunks_exact_mut(2).zip(qsids) {
+                    chunk.copy_from_slice(&qsid.to_le_bytes());
+                }
+            }
+            c if c == VialCommandId::QmkSettingsGet as u8 => {
+                let qsid = u16::from_le_bytes([data[2], data[3]]);
+                match self.qmk_settings.get(&qsid) {
+                    Some(value) => {
+                        data[0] = 0;
+                        data[1..=value.len()].copy_from_slice(value);
+                    }
+                    None => data[0] = 0xff,
+                }
+            }
+            c if c == VialCommandId::QmkSettingsSet as u8 => {
+                let qsid = u16::from_le_bytes([data[2], data[3]]);
+                match self.qmk_settings.get_mut(&qsid) {
+                    Some(value) => {
+                        let width = value.len();
+                        value.copy_from_slice(&data[4..4 + width]);
+                        data[0] = 0;
+                    }
+                    None => data[0] = 0xff,
+                }
+            }
+            c if c == VialCommandId::QmkSettingsReset as u8 => self
+                .qmk_settings
+                .values_mut()
+                .for_each(|value| value.fill(0)),
+            c if c == VialCommandId::DynamicEntryOp as u8 => self.vial_dynamic_entry(data),
+            _ => {}
+        }
+    }
+
+    /// Handles a Vial dynamic entry operation. Invalid indices are answered with a status of -1.
+    fn vial_dynamic_entry(&mut self, data: &mut [u8]) {
+        let index = data[3] as usize;
+        match data[2] {
+            op if op == VialDynamicEntryOp::GetNumberOfEntries as u8 => {
+                data.fill(0);
+                data[0] = self.tap_dances.len() as u8;
+                data[1] = self.combos.len() as u8;
+                data[2] = self.key_overrides.len() as u8;
+            }
+            op if op == VialDynamicEntryOp::TapDanceGet as u8 => {
+                get_dynamic_entry(&self.tap_dances, index, data)
+            }
+            op if op == VialDynamicEntryOp::TapDanceSet as u8 => {
+                set_dynamic_entry(&mut self.tap_dances, index, data)
+            }
+            op if op == VialDynamicEntryOp::ComboGet as u8 => {
+                get_dynamic_entry(&self.combos, index, data)
+            }
+            op if op == VialDynamicEntryOp::ComboSet as u8 => {
+                set_dynamic_entry(&mut self.combos, index, data)
+            }
+            op if op == VialDynamicEntryOp::KeyOverrideGet as u8 => {
+                get_dynamic_entry(&self.key_overrides, index, data)
+            }
+            op if op == VialDynamicEntryOp::KeyOverrideSet as u8 => {
+                set_dynamic_entry(&mut self.key_overrides, index, data)
+            }
+            _ => {}
+        }
+    }
+
+    fn get_keyboard_value(&mut self, data: &mut [u8]) {
+        match data[1] {
+            v if v == KeyboardValue::Uptime as u8 => {
+                let uptime = self.started.elapsed().as_millis() as u32;
+                data[2..6].copy_from_slice(&uptime.to_be_bytes());
+            }
+            v if v == KeyboardValue::LayoutOptions as u8 => {
+                data[2..6].copy_from_slice(&self.layout_options.to_be_bytes());
+            }
+            v if v == KeyboardValue::SwitchMatrixState as u8 => {
+                let bytes_per_row = (self.config.matrix.cols as usize).div_ceil(8);
+                let rows_per_report = (data.len() - 4) / bytes_per_row;
+                let offset = data[2] as usize;
+                let mut i = 3;
+                for row in self.switch_matrix.iter().skip(offset).take(rows_per_report) {
+                    let row_bytes = row.to_be_bytes();
+                    data[i..i + bytes_per_row].copy_from_slice(&row_bytes[4 - bytes_per_row..]);
+                    i += bytes_per_row;
+                }
+            }
+            v if v == KeyboardValue::FirmwareVersion as u8
+                && self.config.protocol_version >= PROTOCOL_V3 =>
+            {
+                data[2..6].copy_from_slice(&self.config.firmware_version.to_be_bytes());
+            }
+            _ => data[0] = ID_UNHANDLED,
+        }
+    }
+
+    fn set_keyboard_value(&mut self, data: &mut [u8]) {
+        match data[1] {
+            v if v == KeyboardValue::LayoutOptions as u8 => {
+                self.layout_options = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+            }
+            v if v == KeyboardValue::DeviceIndication as u8
+                && self.config.protocol_version >= PROTOCOL_V3 =>
+            {
+                self.device_indications += 1;
+            }
+            _ => data[0] = ID_UNHANDLED,
+        }
+    }
+
+    fn custom_menu(&mut self, data: &mut [u8]) {
+        // Before protocol V3 lighting values were addressed without a channel id.
+        let (channel, value_start) = if self.config.protocol_version >= PROTOCOL_V3 {
+            if !self.supports_channel(data[1]) {
+                data[0] = ID_UNHANDLED;
+                return;
+            }
+            (data[1], 3)
+        } else {
+            (ViaChannelId::IdCustomChannel as u8, 2)
+        };
+
+        let command = data[0];
+        if command == ViaCommandId::CustomMenuSave as u8 {
+            *self.saves.entry(channel).or_default() += 1;
+            return;
+        }
+
+        let value_id = data[value_start - 1];
+        if command == ViaCommandId::CustomMenuSetValue as u8 {
+            self.custom_values
+                .insert((channel, value_id), data[value_start..].to_vec());
+        } else if let Some(value) = self.custom_values.get(&(channel, value_id)) {
+            data[value_start..].copy_from_slice(value);
+        } else {
+            data[value_start..].fill(0);
+        }
+    }
+}
+
+fn get_dynamic_entry(entries: &[[u8; DYNAMIC_ENTRY_SIZE]], index: usize, data: &mut [u8]) {
+    let entry = entries.get(index);
+    data[0] = if entry.is_some() { 0 } else { 0xff };
+    data[1..=DYNAMIC_ENTRY_SIZE].copy_from_slice(entry.unwrap_or(&[0; DYNAMIC_ENTRY_SIZE]));
+}
+
+fn set_dynamic_entry(entries: &mut [[u8; DYNAMIC_ENTRY_SIZE]], index: usize, data: &mut [u8]) {
+    data[0] = match entries.get_mut(index) {
+        Some(entry) => {
+            entry.copy_from_slice(&data[4..4 + DYNAMIC_ENTRY_SIZE]);
+            0
+        }
+        None => 0xff,
+    };
+}
+
+/// Returns the offset and size of a buffer request, clamped to the report payload.
+fn buffer_window(data: &[u8]) -> (usize, usize) {
+    let offset = utils::shift_to_16_bit(data[1], data[2]) as usize;
+    let size = std::cmp::min(data[3] as usize, data.len() - 4);
+    (offset, size)
+}
+
+/// An in-process VIA keyboard that answers commands the way QMK's `via.c` does.
+///
+/// The keyboard implements [`Transport`] and can therefore be passed to
+/// [`KeyboardApi::from_transport`](crate::api::KeyboardApi::from_transport). Clones share
+/// the same state, so a clone can be kept around to inspect or modify the emulated EEPROM.
+///
+/// ```
+/// use qmk_via_api::api::KeyboardApi;
+/// use qmk_via_api::emulator::{VirtualKeyboard, VirtualKeyboardConfig};
+///
+/// let keyboard = VirtualKeyboard::new(VirtualKeyboardConfig::default())?;
+/// let api = KeyboardApi::from_transport(keyboard.clone())?;
+/// api.set_key(0, 1, 2, 0x0004)?;
+/// assert_eq!(keyboard.key(0, 1, 2), 0x0004);
+/// # Ok::<(), qmk_via_api::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct VirtualKeyboard {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for VirtualKeyboard {
+    fn default() -> Self {
+        Self::new(VirtualKeyboardConfig::default()).expect("valid default configuration")
+    }
+}
+
+impl VirtualKeyboard {
+    /// Creates a keyboard, failing if the configuration cannot be emulated.
+    pub fn new(config: VirtualKeyboardConfig) -> Result<Self> {
+        if !(MIN_REPORT_SIZE..=MAX_REPORT_SIZE).contains(&config.report_size) {
+            return Err(Error::InvalidArgument("report size"));
+        }
+        // The switch matrix state holds up to 32 columns per row
+        if config.matrix.cols > 32 {
+            return Err(Error::InvalidArgument("matrix columns"));
+        }
+        Ok(VirtualKeyboard {
+            state: Arc::new(Mutex::new(State::new(config))),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns the configuration the keyboard was created with.
+    pub fn config(&self) -> VirtualKeyboardConfig {
+        self.state().config.clone()
+    }
+
+    /// Returns the keycode at the given layer, row and column.
+    pub fn key(&self, layer: u8, row: u8, col: u8) -> u16 {
+        let state = self.state();
+        state
+            .key_index(layer, row, col)
+            .map_or(0, |idx| state.keymap[idx])
+    }
+
+    /// Sets the keycode at the given layer, row and column.
+    pub fn set_key(&self, layer: u8, row: u8, col: u8, keycode: u16) {
+        let mut state = self.state();
+        if let Some(idx) = state.key_index(layer, row, col) {
+            state.keymap[idx] = keycode;
+        }
+    }
+
+    /// Returns all keycodes of the given layer in row-major order.
+    pub fn layer(&self, layer: u8) -> Vec<u16> {
+        let state = self.state();
+        let size = state.config.matrix.rows as usize * state.config.matrix.cols as usize;
+        state
+            .keymap
+            .iter()
+            .skip(layer as usize * size)
+            .take(size)
+            .copied()
+            .collect()
+    }
+
+    /// Returns the keycode assigned to the given encoder and direction.
+    pub fn encoder(&self, layer: u8, id: u8, is_clockwise: bool) -> u16 {
+        let state = self.state();
+        state
+            .encoder_index(layer, id, is_clockwise)
+            .map_or(0, |idx| state.encoders[idx])
+    }
+
+    /// Returns the contents of the macro buffer.
+    pub fn macro_buffer(&self) -> Vec<u8> {
+        self.state().macro_buffer.clone()
+    }
+
+    /// Returns the current layout options.
+    pub fn layout_options(&self) -> u32 {
+        self.state().layout_options
+    }
+
+    /// Sets the pressed state of a matrix row, one bit per column.
+    pub fn set_switch_matrix_row(&self, row: u8, value: u32) {
+        if let Some(state) = self.state().switch_matrix.get_mut(row as usize) {
+            *state = value;
+        }
+    }
+
+    /// Returns the raw bytes stored for a custom menu value. Keyboards using a protocol older
+    /// than V3 store all values under [`ViaChannelId::IdCustomChannel`].
+    pub fn custom_value(&self, channel: ViaChannelId, value_id: u8) -> Option<Vec<u8>> {
+        self.state()
+            .custom_values
+            .get(&(channel as u8, value_id))
+            .cloned()
+    }
+
+    /// Returns how often the given channel has been saved.
+    pub fn save_count(&self, channel: ViaChannelId) -> usize {
+        self.state()
+            .saves
+            .get(&(channel as u8))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Returns how often the device indication has been triggered.
+    pub fn device_indication_count(&self) -> usize {
+        self.state().device_indications
+    }
+
+    /// Returns how often the keyboard was asked to jump to the bootloader.
+    pub fn bootloader_jump_count(&self) -> usize {
+        self.state().bootloader_jumps
+    }
+
+    /// Queues an unsolicited input report, e.g. to simulate a stale response.
+    pub fn inject_report(&self, report: Vec<u8>) {
+        self.state().responses.push_back(report);
+    }
+
+    /// Processes the next `count` requests without sending a response.
+    pub fn drop_responses(&self, count: usize) {
+        self.state().dropped_responses += count;
+    }
+}
+
+impl Transport for VirtualKeyboard {
+    fn send_report(&self, data: &[u8]) -> Result<usize> {
+        // Skip the report id, the emulated keyboard only has a single report.
+        let mut state = self.state();
+        let mut report = vec![0; state.config.report_size];
+        let payload = data.get(1..).unwrap_or_default();
+        let length = std::cmp::min(payload.len(), report.len());
+        report[..length].copy_from_slice(&payload[..length]);
+
+        state.handle(&mut report);
+        if state.dropped_responses > 0 {
+            state.dropped_responses -= 1;
+        } else {
+            state.responses.push_back(report);
+        }
+        Ok(data.len())
+    }
+
+    fn read_report(&self, buffer: &mut [u8], _timeout_ms: i32) -> Result<usize> {
+        match self.state().responses.pop_front() {
+            Some(report) => {
+                let length = std::cmp::min(buffer.len(), report.len());
+                buffer[..length].copy_from_slice(&report[..length]);
+                Ok(length)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn report_size(&self) -> Result<Option<usize>> {
+        Ok(Some(self.state().config.report_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::KeyboardApi;
+    use crate::Error;
+
+    fn api_with(config: VirtualKeyboardConfig) -> (VirtualKeyboard, KeyboardApi) {
+        let keyboard = VirtualKeyboard::new(config).unwrap();
+        let api = KeyboardApi::from_transport(keyboard.clone()).unwrap();
+        (keyboard, api)
+    }
+
+    fn api() -> (VirtualKeyboard, KeyboardApi) {
+        api_with(VirtualKeyboardConfig::default())
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        for report_size in [0, MIN_REPORT_SIZE - 1, MAX_REPORT_SIZE + 1] {
+            let config = VirtualKeyboardConfig {
+                report_size,
+                ..Default::default()
+            };
+            assert!(matches!(
+                VirtualKeyboard::new(config),
+                Err(Error::InvalidArgument("report size"))
+            ));
+        }
+        let config = VirtualKeyboardConfig {
+            matrix: MatrixInfo { rows: 2, cols: 33 },
+            ..Default::default()
+        };
+        assert!(matches!(
+            VirtualKeyboard::new(config),
+            Err(Error::InvalidArgument("matrix columns"))
+        ));
+    }
+
+    #[test]
+    fn raw_matrix_round_trip() {
+        let (keyboard, api) = api();
+        let matrix = keyboard.config().matrix;
+        let keymap: Vec<Vec<u16>> = (0..4)
+            .map(|layer| {
+                (0..matrix.rows as u16 * matrix.cols as u16)
+                    .map(|key| layer << 8 | key)
+                    .collect()
+            })
+            .collect();
+        api.write_raw_matrix(matrix, keymap.clone()).unwrap();
+
+        for (layer, keys) in keymap.iter().enumerate() {
+            assert_eq!(keyboard.layer(layer as u8), *keys);
+            assert_eq!(api.read_raw_matrix(matrix, layer as u8).unwrap(), *keys);
+        }
+        assert_eq!(keyboard.key(2, 1, 3), 2 << 8 | 18);
+    }
+
+    #[test]
+    fn raw_matrix_with_large_reports() {
+        let (keyboard, api) = api_with(VirtualKeyboardConfig {
+            report_size: 64,
+            ..Default::default()
+        });
+        let matrix = keyboard.config().matrix;
+        keyboard.set_key(1, 4, 14, 0x0029);
+        let keys = api.read_raw_matrix(matrix, 1).unwrap();
+        assert_eq!(keys.len(), matrix.rows as usize * matrix.cols as usize);
+        assert_eq!(keys.last(), Some(&0x0029));
+    }
+
+    #[test]
+    fn macro_bytes_round_trip() {
+        let (keyboard, api) = api_with(VirtualKeyboardConfig {
+            macro_buffer_size: 100,
+            ..Default::default()
+        });
+        let data: Vec<u8> = (1..=99).collect();
+        api.set_macro_bytes(data.clone()).unwrap();
+        assert_eq!(&keyboard.macro_buffer()[..99], &data[..]);
+        assert_eq!(&api.get_macro_bytes().unwrap()[..99], &data[..]);
+
+        let too_long = vec![1; 101];
+        assert!(matches!(
+            api.set_macro_bytes(too_long),
+            Err(Error::SizeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn macro_bytes_without_buffer() {
+        let (_, api) = api_with(VirtualKeyboardConfig {
+            macro_buffer_size: 0,
+            ..Default::default()
+        });
+        assert!(matches!(
+            api.set_macro_bytes(Vec::new()),
+            Err(Error::SizeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn retries_only_safe_commands() {
+        let (keyboard, api) = api();
+        keyboard.drop_responses(1);
+        assert_eq!(api.get_layer_count().unwrap(), 4);
+
+        keyboard.drop_responses(1);
+        assert!(matches!(
+            api.reset_eeprom(),
+            Err(Error::Timeout(ViaCommandId::EepromReset))
+        ));
+
+        keyboard.drop_responses(1);
+        api.jump_to_bootloader().unwrap();
+        assert_eq!(keyboard.bootloader_jump_count(), 1);
+    }
+
+    #[test]
+    fn bootloader_jump_without_timeout() {
+        let (keyboard, api) = api();
+        api.set_timeout(None);
+        keyboard.drop_responses(1);
+        api.jump_to_bootloader().unwrap();
+        assert_eq!(keyboard.bootloader_jump_count(), 1);
+    }
+
+    #[test]
+    fn trace_write_errors() {
+        /// Writer failing after the given number of writes.
+        struct FailingWriter(usize);
+
+        impl std::io::Write for FailingWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                match self.0.checked_sub(1) {
+                    Some(writes) => {
+                        self.0 = writes;
+                        Ok(buf.len())
+                    }
+                    None => Err(std::io::ErrorKind::StorageFull.into()),
+                }
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for writes in 1..4 {
+            let (_, api) = api();
+            api.start_trace_to_writer(FailingWriter(writes)).unwrap();
+            assert_eq!(api.get_layer_count().unwrap(), 4);
+            api.set_key(0, 0, 0, 0x0004).unwrap();
+            assert_eq!(api.get_key(0, 0, 0).unwrap(), 0x0004);
+        }
+    }
+
+    #[test]
+    fn options_of_shared_handle() {
+        let (keyboard, api) = api();
+        let api = Arc::new(api);
+        api.set_timeout(Some(50));
+        api.set_retries(0);
+        api.set_reconnect_timeout(Some(1000));
+        assert_eq!(api.get_timeout(), Some(50));
+        assert_eq!(api.get_retries(), 0);
+        assert_eq!(api.get_reconnect_timeout(), Some(1000));
+
+        keyboard.drop_responses(1);
+        let other = Arc::clone(&api);
+        let result = std::thread::spawn(move || other.get_layer_count())
+            .join()
+            .unwrap();
+        assert!(matches!(result, Err(Error::Timeout(_))));
+    }
+
+    #[test]
+    fn lighting_round_trip() {
+        let (keyboard, api) = api();
+        api.set_backlight_brightness(10).unwrap();
+        api.set_backlight_effect(2).unwrap();
+        assert_eq!(api.get_backlight_brightness().unwrap(), 10);
+        assert_eq!(api.get_backlight_effect().unwrap(), 2);
+
+        api.set_rgblight_brightness(20).unwrap();
+        api.set_rgblight_effect(3).unwrap();
+        api.set_rgblight_effect_speed(4).unwrap();
+        api.set_rgblight_color(5, 6).unwrap();
+        assert_eq!(api.get_rgblight_brightness().unwrap(), 20);
+        assert_eq!(api.get_rgblight_effect().unwrap(), 3);
+        assert_eq!(api.get_rgblight_effect_speed().unwrap(), 4);
+        assert_eq!(api.get_rgblight_color().unwrap(), (5, 6));
+
+        api.set_rgb_matrix_brightness(30).unwrap();
+        api.set_rgb_matrix_effect(7).unwrap();
+        api.set_rgb_matrix_effect_speed(8).unwrap();
+        api.set_rgb_matrix_color(9, 10).unwrap();
+        assert_eq!(api.get_rgb_matrix_brightness().unwrap(), 30);
+        assert_eq!(api.get_rgb_matrix_effect().unwrap(), 7);
+        assert_eq!(api.get_rgb_matrix_effect_speed().unwrap(), 8);
+        assert_eq!(api.get_rgb_matrix_color().unwrap(), (9, 10));
+
+        api.set_led_matrix_brightness(40).unwrap();
+        api.set_led_matrix_effect(11).unwrap();
+        api.set_led_matrix_effect_speed(12).unwrap();
+        assert_eq!(api.get_led_matrix_brightness().unwrap(), 40);
+        assert_eq!(api.get_led_matrix_effect().unwrap(), 11);
+        assert_eq!(api.get_led_matrix_effect_speed().unwrap(), 12);
+
+        api.set_audio_enabled(true).unwrap();
+        api.set_audio_clicky_enabled(true).unwrap();
+        assert!(api.get_audio_enabled().unwrap());
+        assert!(api.get_audio_clicky_enabled().unwrap());
+
+        assert_eq!(
+            keyboard
+                .custom_value(ViaChannelId::IdQmkRgbMatrixChannel, 4)
+                .map(|value| value[..2].to_vec()),
+            Some(vec![9, 10])
+        );
+    }
+
+    #[test]
+    fn oversized_values() {
+        let (_, api) = api();
+        assert!(matches!(
+            api.get_keyboard_value(KeyboardValue::Uptime, Vec::new(), 31),
+            Err(Error::SizeMismatch { .. })
+        ));
+        let value = api.get_custom_menu_value(vec![ViaChannelId::IdQmkRgblightChannel as u8, 1]);
+        assert_eq!(value.unwrap().len(), RAW_EPSIZE - 3);
+    }
+
+    fn vial_api(vial: VirtualVialConfig) -> KeyboardApi {
+        api_with(VirtualKeyboardConfig {
+            vial: Some(vial),
+            ..Default::default()
+        })
+        .1
+    }
+
+    #[test]
+    fn vial_responses_are_not_unhandled() {
+        // The size of 511 bytes is answered with FF 01 00 00
+        let definition: Vec<u8> = (0..511).map(|i| i as u8).collect();
+        let api = vial_api(VirtualVialConfig {
+            definition: definition.clone(),
+            ..Default::default()
+        });
+        assert_eq!(api.get_vial_definition_compressed().unwrap(), definition);
+    }
+
+    #[test]
+    fn vial_on_keyboard_without_vial() {
+        let (_, api) = api();
+        assert!(!api.get_capabilities().unwrap().vial);
+        assert!(matches!(
+            api.get_vial_keyboard_id(),
+            Err(Error::UnsupportedCommand { .. })
+        ));
+        assert!(matches!(
+            api.request(&vial::GetSize),
+            Err(Error::UnsupportedCommand { .. })
+        ));
+    }
+
+    #[test]
+    fn vial_invalid_arguments() {
+        let api = vial_api(VirtualVialConfig::default());
+        assert!(api.get_capabilities().unwrap().vial);
+        assert!(matches!(
+            api.set_combo(4, Default::default()),
+            Err(Error::InvalidArgument("Vial dynamic entry index"))
+        ));
+        assert!(matches!(
+            api.get_tap_dance(4),
+            Err(Error::InvalidArgument("Vial dynamic entry index"))
+        ));
+        assert!(matches!(
+            api.get_qmk_setting_as(0xfffe, crate::qmk_settings::QmkSettingType::U8),
+            Err(Error::InvalidArgument("QMK setting id"))
+        ));
+    }
+
+    #[test]
+    fn vial_entries_round_trip() {
+        use crate::keycodes::Keycode;
+        use crate::vial::{Combo, KeyOverride, TapDance};
+
+        let api = vial_api(VirtualVialConfig::default());
+        // MO(1) has no Keycode variant
+        let tap_dance = TapDance::from_raw(Keycode::KC_A.into(), 0x5221, 0, 0, 200);
+        api.set_tap_dance(1, tap_dance).unwrap();
+        let read = api.get_tap_dance(1).unwrap();
+        assert_eq!(read, tap_dance);
+        assert_eq!(read.on_tap(), Some(Keycode::KC_A));
+        assert_eq!(read.on_hold(), None);
+        assert_eq!(read.raw_on_hold(), 0x5221);
+
+        let combo = Combo::new(&[Keycode::KC_J, Keycode::KC_K], Keycode::KC_ESCAPE);
+        api.set_combo(2, combo).unwrap();
+        assert_eq!(api.get_combo(2).unwrap().output(), Some(Keycode::KC_ESCAPE));
+        api.clear_combo(2).unwrap();
+        assert!(api.get_combo(2).unwrap().is_empty());
+
+        let key_override = KeyOverride::new(Keycode::KC_BACKSPACE, Keycode::KC_DELETE);
+        api.set_key_override(3, key_override).unwrap();
+        assert_eq!(api.get_key_overrides().unwrap()[3], key_override);
+    }
+
+    #[test]
+    fn lighting_on_unsupported_channel() {
+        let (_, api) = api_with(VirtualKeyboardConfig {
+            channels: vec![ViaChannelId::IdQmkRgblightChannel],
+            ..Default::default()
+        });
+        assert_eq!(api.get_rgblight_brightness().unwrap(), 0);
+        assert!(matches!(
+            api.get_rgb_matrix_brightness(),
+            Err(Error::UnsupportedCommand { .. })
+        ));
+    }
+}
@@ -1,21 +1,24 @@
-use std::fmt::Debug;
-
 use hidapi::HidError;
 
-use crate::api_commands::ViaCommandId;
+use crate::api_commands::{ViaChannelId, ViaCommandId};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
-    Hid(String),
+    Hid(HidError),
     BadCommandResponse(ViaCommandId),
-    SendCommand(ViaCommandId, String),
+    SendCommand(ViaCommandId, Box<Error>),
     NoSuchKeyboard {
         vid: u16,
         pid: u16,
         usage_page: u16,
     },
+    NoMatchingKeyboard(String),
+    AmbiguousKeyboard {
+        selector: String,
+        count: usize,
+    },
     UnsupportedProtocol(u16),
     UnsupportedFeature(&'static str),
     SizeMismatch {
@@ -24,6 +27,21 @@ pub enum Error {
         context: &'static str,
     },
     InvalidArgument(&'static str),
+    Timeout(ViaCommandId),
+    /// The keyboard is no longer connected. Holds the error that revealed the disconnect.
+    Disconnected(Box<Error>),
+    UnsupportedCommand {
+        command: ViaCommandId,
+        channel: Option<ViaChannelId>,
+    },
+    Io(std::io::Error),
+    ReplayMismatch {
+        index: usize,
+        expected: Option<Vec<u8>>,
+        actual: Option<Vec<u8>>,
+    },
+    /// The keyboard definition downloaded from Vial firmware could not be decompressed or parsed.
+    InvalidVialDefinition(String),
 }
 
 impl Error {
@@ -61,67 +79,112 @@ impl std::fmt::Display for Error {
                 "could not find keyboard: 0x{:04x}/0x{:04x}/0x{:04x} (vendor_id/product_id/usage_page)",
                 vid, pid, usage_page
             )),
+            Error::NoMatchingKeyboard(selector) => {
+                f.write_fmt(format_args!("could not find keyboard: {}", selector))
+            }
+            Error::AmbiguousKeyboard { selector, count } => f.write_fmt(format_args!(
+                "{} keyboards match {}, expected exactly one",
+                count, selector
+            )),
             Error::BadCommandResponse(cmd) => f.write_fmt(format_args!(
                 "unexpected command response for command {:?}",
                 cmd
             )),
             Error::InvalidArgument(arg) => f.write_fmt(format_args!("invalid argument: {}", arg)),
-            _ => Debug::fmt(&self, f),
+            Error::Timeout(cmd) => f.write_fmt(format_args!(
+                "timed out waiting for response to command {:?}",
+                cmd
+            )),
+            Error::UnsupportedCommand { command, channel } => {
+                f.write_fmt(format_args!("{}", unsupported_command_message(command, channel)))
+            }
+            Error::Io(err) => f.write_fmt(format_args!("I/O error: {}", err)),
+            Error::ReplayMismatch {
+                index,
+                expected,
+                actual,
+            } => f.write_fmt(format_args!(
+                "replayed report {} does not match the trace: expected {:02x?}, actual {:02x?}",
+                index, expected, actual
+            )),
+            Error::Hid(err) => f.write_fmt(format_args!("HID error: {}", err)),
+            Error::SendCommand(cmd, err) => {
+                f.write_fmt(format_args!("failed sending command {:?}: {}", cmd, err))
+            }
+            Error::Disconnected(err) => {
+                f.write_fmt(format_args!("keyboard was disconnected: {}", err))
+            }
+            Error::InvalidVialDefinition(reason) => {
+                f.write_fmt(format_args!("invalid Vial keyboard definition: {}", reason))
+            }
         }
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Hid(err) => Some(err),
+            Error::SendCommand(_, err) | Error::Disconnected(err) => Some(err.as_ref()),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+fn unsupported_command_message(command: &ViaCommandId, channel: &Option<ViaChannelId>) -> String {
+    match channel {
+        Some(channel) => format!(
+            "command {:?} is not supported by the keyboard on channel {:?}",
+            command, channel
+        ),
+        None => format!("command {:?} is not supported by the keyboard", command),
+    }
+}
+
 impl From<HidError> for Error {
     fn from(value: HidError) -> Self {
-        Error::Hid(value.to_string())
+        Error::Hid(value)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::Io(value)
     }
 }
 
 #[cfg(feature = "python")]
 impl From<Error> for pyo3::PyErr {
     fn from(err: Error) -> Self {
+        let message = err.to_string();
         match err {
-            Error::Hid(msg) => pyo3::PyErr::new::<crate::HidError, _>(msg),
-            Error::NoSuchKeyboard {
-                vid,
-                pid,
-                usage_page,
-            } => pyo3::PyErr::new::<crate::DeviceNotFoundError, _>(format!(
-                "could not find keyboard: 0x{:04x}/0x{:04x}/0x{:04x} (vendor_id/product_id/usage_page)",
-                vid, pid, usage_page
-            )),
-            Error::UnsupportedProtocol(version) => {
-                pyo3::PyErr::new::<crate::UnsupportedProtocolError, _>(format!(
-                    "unsupported protocol version: {}",
-                    version
-                ))
+            Error::Hid(_) => pyo3::PyErr::new::<crate::HidError, _>(message),
+            Error::Disconnected(_) => pyo3::PyErr::new::<crate::DisconnectedError, _>(message),
+            Error::NoSuchKeyboard { .. } | Error::NoMatchingKeyboard(_) => {
+                pyo3::PyErr::new::<crate::DeviceNotFoundError, _>(message)
             }
-            Error::UnsupportedFeature(feature) => {
-                pyo3::PyErr::new::<crate::UnsupportedProtocolError, _>(format!(
-                    "unsupported feature: {}",
-                    feature
-                ))
+            Error::AmbiguousKeyboard { .. } => {
+                pyo3::PyErr::new::<crate::AmbiguousDeviceError, _>(message)
             }
-            Error::SizeMismatch {
-                expected,
-                actual,
-                context,
-            } => pyo3::PyErr::new::<crate::SizeMismatchError, _>(format!(
-                "{}: expected size = {}, actual size = {}",
-                context, expected, actual
-            )),
-            Error::BadCommandResponse(cmd) => {
-                pyo3::PyErr::new::<crate::CommandResponseError, _>(format!(
-                    "command failure for {:?}",
-                    cmd
-                ))
+            Error::UnsupportedProtocol(_) | Error::UnsupportedFeature(_) => {
+                pyo3::PyErr::new::<crate::UnsupportedProtocolError, _>(message)
+            }
+            Error::SizeMismatch { .. } => pyo3::PyErr::new::<crate::SizeMismatchError, _>(message),
+            Error::BadCommandResponse(_)
+            | Error::SendCommand(..)
+            | Error::ReplayMismatch { .. }
+            | Error::InvalidVialDefinition(_) => {
+                pyo3::PyErr::new::<crate::CommandResponseError, _>(message)
+            }
+            Error::InvalidArgument(_) => {
+                pyo3::PyErr::new::<crate::InvalidArgumentError, _>(message)
             }
-            Error::SendCommand(cmd, msg) => pyo3::PyErr::new::<crate::CommandResponseError, _>(
-                format!("failed sending command {:?}: {}", cmd, msg),
-            ),
-            Error::InvalidArgument(arg) => {
-                pyo3::PyErr::new::<crate::InvalidArgumentError, _>(arg)
+            Error::Timeout(_) => pyo3::PyErr::new::<crate::CommandTimeoutError, _>(message),
+            Error::UnsupportedCommand { .. } => {
+                pyo3::PyErr::new::<crate::UnsupportedCommandError, _>(message)
             }
+            Error::Io(_) => pyo3::PyErr::new::<crate::IoError, _>(message),
         }
     }
 }
@@ -0,0 +1,277 @@
+use crate::scan::{KeyboardDeviceInfo, VIA_USAGE_PAGE};
+use crate::Result;
+use hidapi::HidApi;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[cfg(feature = "python")]
+use pyo3::prelude::*;
+
+/// Default interval between two scans of the HID device list.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A change in the set of connected VIA keyboards.
+#[cfg_attr(feature = "python", pyclass(skip_from_py_object))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HotplugEvent {
+    /// A keyboard was plugged in
+    Connected(KeyboardDeviceInfo),
+    /// A keyboard was unplugged
+    Disconnected(KeyboardDeviceInfo),
+}
+
+impl HotplugEvent {
+    /// Returns the keyboard the event refers to.
+    pub fn device(&self) -> &KeyboardDeviceInfo {
+        match self {
+            HotplugEvent::Connected(device) | HotplugEvent::Disconnected(device) => device,
+        }
+    }
+}
+
+/// Watches for VIA keyboards being connected and disconnected.
+///
+/// A background thread polls the HID device list in a fixed interval and emits a
+/// [`HotplugEvent`] for every VIA interface that appeared or disappeared since the previous poll.
+/// Keyboards that are already connected when the watcher is started are reported as
+/// [`HotplugEvent::Connected`] first. The thread is stopped when the watcher is dropped.
+///
+/// ```no_run
+/// use qmk_via_api::hotplug::{HotplugEvent, KeyboardWatcher, DEFAULT_POLL_INTERVAL};
+///
+/// let watcher = KeyboardWatcher::new(DEFAULT_POLL_INTERVAL)?;
+/// while let Some(event) = watcher.recv() {
+///     match event {
+///         HotplugEvent::Connected(device) => println!("connected: {:?}", device.product),
+///         HotplugEvent::Disconnected(device) => println!("disconnected: {:?}", device.product),
+///     }
+/// }
+/// # Ok::<(), qmk_via_api::Error>(())
+/// ```
+#[cfg_attr(feature = "python", pyclass)]
+pub struct KeyboardWatcher {
+    events: Option<Mutex<mpsc::Receiver<HotplugEvent>>>,
+    worker: Mutex<Option<(mpsc::Sender<()>, JoinHandle<()>)>>,
+}
+
+impl KeyboardWatcher {
+    /// Starts a watcher whose events are received with [`KeyboardWatcher::recv`] and friends.
+    pub fn new(interval: Duration) -> Result<KeyboardWatcher> {
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher = Self::spawn(interval, move |event| {
+            let _ = sender.send(event);
+        })?;
+        watcher.events = Some(Mutex::new(receiver));
+        Ok(watcher)
+    }
+
+    /// Starts a watcher that invokes `callback` on its background thread for every event.
+    pub fn with_callback<F>(interval: Duration, callback: F) -> Result<KeyboardWatcher>
+    where
+        F: FnMut(HotplugEvent) + Send + 'static,
+    {
+        Self::spawn(interval, callback)
+    }
+
+    fn spawn<F>(interval: Duration, mut emit: F) -> Result<KeyboardWatcher>
+    where
+        F: FnMut(HotplugEvent) + Send + 'static,
+    {
+        let mut api = HidApi::new()?;
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let mut known = HashMap::new();
+            loop {
+                if api.refresh_devices().is_ok() {
+                    let current = api
+                        .device_list()
+                        .filter(|d| d.usage_page() == VIA_USAGE_PAGE)
+                        .map(KeyboardDeviceInfo::from);
+                    for event in Self::diff(&mut known, current) {
+                        emit(event);
+                    }
+                }
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            }
+        });
+        Ok(KeyboardWatcher {
+            events: None,
+            worker: Mutex::new(Some((stop, handle))),
+        })
+    }
+
+    /// Updates the known devices, keyed by path, from the currently connected devices and returns
+    /// what changed.
+    fn diff(
+        known: &mut HashMap<String, KeyboardDeviceInfo>,
+        current: impl IntoIterator<Item = KeyboardDeviceInfo>,
+    ) -> Vec<HotplugEvent> {
+        let current: HashMap<String, KeyboardDeviceInfo> = current
+            .into_iter()
+            .map(|device| (device.path.clone(), device))
+            .collect();
+
+        let mut events: Vec<HotplugEvent> = known
+            .iter()
+            .filter(|(path, _)| !current.contains_key(*path))
+            .map(|(_, device)| HotplugEvent::Disconnected(device.clone()))
+            .collect();
+        events.extend(
+            current
+                .iter()
+                .filter(|(path, _)| !known.contains_key(*path))
+                .map(|(_, device)| HotplugEvent::Connected(device.clone())),
+        );
+        *known = current;
+        events
+    }
+
+    /// Blocks until the next event. Returns `None` if the watcher was stopped or uses a callback.
+    pub fn recv(&self) -> Option<HotplugEvent> {
+        self.events.as_ref()?.lock().ok()?.recv().ok()
+    }
+
+    /// Waits up to `timeout` for the next event.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<HotplugEvent> {
+        self.events
+            .as_ref()?
+            .lock()
+            .ok()?
+            .recv_timeout(timeout)
+            .ok()
+    }
+
+    /// Returns the next event if one is pending.
+    pub fn try_recv(&self) -> Option<HotplugEvent> {
+        self.events.as_ref()?.lock().ok()?.try_recv().ok()
+    }
+
+    /// Stops the background thread. Events that were already emitted can still be received.
+    pub fn stop(&self) {
+        let worker = self
+            .worker
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if let Some((stop, handle)) = worker {
+            drop(stop);
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for KeyboardWatcher {
+    fn drop(&mut self) {
+        // Only signal the thread instead of joining it, a callback might be waiting for a lock
+        // that is held by the thread dropping the watcher.
+        if let Ok(worker) = self.worker.get_mut() {
+            worker.take();
+        }
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl KeyboardWatcher {
+    #[new]
+    #[pyo3(signature = (interval_ms=500, callback=None))]
+    fn py_new(interval_ms: u64, callback: Option<Py<PyAny>>) -> Result<Self> {
+        let interval = Duration::from_millis(interval_ms);
+        match callback {
+            Some(callback) => Self::with_callback(interval, move |event| {
+                Python::attach(|py| {
+                    if let Err(err) = callback.call1(py, (event,)) {
+                        err.print(py);
+                    }
+                })
+            }),
+            None => Self::new(interval),
+        }
+    }
+
+    /// Returns the next event, waiting up to `timeout_ms` or forever if not given.
+    #[pyo3(name = "poll", signature = (timeout_ms=None))]
+    fn py_poll(&self, py: Python<'_>, timeout_ms: Option<u64>) -> Option<HotplugEvent> {
+        py.detach(|| match timeout_ms {
+            Some(timeout_ms) => self.recv_timeout(Duration::from_millis(timeout_ms)),
+            None => self.recv(),
+        })
+    }
+
+    #[pyo3(name = "stop")]
+    fn py_stop(&self, py: Python<'_>) {
+        py.detach(|| self.stop())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(path: &str) -> KeyboardDeviceInfo {
+        KeyboardDeviceInfo {
+            vendor_id: 0x4653,
+            product_id: 0x0001,
+            usage_page: VIA_USAGE_PAGE,
+            usage: 0x61,
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+            path: path.to_string(),
+            interface_number: 1,
+        }
+    }
+
+    #[test]
+    fn diff_connected() {
+        let mut known = HashMap::new();
+        let mut events = KeyboardWatcher::diff(&mut known, [device("a"), device("b")]);
+        events.sort_by(|a, b| a.device().path.cmp(&b.device().path));
+        assert_eq!(
+            events,
+            [
+                HotplugEvent::Connected(device("a")),
+                HotplugEvent::Connected(device("b"))
+            ]
+        );
+        assert_eq!(known.len(), 2);
+    }
+
+    #[test]
+    fn diff_disconnected() {
+        let mut known = HashMap::new();
+        KeyboardWatcher::diff(&mut known, [device("a"), device("b")]);
+        let events = KeyboardWatcher::diff(&mut known, [device("b")]);
+        assert_eq!(events, [HotplugEvent::Disconnected(device("a"))]);
+        assert_eq!(
+            KeyboardWatcher::diff(&mut known, []),
+            [HotplugEvent::Disconnected(device("b"))]
+        );
+        assert!(known.is_empty());
+    }
+
+    #[test]
+    fn diff_unchanged() {
+        let mut known = HashMap::new();
+        KeyboardWatcher::diff(&mut known, [device("a")]);
+        assert!(KeyboardWatcher::diff(&mut known, [device("a")]).is_empty());
+        assert!(KeyboardWatcher::diff(&mut HashMap::new(), []).is_empty());
+
+        // A device replaced by another one at a different path
+        let events = KeyboardWatcher::diff(&mut known, [device("c")]);
+        assert_eq!(
+            events,
+            [
+                HotplugEvent::Disconnected(device("a")),
+                HotplugEvent::Connected(device("c"))
+            ]
+        );
+    }
+}
@@ -2,6 +2,7 @@ use num_enum::TryFromPrimitive;
 use strum_macros::AsRefStr;
 
 #[derive(Debug, Clone, Eq, PartialEq, TryFromPrimitive, AsRefStr)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u16)]
 #[allow(non_camel_case_types)]
 pub enum Keycode {
@@ -725,3 +726,9 @@ pub enum Keycode {
     QK_USER_30 = 0x7E5E,
     QK_USER_31 = 0x7E5F,
 }
+
+impl From<Keycode> for u16 {
+    fn from(keycode: Keycode) -> u16 {
+        keycode as u16
+    }
+}
@@ -1,9 +1,37 @@
+#![cfg_attr(not(feature = "host"), no_std)]
+
+#[cfg(feature = "host")]
 pub mod api;
 pub mod api_commands;
+#[cfg(feature = "tokio")]
+pub mod async_api;
+#[cfg(feature = "host")]
+pub mod conformance;
+#[cfg(feature = "device")]
+pub mod device;
+#[cfg(feature = "host")]
+pub mod emulator;
+#[cfg(feature = "host")]
 pub mod error;
+#[cfg(feature = "host")]
+pub mod hotplug;
 pub mod keycodes;
+#[cfg(feature = "host")]
+pub mod protocol;
+#[cfg(feature = "host")]
+pub mod qmk_settings;
+#[cfg(feature = "host")]
+pub mod replay;
+#[cfg(feature = "host")]
 pub mod scan;
+#[cfg(feature = "host")]
+pub mod trace;
+#[cfg(feature = "host")]
+pub mod transport;
+#[cfg(feature = "host")]
 pub mod utils;
+#[cfg(feature = "host")]
+pub mod vial;
 
 #[cfg(feature = "python")]
 use pyo3::create_exception;
@@ -12,6 +40,7 @@ use pyo3::exceptions::PyException;
 #[cfg(feature = "python")]
 use pyo3::prelude::*;
 
+#[cfg(feature = "host")]
 pub use error::*;
 
 #[cfg(feature = "python")]
@@ -19,8 +48,12 @@ create_exception!(qmk_via_api, QmkViaError, PyException);
 #[cfg(feature = "python")]
 create_exception!(qmk_via_api, HidError, QmkViaError);
 #[cfg(feature = "python")]
+create_exception!(qmk_via_api, DisconnectedError, HidError);
+#[cfg(feature = "python")]
 create_exception!(qmk_via_api, DeviceNotFoundError, QmkViaError);
 #[cfg(feature = "python")]
+create_exception!(qmk_via_api, AmbiguousDeviceError, QmkViaError);
+#[cfg(feature = "python")]
 create_exception!(qmk_via_api, UnsupportedProtocolError, QmkViaError);
 #[cfg(feature = "python")]
 create_exception!(qmk_via_api, SizeMismatchError, QmkViaError);
@@ -28,17 +61,51 @@ create_exception!(qmk_via_api, SizeMismatchError, QmkViaError);
 create_exception!(qmk_via_api, CommandResponseError, QmkViaError);
 #[cfg(feature = "python")]
 create_exception!(qmk_via_api, InvalidArgumentError, QmkViaError);
+#[cfg(feature = "python")]
+create_exception!(qmk_via_api, CommandTimeoutError, QmkViaError);
+#[cfg(feature = "python")]
+create_exception!(qmk_via_api, UnsupportedCommandError, QmkViaError);
+#[cfg(feature = "python")]
+create_exception!(qmk_via_api, IoError, QmkViaError);
 
 #[cfg(feature = "python")]
 #[pymodule]
 fn qmk_via_api(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<api::KeyboardApi>()?;
     m.add_class::<api_commands::ViaCommandId>()?;
+    m.add_class::<api_commands::VialCommandId>()?;
+    m.add_class::<api_commands::VialDynamicEntryOp>()?;
     m.add_class::<api::MatrixInfo>()?;
     m.add_class::<scan::KeyboardDeviceInfo>()?;
+    m.add_class::<scan::KeyboardCapabilities>()?;
+    m.add_class::<scan::ProbedKeyboard>()?;
+    m.add_class::<hotplug::HotplugEvent>()?;
+    m.add_class::<hotplug::KeyboardWatcher>()?;
+    m.add_class::<conformance::CheckStatus>()?;
+    m.add_class::<conformance::ConformanceCheck>()?;
+    m.add_class::<conformance::ConformanceReport>()?;
+    m.add_class::<vial::VialKeyboardId>()?;
+    m.add_class::<vial::VialEntryCounts>()?;
+    m.add_class::<vial::TapDance>()?;
+    m.add_class::<vial::Combo>()?;
+    m.add_class::<vial::KeyOverride>()?;
+    m.add_class::<qmk_settings::QmkSettingType>()?;
+    m.add_class::<qmk_settings::QmkSettingValue>()?;
+    m.add_class::<qmk_settings::QmkSetting>()?;
+    m.add_class::<qmk_settings::QmkSettingFlag>()?;
+    m.add("QMK_SETTINGS", qmk_settings::QMK_SETTINGS.to_vec())?;
+    m.add(
+        "QMK_SETTING_FLAGS",
+        qmk_settings::QMK_SETTING_FLAGS.to_vec(),
+    )?;
     m.add("QmkViaError", _py.get_type::<QmkViaError>())?;
     m.add("HidError", _py.get_type::<HidError>())?;
+    m.add("DisconnectedError", _py.get_type::<DisconnectedError>())?;
     m.add("DeviceNotFoundError", _py.get_type::<DeviceNotFoundError>())?;
+    m.add(
+        "AmbiguousDeviceError",
+        _py.get_type::<AmbiguousDeviceError>(),
+    )?;
     m.add(
         "UnsupportedProtocolError",
         _py.get_type::<UnsupportedProtocolError>(),
@@ -52,6 +119,14 @@ fn qmk_via_api(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
         "InvalidArgumentError",
         _py.get_type::<InvalidArgumentError>(),
     )?;
-    m.add_function(wrap_pyfunction!(scan::scan_keyboards, m)?)?;
+    m.add("CommandTimeoutError", _py.get_type::<CommandTimeoutError>())?;
+    m.add(
+        "UnsupportedCommandError",
+        _py.get_type::<UnsupportedCommandError>(),
+    )?;
+    m.add("IoError", _py.get_type::<IoError>())?;
+    m.add_function(wrap_pyfunction!(scan::py_scan_keyboards, m)?)?;
+    m.add_function(wrap_pyfunction!(scan::probe_keyboards, m)?)?;
+    m.add_function(wrap_pyfunction!(conformance::run_conformance, m)?)?;
     Ok(())
 }
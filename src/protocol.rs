@@ -0,0 +1,825 @@
+//! Encoding of VIA requests and decoding of their responses.
+//!
+//! Every [`ViaCommandId`] has a request struct implementing [`Request`], which knows how to encode
+//! its payload and how to decode the response report. Decoding never panics, responses that are
+//! too short are reported as [`Error::SizeMismatch`].
+//!
+//! ```
+//! use qmk_via_api::protocol::{self, DynamicKeymapGetKeycode};
+//!
+//! let request = DynamicKeymapGetKeycode { layer: 1, row: 2, col: 3 };
+//! let report = protocol::encode_report(&request, 32)?;
+//! assert_eq!(&report[..4], &[0x04, 1, 2, 3]);
+//!
+//! let response = [0x04, 1, 2, 3, 0x00, 0x04];
+//! assert_eq!(protocol::decode_report(&request, &response)?, 0x0004);
+//! assert!(protocol::decode_report(&request, &response[..5]).is_err());
+//! # Ok::<(), qmk_via_api::Error>(())
+//! ```
+
+use crate::api::{Column, KeyboardValue, Layer, Row};
+use crate::api_commands::ViaCommandId;
+use crate::{utils, Error, Result};
+
+pub use crate::api_commands::ID_UNHANDLED;
+
+/// A request to the keyboard together with the decoding of its response.
+pub trait Request {
+    /// Decoded response
+    type Response;
+
+    /// Command id in the first byte of the report.
+    const COMMAND: ViaCommandId;
+
+    /// Whether the response starts with the request.
+    ///
+    /// Requests that do not echo, e.g. Vial commands, answer with a full report of data. Such
+    /// responses can neither be told apart from stale reports nor from unhandled commands, so
+    /// they are only sent to keyboards known to support them.
+    const ECHOES: bool = true;
+
+    /// Encodes the bytes following the command id.
+    fn encode(&self) -> Vec<u8>;
+
+    /// Decodes the response report, which starts with the command id.
+    fn decode(&self, report: &[u8]) -> Result<Self::Response>;
+
+    /// Returns whether a report is the response to this request, given the sent command bytes.
+    ///
+    /// VIA responses start with the request, which tells them apart from stale reports. Any
+    /// report answers a request that does not echo.
+    fn is_response(&self, report: &[u8], command_bytes: &[u8]) -> bool {
+        !Self::ECHOES || report.starts_with(command_bytes)
+    }
+}
+
+/// Encodes a request into a report of the given size, padded with zeros.
+pub fn encode_report<R: Request>(request: &R, report_size: usize) -> Result<Vec<u8>> {
+    let mut report = vec![R::COMMAND as u8];
+    report.extend(request.encode());
+    if report.len() > report_size {
+        return Err(Error::size_mismatch(
+            "send buffer overflow",
+            report_size,
+            report.len(),
+        ));
+    }
+    report.resize(report_size, 0);
+    Ok(report)
+}
+
+/// Checks that the report answers the request and decodes it.
+pub fn decode_report<R: Request>(request: &R, report: &[u8]) -> Result<R::Response> {
+    let mut command_bytes = vec![R::COMMAND as u8];
+    command_bytes.extend(request.encode());
+    if R::ECHOES && is_unhandled_response(report, &command_bytes) {
+        Err(Error::UnsupportedCommand {
+            command: R::COMMAND,
+            channel: None,
+        })
+    } else if request.is_response(report, &command_bytes) {
+        request.decode(report)
+    } else {
+        Err(Error::BadCommandResponse(R::COMMAND))
+    }
+}
+
+/// Returns whether the firmware replaced the command id of the request with [`ID_UNHANDLED`].
+pub fn is_unhandled_response(report: &[u8], command_bytes: &[u8]) -> bool {
+    report.first() == Some(&ID_UNHANDLED) && report[1..].starts_with(&command_bytes[1..])
+}
+
+/// Returns `len` bytes of the report starting at `start`.
+pub fn bytes(report: &[u8], start: usize, len: usize) -> Result<&[u8]> {
+    start
+        .checked_add(len)
+        .and_then(|end| report.get(start..end))
+        .ok_or(Error::size_mismatch(
+            "response too short",
+            start.saturating_add(len),
+            report.len(),
+        ))
+}
+
+/// Returns the byte of the report at `index`.
+pub fn byte(report: &[u8], index: usize) -> Result<u8> {
+    Ok(bytes(report, index, 1)?[0])
+}
+
+/// Returns the big endian 16 bit value of the report at `index`.
+pub fn word(report: &[u8], index: usize) -> Result<u16> {
+    let value = bytes(report, index, 2)?;
+    Ok(utils::shift_to_16_bit(value[0], value[1]))
+}
+
+fn offset_header(offset: u16, size: usize) -> Vec<u8> {
+    let (hi, lo) = utils::shift_from_16_bit(offset);
+    vec![hi, lo, size as u8]
+}
+
+/// Returns the VIA protocol version.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetProtocolVersion;
+
+impl Request for GetProtocolVersion {
+    type Response = u16;
+    const COMMAND: ViaCommandId = ViaCommandId::GetProtocolVersion;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<u16> {
+        word(report, 1)
+    }
+}
+
+/// Returns `result_length` bytes of a keyboard value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetKeyboardValue {
+    pub value: KeyboardValue,
+    pub parameters: Vec<u8>,
+    pub result_length: usize,
+}
+
+impl Request for GetKeyboardValue {
+    type Response = Vec<u8>;
+    const COMMAND: ViaCommandId = ViaCommandId::GetKeyboardValue;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.value as u8];
+        bytes.extend(&self.parameters);
+        bytes
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<Vec<u8>> {
+        bytes(report, 2 + self.parameters.len(), self.result_length).map(|value| value.to_vec())
+    }
+}
+
+/// Sets a keyboard value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetKeyboardValue {
+    pub value: KeyboardValue,
+    pub parameters: Vec<u8>,
+}
+
+impl Request for SetKeyboardValue {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::SetKeyboardValue;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.value as u8];
+        bytes.extend(&self.parameters);
+        bytes
+    }
+
+    fn decode(&self, _report: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the keycode at a key position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapGetKeycode {
+    pub layer: Layer,
+    pub row: Row,
+    pub col: Column,
+}
+
+impl Request for DynamicKeymapGetKeycode {
+    type Response = u16;
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapGetKeycode;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.layer, self.row, self.col]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<u16> {
+        word(report, 4)
+    }
+}
+
+/// Sets the keycode at a key position and returns the keycode echoed by the keyboard.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapSetKeycode {
+    pub layer: Layer,
+    pub row: Row,
+    pub col: Column,
+    pub keycode: u16,
+}
+
+impl Request for DynamicKeymapSetKeycode {
+    type Response = u16;
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapSetKeycode;
+
+    fn encode(&self) -> Vec<u8> {
+        let (hi, lo) = utils::shift_from_16_bit(self.keycode);
+        vec![self.layer, self.row, self.col, hi, lo]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<u16> {
+        word(report, 4)
+    }
+}
+
+/// Clears all keycodes and encoder mappings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapClearAll;
+
+impl Request for DynamicKeymapClearAll {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapClearAll;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn decode(&self, _report: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Sets a custom menu or lighting value.
+///
+/// From protocol V3 on the bytes start with the channel id and value id, before that only with
+/// the lighting value id.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomMenuSetValue {
+    pub bytes: Vec<u8>,
+}
+
+impl Request for CustomMenuSetValue {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::CustomMenuSetValue;
+
+    fn encode(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    fn decode(&self, _report: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns `value_length` bytes of a custom menu or lighting value, which follow the request bytes
+/// in the response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomMenuGetValue {
+    pub bytes: Vec<u8>,
+    pub value_length: usize,
+}
+
+impl Request for CustomMenuGetValue {
+    type Response = Vec<u8>;
+    const COMMAND: ViaCommandId = ViaCommandId::CustomMenuGetValue;
+
+    fn encode(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<Vec<u8>> {
+        bytes(report, 1 + self.bytes.len(), self.value_length).map(|value| value.to_vec())
+    }
+}
+
+/// Persists custom menu or lighting values. Lighting values before protocol V3 are saved without
+/// a channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomMenuSave {
+    pub channel: Option<u8>,
+}
+
+impl Request for CustomMenuSave {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::CustomMenuSave;
+
+    fn encode(&self) -> Vec<u8> {
+        self.channel.into_iter().collect()
+    }
+
+    fn decode(&self, _report: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Resets the EEPROM.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EepromReset;
+
+impl Request for EepromReset {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::EepromReset;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn decode(&self, _report: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Jumps to the bootloader.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootloaderJump;
+
+impl Request for BootloaderJump {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::BootloaderJump;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn decode(&self, _report: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the number of macros.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapMacroGetCount;
+
+impl Request for DynamicKeymapMacroGetCount {
+    type Response = u8;
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapMacroGetCount;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<u8> {
+        byte(report, 1)
+    }
+}
+
+/// Returns the size of the macro buffer in bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapMacroGetBufferSize;
+
+impl Request for DynamicKeymapMacroGetBufferSize {
+    type Response = u16;
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapMacroGetBufferSize;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<u16> {
+        word(report, 1)
+    }
+}
+
+/// Returns `size` bytes of the macro buffer starting at `offset`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapMacroGetBuffer {
+    pub offset: u16,
+    pub size: u8,
+}
+
+impl Request for DynamicKeymapMacroGetBuffer {
+    type Response = Vec<u8>;
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapMacroGetBuffer;
+
+    fn encode(&self) -> Vec<u8> {
+        offset_header(self.offset, self.size as usize)
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<Vec<u8>> {
+        bytes(report, 4, self.size as usize).map(|data| data.to_vec())
+    }
+}
+
+/// Writes bytes to the macro buffer starting at `offset`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapMacroSetBuffer {
+    pub offset: u16,
+    pub data: Vec<u8>,
+}
+
+impl Request for DynamicKeymapMacroSetBuffer {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapMacroSetBuffer;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = offset_header(self.offset, self.data.len());
+        bytes.extend(&self.data);
+        bytes
+    }
+
+    fn decode(&self, _report: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Clears the macro buffer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapMacroReset;
+
+impl Request for DynamicKeymapMacroReset {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapMacroReset;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn decode(&self, _report: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the number of dynamic keymap layers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapGetLayerCount;
+
+impl Request for DynamicKeymapGetLayerCount {
+    type Response = u8;
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapGetLayerCount;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<u8> {
+        byte(report, 1)
+    }
+}
+
+/// Returns `size` bytes of the keymap starting at `offset`. Keycodes are stored big endian.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapGetBuffer {
+    pub offset: u16,
+    pub size: u8,
+}
+
+impl Request for DynamicKeymapGetBuffer {
+    type Response = Vec<u8>;
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapGetBuffer;
+
+    fn encode(&self) -> Vec<u8> {
+        offset_header(self.offset, self.size as usize)
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<Vec<u8>> {
+        bytes(report, 4, self.size as usize).map(|data| data.to_vec())
+    }
+}
+
+/// Writes bytes to the keymap starting at `offset`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapSetBuffer {
+    pub offset: u16,
+    pub data: Vec<u8>,
+}
+
+impl Request for DynamicKeymapSetBuffer {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapSetBuffer;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = offset_header(self.offset, self.data.len());
+        bytes.extend(&self.data);
+        bytes
+    }
+
+    fn decode(&self, _report: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the keycode mapped to an encoder direction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapGetEncoder {
+    pub layer: Layer,
+    pub id: u8,
+    pub clockwise: bool,
+}
+
+impl Request for DynamicKeymapGetEncoder {
+    type Response = u16;
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapGetEncoder;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.layer, self.id, self.clockwise as u8]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<u16> {
+        word(report, 4)
+    }
+}
+
+/// Maps a keycode to an encoder direction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicKeymapSetEncoder {
+    pub layer: Layer,
+    pub id: u8,
+    pub clockwise: bool,
+    pub keycode: u16,
+}
+
+impl Request for DynamicKeymapSetEncoder {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::DynamicKeymapSetEncoder;
+
+    fn encode(&self) -> Vec<u8> {
+        let (hi, lo) = utils::shift_from_16_bit(self.keycode);
+        vec![self.layer, self.id, self.clockwise as u8, hi, lo]
+    }
+
+    fn decode(&self, _report: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a response report of 32 bytes starting with the given bytes.
+    fn response(bytes: &[u8]) -> Vec<u8> {
+        let mut report = bytes.to_vec();
+        report.resize(32, 0);
+        report
+    }
+
+    /// Returns the command bytes sent for a request.
+    fn command_bytes<R: Request>(request: &R) -> Vec<u8> {
+        let mut bytes = vec![R::COMMAND as u8];
+        bytes.extend(request.encode());
+        bytes
+    }
+
+    fn assert_size_mismatch<T: core::fmt::Debug>(result: Result<T>) {
+        assert!(
+            matches!(result, Err(Error::SizeMismatch { .. })),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn encode_report_pads_and_checks_size() {
+        let request = DynamicKeymapSetKeycode {
+            layer: 1,
+            row: 2,
+            col: 3,
+            keycode: 0x1234,
+        };
+        assert_eq!(
+            encode_report(&request, 8).unwrap(),
+            [0x05, 1, 2, 3, 0x12, 0x34, 0, 0]
+        );
+        assert_size_mismatch(encode_report(&request, 5));
+    }
+
+    #[test]
+    fn protocol_version() {
+        let request = GetProtocolVersion;
+        assert_eq!(command_bytes(&request), [0x01]);
+        let report = response(&[0x01, 0x00, 0x0b]);
+        assert_eq!(decode_report(&request, &report).unwrap(), 11);
+        assert_size_mismatch(decode_report(&request, &report[..2]));
+    }
+
+    #[test]
+    fn keyboard_values() {
+        let request = GetKeyboardValue {
+            value: KeyboardValue::SwitchMatrixState,
+            parameters: vec![2],
+            result_length: 3,
+        };
+        assert_eq!(command_bytes(&request), [0x02, 0x03, 2]);
+        let report = response(&[0x02, 0x03, 2, 0xaa, 0xbb, 0xcc, 0xdd]);
+        assert_eq!(
+            decode_report(&request, &report).unwrap(),
+            [0xaa, 0xbb, 0xcc]
+        );
+        assert_size_mismatch(decode_report(&request, &report[..5]));
+
+        let oversized = GetKeyboardValue {
+            result_length: 31,
+            ..request.clone()
+        };
+        assert_size_mismatch(decode_report(&oversized, &report));
+        let overflowing = GetKeyboardValue {
+            result_length: usize::MAX,
+            ..request
+        };
+        assert_size_mismatch(decode_report(&overflowing, &report));
+
+        let request = SetKeyboardValue {
+            value: KeyboardValue::LayoutOptions,
+            parameters: vec![0, 0, 0, 5],
+        };
+        assert_eq!(command_bytes(&request), [0x03, 0x02, 0, 0, 0, 5]);
+        decode_report(&request, &response(&command_bytes(&request))).unwrap();
+    }
+
+    #[test]
+    fn keycodes() {
+        let request = DynamicKeymapGetKeycode {
+            layer: 1,
+            row: 2,
+            col: 3,
+        };
+        assert_eq!(command_bytes(&request), [0x04, 1, 2, 3]);
+        let report = response(&[0x04, 1, 2, 3, 0x00, 0x29]);
+        assert_eq!(decode_report(&request, &report).unwrap(), 0x0029);
+        assert_size_mismatch(decode_report(&request, &report[..5]));
+
+        let request = DynamicKeymapSetKeycode {
+            layer: 1,
+            row: 2,
+            col: 3,
+            keycode: 0x0029,
+        };
+        let report = response(&[0x05, 1, 2, 3, 0x00, 0x29]);
+        assert_eq!(decode_report(&request, &report).unwrap(), 0x0029);
+        assert_size_mismatch(request.decode(&report[..5]));
+
+        let request = DynamicKeymapClearAll;
+        assert_eq!(command_bytes(&request), [0x06]);
+        decode_report(&request, &response(&[0x06])).unwrap();
+    }
+
+    #[test]
+    fn custom_menu_values() {
+        let request = CustomMenuGetValue {
+            bytes: vec![3, 1],
+            value_length: 2,
+        };
+        assert_eq!(command_bytes(&request), [0x08, 3, 1]);
+        let report = response(&[0x08, 3, 1, 0x10, 0x20]);
+        assert_eq!(decode_report(&request, &report).unwrap(), [0x10, 0x20]);
+        assert_size_mismatch(decode_report(&request, &report[..4]));
+
+        let oversized = CustomMenuGetValue {
+            value_length: 30,
+            ..request.clone()
+        };
+        assert_size_mismatch(decode_report(&oversized, &report));
+        let overflowing = CustomMenuGetValue {
+            value_length: usize::MAX,
+            ..request
+        };
+        assert_size_mismatch(decode_report(&overflowing, &report));
+
+        let request = CustomMenuSetValue {
+            bytes: vec![3, 1, 0x10],
+        };
+        assert_eq!(command_bytes(&request), [0x07, 3, 1, 0x10]);
+
+        assert_eq!(
+            command_bytes(&CustomMenuSave { channel: Some(3) }),
+            [0x09, 3]
+        );
+        assert_eq!(command_bytes(&CustomMenuSave { channel: None }), [0x09]);
+    }
+
+    #[test]
+    fn resets() {
+        assert_eq!(command_bytes(&EepromReset), [0x0a]);
+        assert_eq!(command_bytes(&BootloaderJump), [0x0b]);
+        assert_eq!(command_bytes(&DynamicKeymapMacroReset), [0x10]);
+    }
+
+    #[test]
+    fn macros() {
+        let report = response(&[0x0c, 16]);
+        assert_eq!(
+            decode_report(&DynamicKeymapMacroGetCount, &report).unwrap(),
+            16
+        );
+        assert_size_mismatch(decode_report(&DynamicKeymapMacroGetCount, &report[..1]));
+
+        let report = response(&[0x0d, 0x04, 0x00]);
+        let buffer_size = decode_report(&DynamicKeymapMacroGetBufferSize, &report);
+        assert_eq!(buffer_size.unwrap(), 1024);
+        assert_size_mismatch(decode_report(
+            &DynamicKeymapMacroGetBufferSize,
+            &report[..2],
+        ));
+
+        let request = DynamicKeymapMacroGetBuffer {
+            offset: 0x0102,
+            size: 3,
+        };
+        assert_eq!(command_bytes(&request), [0x0e, 0x01, 0x02, 3]);
+        let report = response(&[0x0e, 0x01, 0x02, 3, 0x61, 0x62, 0x00]);
+        assert_eq!(
+            decode_report(&request, &report).unwrap(),
+            [0x61, 0x62, 0x00]
+        );
+        assert_size_mismatch(decode_report(&request, &report[..6]));
+
+        let request = DynamicKeymapMacroSetBuffer {
+            offset: 0x0102,
+            data: vec![0x61, 0x62],
+        };
+        assert_eq!(command_bytes(&request), [0x0f, 0x01, 0x02, 2, 0x61, 0x62]);
+    }
+
+    #[test]
+    fn keymap_buffers() {
+        let report = response(&[0x11, 4]);
+        assert_eq!(
+            decode_report(&DynamicKeymapGetLayerCount, &report).unwrap(),
+            4
+        );
+        assert_size_mismatch(decode_report(&DynamicKeymapGetLayerCount, &report[..1]));
+
+        let request = DynamicKeymapGetBuffer {
+            offset: 0x0010,
+            size: 4,
+        };
+        assert_eq!(command_bytes(&request), [0x12, 0x00, 0x10, 4]);
+        let report = response(&[0x12, 0x00, 0x10, 4, 0x00, 0x04, 0x00, 0x05]);
+        assert_eq!(decode_report(&request, &report).unwrap(), [0, 4, 0, 5]);
+        assert_size_mismatch(decode_report(&request, &report[..7]));
+
+        let request = DynamicKeymapSetBuffer {
+            offset: 0x0010,
+            data: vec![0x00, 0x04],
+        };
+        assert_eq!(command_bytes(&request), [0x13, 0x00, 0x10, 2, 0x00, 0x04]);
+    }
+
+    #[test]
+    fn encoders() {
+        let request = DynamicKeymapGetEncoder {
+            layer: 1,
+            id: 0,
+            clockwise: true,
+        };
+        assert_eq!(command_bytes(&request), [0x14, 1, 0, 1]);
+        let report = response(&[0x14, 1, 0, 1, 0x00, 0x80]);
+        assert_eq!(decode_report(&request, &report).unwrap(), 0x0080);
+        assert_size_mismatch(decode_report(&request, &report[..5]));
+
+        let request = DynamicKeymapSetEncoder {
+            layer: 1,
+            id: 0,
+            clockwise: false,
+            keycode: 0x0081,
+        };
+        assert_eq!(command_bytes(&request), [0x15, 1, 0, 0, 0x00, 0x81]);
+    }
+
+    #[test]
+    fn unhandled_responses() {
+        let request = DynamicKeymapGetKeycode {
+            layer: 1,
+            row: 2,
+            col: 3,
+        };
+        let sent = command_bytes(&request);
+        let unhandled = response(&[ID_UNHANDLED, 1, 2, 3]);
+        assert!(is_unhandled_response(&unhandled, &sent));
+        assert!(matches!(
+            decode_report(&request, &unhandled),
+            Err(Error::UnsupportedCommand {
+                command: ViaCommandId::DynamicKeymapGetKeycode,
+                channel: None
+            })
+        ));
+
+        // The rest of the request has to be echoed as well
+        assert!(!is_unhandled_response(
+            &response(&[ID_UNHANDLED, 1, 2, 4]),
+            &sent
+        ));
+        assert!(!is_unhandled_response(&response(&[0x04, 1, 2, 3]), &sent));
+        assert!(!is_unhandled_response(&[], &sent));
+        assert!(is_unhandled_response(&[ID_UNHANDLED], &[0x0c]));
+    }
+
+    #[test]
+    fn responses_without_echo() {
+        // A Vial definition of 511 bytes has the size of an unhandled GetSize
+        let report = response(&[ID_UNHANDLED, 0x01, 0x00, 0x00]);
+        assert!(is_unhandled_response(
+            &report,
+            &command_bytes(&crate::vial::GetSize)
+        ));
+        assert_eq!(decode_report(&crate::vial::GetSize, &report).unwrap(), 511);
+    }
+
+    #[test]
+    fn stale_responses() {
+        let request = DynamicKeymapGetKeycode {
+            layer: 1,
+            row: 2,
+            col: 3,
+        };
+        let stale = response(&[0x04, 1, 2, 2, 0x00, 0x04]);
+        assert!(!request.is_response(&stale, &command_bytes(&request)));
+        assert!(matches!(
+            decode_report(&request, &stale),
+            Err(Error::BadCommandResponse(
+                ViaCommandId::DynamicKeymapGetKeycode
+            ))
+        ));
+    }
+}
@@ -0,0 +1,409 @@
+//! QMK settings of Vial firmware.
+//!
+//! Vial firmware stores runtime settings like the tapping term in EEPROM. Every setting has a
+//! numeric id, the QSID, and a fixed width. [`QMK_SETTINGS`] names the well-known settings and
+//! [`QMK_SETTING_FLAGS`] the bits of the settings that are bit flags.
+//!
+//! ```
+//! use qmk_via_api::api::KeyboardApi;
+//! use qmk_via_api::emulator::{VirtualKeyboard, VirtualKeyboardConfig, VirtualVialConfig};
+//! use qmk_via_api::qmk_settings::{QmkSetting, QmkSettingValue};
+//!
+//! let keyboard = VirtualKeyboard::new(VirtualKeyboardConfig {
+//!     vial: Some(VirtualVialConfig::default()),
+//!     ..Default::default()
+//! })?;
+//! let api = KeyboardApi::from_transport(keyboard)?;
+//!
+//! let tapping_term = QmkSetting::by_name("tapping_term").unwrap();
+//! api.set_qmk_setting(tapping_term.qsid, QmkSettingValue::U16(180))?;
+//! assert_eq!(api.get_qmk_setting(tapping_term.qsid)?, QmkSettingValue::U16(180));
+//!
+//! api.set_qmk_setting_flag("permissive_hold", true)?;
+//! assert!(api.get_qmk_setting_flag("permissive_hold")?);
+//! # Ok::<(), qmk_via_api::Error>(())
+//! ```
+
+use crate::api::KeyboardApi;
+use crate::api_commands::{ViaCommandId, VialCommandId};
+use crate::protocol::{self, Request};
+use crate::{trace, vial, Error, Result};
+
+#[cfg(feature = "python")]
+use pyo3::prelude::*;
+
+/// First Vial protocol version with QMK settings.
+pub const VIAL_PROTOCOL_QMK_SETTINGS: u32 = 4;
+
+/// Id the firmware pads the response to a settings query with.
+const QSID_END: u16 = 0xffff;
+
+/// Width and interpretation of a setting value.
+#[cfg_attr(feature = "python", pyclass(from_py_object))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum QmkSettingType {
+    U8,
+    U16,
+    U32,
+    /// 8 bit flags, see [`QMK_SETTING_FLAGS`]
+    Flags,
+}
+
+impl QmkSettingType {
+    /// Returns the width of values in bytes.
+    pub fn width(&self) -> usize {
+        match self {
+            QmkSettingType::U8 | QmkSettingType::Flags => 1,
+            QmkSettingType::U16 => 2,
+            QmkSettingType::U32 => 4,
+        }
+    }
+}
+
+/// Value of a setting.
+#[cfg_attr(feature = "python", pyclass(from_py_object))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum QmkSettingValue {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    Flags(u8),
+}
+
+impl QmkSettingValue {
+    /// Creates a value of the given type, failing if it does not fit.
+    pub fn new(setting_type: QmkSettingType, value: u32) -> Result<Self> {
+        let out_of_range = |_| Error::InvalidArgument("QMK setting value out of range");
+        Ok(match setting_type {
+            QmkSettingType::U8 => QmkSettingValue::U8(value.try_into().map_err(out_of_range)?),
+            QmkSettingType::U16 => QmkSettingValue::U16(value.try_into().map_err(out_of_range)?),
+            QmkSettingType::U32 => QmkSettingValue::U32(value),
+            QmkSettingType::Flags => {
+                QmkSettingValue::Flags(value.try_into().map_err(out_of_range)?)
+            }
+        })
+    }
+
+    pub fn setting_type(&self) -> QmkSettingType {
+        match self {
+            QmkSettingValue::U8(_) => QmkSettingType::U8,
+            QmkSettingValue::U16(_) => QmkSettingType::U16,
+            QmkSettingValue::U32(_) => QmkSettingType::U32,
+            QmkSettingValue::Flags(_) => QmkSettingType::Flags,
+        }
+    }
+
+    /// Returns the value as an unsigned number, regardless of its width.
+    pub fn as_u32(&self) -> u32 {
+        match *self {
+            QmkSettingValue::U8(value) | QmkSettingValue::Flags(value) => value as u32,
+            QmkSettingValue::U16(value) => value as u32,
+            QmkSettingValue::U32(value) => value,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        self.as_u32().to_le_bytes()[..self.setting_type().width()].to_vec()
+    }
+}
+
+/// A well-known setting.
+#[cfg_attr(feature = "python", pyclass(get_all, skip_from_py_object))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct QmkSetting {
+    pub qsid: u16,
+    /// Name of the setting, derived from the QMK option it configures
+    pub name: &'static str,
+    pub setting_type: QmkSettingType,
+}
+
+impl QmkSetting {
+    /// Returns the well-known setting with the given id.
+    pub fn by_qsid(qsid: u16) -> Option<&'static QmkSetting> {
+        QMK_SETTINGS.iter().find(|setting| setting.qsid == qsid)
+    }
+
+    /// Returns the well-known setting with the given name.
+    pub fn by_name(name: &str) -> Option<&'static QmkSetting> {
+        QMK_SETTINGS.iter().find(|setting| setting.name == name)
+    }
+}
+
+/// A bit of a setting of type [`QmkSettingType::Flags`].
+#[cfg_attr(feature = "python", pyclass(get_all, skip_from_py_object))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct QmkSettingFlag {
+    pub qsid: u16,
+    pub bit: u8,
+    /// Name of the flag, derived from the QMK option it configures
+    pub name: &'static str,
+}
+
+impl QmkSettingFlag {
+    /// Returns the well-known flag with the given name.
+    pub fn by_name(name: &str) -> Option<&'static QmkSettingFlag> {
+        QMK_SETTING_FLAGS.iter().find(|flag| flag.name == name)
+    }
+}
+
+const fn setting(qsid: u16, name: &'static str, setting_type: QmkSettingType) -> QmkSetting {
+    QmkSetting {
+        qsid,
+        name,
+        setting_type,
+    }
+}
+
+const fn flag(qsid: u16, bit: u8, name: &'static str) -> QmkSettingFlag {
+    QmkSettingFlag { qsid, bit, name }
+}
+
+/// Well-known settings as defined by Vial's `qmk_settings.c`.
+pub const QMK_SETTINGS: &[QmkSetting] = &[
+    setting(1, "grave_esc_override", QmkSettingType::Flags),
+    setting(2, "combo_term", QmkSettingType::U16),
+    setting(3, "auto_shift", QmkSettingType::Flags),
+    setting(4, "auto_shift_timeout", QmkSettingType::U16),
+    setting(5, "oneshot_tap_toggle", QmkSettingType::U8),
+    setting(6, "oneshot_timeout", QmkSettingType::U16),
+    setting(7, "tapping_term", QmkSettingType::U16),
+    setting(8, "tapping", QmkSettingType::Flags),
+    setting(9, "mousekey_delay", QmkSettingType::U16),
+    setting(10, "mousekey_interval", QmkSettingType::U16),
+    setting(11, "mousekey_move_delta", QmkSettingType::U16),
+    setting(12, "mousekey_max_speed", QmkSettingType::U16),
+    setting(13, "mousekey_time_to_max", QmkSettingType::U16),
+    setting(14, "mousekey_wheel_delay", QmkSettingType::U16),
+    setting(15, "mousekey_wheel_interval", QmkSettingType::U16),
+    setting(16, "mousekey_wheel_max_speed", QmkSettingType::U16),
+    setting(17, "mousekey_wheel_time_to_max", QmkSettingType::U16),
+    setting(18, "tap_code_delay", QmkSettingType::U16),
+    setting(19, "tap_hold_caps_delay", QmkSettingType::U16),
+    setting(20, "tapping_toggle", QmkSettingType::U8),
+];
+
+/// Bits of the well-known flag settings.
+pub const QMK_SETTING_FLAGS: &[QmkSettingFlag] = &[
+    flag(1, 0, "grave_esc_alt_override"),
+    flag(1, 1, "grave_esc_ctrl_override"),
+    flag(1, 2, "grave_esc_gui_override"),
+    flag(1, 3, "grave_esc_shift_override"),
+    flag(3, 0, "auto_shift_enabled"),
+    flag(3, 1, "auto_shift_modifiers"),
+    flag(3, 2, "auto_shift_no_special"),
+    flag(3, 3, "auto_shift_no_numeric"),
+    flag(3, 4, "auto_shift_no_alpha"),
+    flag(3, 5, "auto_shift_repeat"),
+    flag(3, 6, "auto_shift_no_auto_repeat"),
+    flag(8, 0, "permissive_hold"),
+    flag(8, 1, "ignore_mod_tap_interrupt"),
+    flag(8, 2, "tapping_force_hold"),
+    flag(8, 3, "retro_tapping"),
+    flag(8, 4, "hold_on_other_key_press"),
+];
+
+/// Checks the status the firmware returns in the first byte for settings commands.
+fn settings_status(report: &[u8]) -> Result<()> {
+    match protocol::byte(report, 0)? {
+        0 => Ok(()),
+        _ => Err(Error::InvalidArgument("QMK setting id")),
+    }
+}
+
+/// Returns the ids of the supported settings greater than `after`, in ascending order.
+///
+/// The response holds as many ids as fit into a report.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QmkSettingsQuery {
+    pub after: u16,
+}
+
+impl Request for QmkSettingsQuery {
+    type Response = Vec<u16>;
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        let [lo, hi] = self.after.to_le_bytes();
+        vec![VialCommandId::QmkSettingsQuery as u8, lo, hi]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<Vec<u16>> {
+        Ok(report
+            .chunks_exact(2)
+            .map(|qsid| u16::from_le_bytes([qsid[0], qsid[1]]))
+            .take_while(|qsid| *qsid != QSID_END)
+            .collect())
+    }
+}
+
+/// Returns the value of a setting.
+///
+/// Unknown ids are reported as invalid argument errors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QmkSettingsGet {
+    pub qsid: u16,
+    pub setting_type: QmkSettingType,
+}
+
+impl Request for QmkSettingsGet {
+    type Response = QmkSettingValue;
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        let [lo, hi] = self.qsid.to_le_bytes();
+        vec![VialCommandId::QmkSettingsGet as u8, lo, hi]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<QmkSettingValue> {
+        settings_status(report)?;
+        let value = vial::le_value(report, 1, self.setting_type.width())?;
+        QmkSettingValue::new(self.setting_type, value)
+    }
+}
+
+/// Sets the value of a setting.
+///
+/// Unknown ids are reported as invalid argument errors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QmkSettingsSet {
+    pub qsid: u16,
+    pub value: QmkSettingValue,
+}
+
+impl Request for QmkSettingsSet {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        let [lo, hi] = self.qsid.to_le_bytes();
+        let mut bytes = vec![VialCommandId::QmkSettingsSet as u8, lo, hi];
+        bytes.extend(self.value.encode());
+        bytes
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<()> {
+        settings_status(report)
+    }
+}
+
+/// Resets all settings to the defaults of the firmware.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QmkSettingsReset;
+
+impl Request for QmkSettingsReset {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![VialCommandId::QmkSettingsReset as u8]
+    }
+
+    fn decode(&self, _report: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the well-known flag with the given name, or an invalid argument error.
+fn known_flag(name: &str) -> Result<&'static QmkSettingFlag> {
+    QmkSettingFlag::by_name(name).ok_or(Error::InvalidArgument("unknown QMK setting flag"))
+}
+
+#[cfg_attr(feature = "python", pymethods)]
+impl KeyboardApi {
+    /// Returns the ids of all settings supported by the keyboard in ascending order.
+    ///
+    /// Fails with an unsupported feature error if the Vial protocol predates QMK settings.
+    pub fn get_qmk_setting_qsids(&self) -> Result<Vec<u16>> {
+        let _call = trace::enter_call("get_qmk_setting_qsids");
+        if self.get_vial_keyboard_id()?.protocol_version < VIAL_PROTOCOL_QMK_SETTINGS {
+            return Err(Error::UnsupportedFeature("Vial QMK settings"));
+        }
+
+        let page_size = self.get_report_size() / 2;
+        let mut qsids: Vec<u16> = Vec::new();
+        let mut after = 0;
+        loop {
+            let page = self.request(&QmkSettingsQuery { after })?;
+            qsids.extend(&page);
+            match page.last() {
+                // A full page may be followed by more ids
+                Some(&last) if page.len() == page_size && last > after => after = last,
+                _ => return Ok(qsids),
+            }
+        }
+    }
+
+    /// Returns the value of a well-known setting. Other settings are read with
+    /// [`get_qmk_setting_as`](KeyboardApi::get_qmk_setting_as).
+    ///
+    /// Fails with an invalid argument error if the keyboard does not support the setting.
+    pub fn get_qmk_setting(&self, qsid: u16) -> Result<QmkSettingValue> {
+        let _call = trace::enter_call("get_qmk_setting");
+        let setting =
+            QmkSetting::by_qsid(qsid).ok_or(Error::InvalidArgument("unknown QMK setting"))?;
+        self.get_qmk_setting_as(qsid, setting.setting_type)
+    }
+
+    /// Returns the value of any setting, read as the given type.
+    pub fn get_qmk_setting_as(
+        &self,
+        qsid: u16,
+        setting_type: QmkSettingType,
+    ) -> Result<QmkSettingValue> {
+        let _call = trace::enter_call("get_qmk_setting_as");
+        self.request(&QmkSettingsGet { qsid, setting_type })
+    }
+
+    /// Sets the value of a setting. Values of well-known settings must have the type of the
+    /// setting.
+    ///
+    /// Fails with an invalid argument error if the keyboard does not support the setting.
+    pub fn set_qmk_setting(&self, qsid: u16, value: QmkSettingValue) -> Result<()> {
+        let _call = trace::enter_call("set_qmk_setting");
+        if QmkSetting::by_qsid(qsid)
+            .is_some_and(|setting| setting.setting_type != value.setting_type())
+        {
+            return Err(Error::InvalidArgument("QMK setting value type"));
+        }
+        self.request(&QmkSettingsSet { qsid, value })
+    }
+
+    /// Returns whether a well-known flag, e.g. `permissive_hold`, is set.
+    pub fn get_qmk_setting_flag(&self, name: &str) -> Result<bool> {
+        let _call = trace::enter_call("get_qmk_setting_flag");
+        let flag = known_flag(name)?;
+        let value = self.get_qmk_setting(flag.qsid)?;
+        Ok(value.as_u32() & 1 << flag.bit != 0)
+    }
+
+    /// Sets or clears a well-known flag, keeping the other flags of its setting.
+    pub fn set_qmk_setting_flag(&self, name: &str, enabled: bool) -> Result<()> {
+        let _call = trace::enter_call("set_qmk_setting_flag");
+        let flag = known_flag(name)?;
+        let QmkSettingValue::Flags(flags) = self.get_qmk_setting(flag.qsid)? else {
+            return Err(Error::InvalidArgument("QMK setting value type"));
+        };
+        let flags = match enabled {
+            true => flags | 1 << flag.bit,
+            false => flags & !(1 << flag.bit),
+        };
+        self.request(&QmkSettingsSet {
+            qsid: flag.qsid,
+            value: QmkSettingValue::Flags(flags),
+        })
+    }
+
+    /// Resets all settings to the defaults of the firmware.
+    pub fn reset_qmk_settings(&self) -> Result<()> {
+        let _call = trace::enter_call("reset_qmk_settings");
+        self.request(&QmkSettingsReset)
+    }
+}
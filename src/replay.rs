@@ -0,0 +1,257 @@
+use crate::api_commands::ViaCommandId;
+use crate::trace::{TraceDirection, TraceEntry};
+use crate::transport::Transport;
+use crate::{utils, Error, Result};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+struct State {
+    reports: VecDeque<(TraceDirection, Vec<u8>)>,
+    position: usize,
+    /// Response that is not part of the trace, read before the remaining reports
+    synthesized: Option<Vec<u8>>,
+}
+
+/// A fake device that plays back a trace recorded with
+/// [`KeyboardApi::start_trace`](crate::api::KeyboardApi::start_trace).
+///
+/// Every report sent by the library must match the next outgoing report of the trace, otherwise
+/// [`Error::ReplayMismatch`] is returned. Reads return the recorded incoming reports in order.
+/// If the trace starts after the keyboard was opened, the protocol version request issued when
+/// opening is answered from the session description of the trace.
+///
+/// ```no_run
+/// use qmk_via_api::api::KeyboardApi;
+/// use qmk_via_api::replay::ReplayTransport;
+///
+/// let replay = ReplayTransport::open("trace.jsonl")?;
+/// let api = KeyboardApi::from_transport(replay.clone())?;
+/// api.set_macro_bytes(vec![0x61, 0x62, 0x00])?;
+/// replay.finish()?;
+/// # Ok::<(), qmk_via_api::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct ReplayTransport {
+    state: Arc<Mutex<State>>,
+    protocol_version: Option<u16>,
+    report_size: Option<usize>,
+}
+
+impl ReplayTransport {
+    /// Creates a replay from a list of reports. Outgoing reports do not include the report ID.
+    pub fn new(reports: Vec<(TraceDirection, Vec<u8>)>) -> Self {
+        ReplayTransport {
+            report_size: reports.first().map(|(_, report)| report.len()),
+            state: Arc::new(Mutex::new(State {
+                reports: reports.into(),
+                position: 0,
+                synthesized: None,
+            })),
+            protocol_version: None,
+        }
+    }
+
+    /// Reads a trace in the JSON lines format written by [`crate::trace::TraceRecorder`].
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut reports = Vec::new();
+        let mut session = None;
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match TraceEntry::parse(&line)? {
+                TraceEntry::Session {
+                    protocol_version,
+                    report_size,
+                } => session = Some((protocol_version, report_size)),
+                TraceEntry::Report { direction, report } => reports.push((direction, report)),
+            }
+        }
+
+        let mut replay = Self::new(reports);
+        if let Some((protocol_version, report_size)) = session {
+            replay.protocol_version = Some(protocol_version);
+            replay.report_size = Some(report_size);
+        }
+        Ok(replay)
+    }
+
+    /// Reads a trace file in the JSON lines format written by [`crate::trace::TraceRecorder`].
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns the number of reports that have not been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.state().reports.len()
+    }
+
+    /// Checks that every recorded report has been replayed.
+    pub fn finish(&self) -> Result<()> {
+        let state = self.state();
+        match state.reports.front() {
+            Some((_, report)) => Err(Error::ReplayMismatch {
+                index: state.position,
+                expected: Some(report.clone()),
+                actual: None,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Builds the response to a protocol version request that is not part of the trace.
+    fn protocol_version_response(&self, request: &[u8]) -> Option<Vec<u8>> {
+        let protocol_version = self.protocol_version?;
+        if request.first() != Some(&(ViaCommandId::GetProtocolVersion as u8)) {
+            return None;
+        }
+        let mut response = request.to_vec();
+        (response[1], response[2]) = utils::shift_from_16_bit(protocol_version);
+        Some(response)
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send_report(&self, data: &[u8]) -> Result<usize> {
+        // Traces do not contain the report id
+        let report = data.get(1..).unwrap_or_default();
+        let mut state = self.state();
+        match state.reports.front() {
+            Some((TraceDirection::Out, expected)) if expected == report => {
+                state.reports.pop_front();
+                state.position += 1;
+                Ok(data.len())
+            }
+            expected => match self.protocol_version_response(report) {
+                Some(response) if state.position == 0 => {
+                    state.synthesized = Some(response);
+                    Ok(data.len())
+                }
+                _ => Err(Error::ReplayMismatch {
+                    index: state.position,
+                    expected: expected.map(|(_, expected)| expected.clone()),
+                    actual: Some(report.to_vec()),
+                }),
+            },
+        }
+    }
+
+    fn read_report(&self, buffer: &mut [u8], _timeout_ms: i32) -> Result<usize> {
+        let mut state = self.state();
+        let report = match state.synthesized.take() {
+            Some(report) => report,
+            None => {
+                if !matches!(state.reports.front(), Some((TraceDirection::In, _))) {
+                    return Ok(0);
+                }
+                let Some((_, report)) = state.reports.pop_front() else {
+                    return Ok(0);
+                };
+                state.position += 1;
+                report
+            }
+        };
+        let length = std::cmp::min(buffer.len(), report.len());
+        buffer[..length].copy_from_slice(&report[..length]);
+        Ok(length)
+    }
+
+    fn report_size(&self) -> Result<Option<usize>> {
+        Ok(self.report_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::KeyboardApi;
+    use crate::emulator::VirtualKeyboard;
+
+    const TRACE: &str = r#"{"time_us":0,"protocol_version":11,"report_size":8}
+{"time_us":1,"direction":"out","command":"DynamicKeymapGetLayerCount","call":"get_layer_count","report":"1100000000000000"}
+{"time_us":2,"direction":"in","command":"DynamicKeymapGetLayerCount","call":"get_layer_count","report":"1104000000000000"}
+"#;
+
+    #[test]
+    fn answers_protocol_version_before_trace() {
+        let replay = ReplayTransport::from_reader(TRACE.as_bytes()).unwrap();
+        assert_eq!(replay.report_size().unwrap(), Some(8));
+
+        let api = KeyboardApi::from_transport(replay.clone()).unwrap();
+        assert_eq!(api.get_protocol_version().unwrap(), 11);
+        assert_eq!(api.get_layer_count().unwrap(), 4);
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn rejects_protocol_version_within_trace() {
+        let replay = ReplayTransport::from_reader(TRACE.as_bytes()).unwrap();
+        replay
+            .send_report(&[0x00, 0x11, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        assert!(matches!(
+            replay.send_report(&[0x00, 0x01, 0, 0, 0, 0, 0, 0, 0]),
+            Err(Error::ReplayMismatch {
+                index: 1,
+                expected: Some(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn reports_mismatch() {
+        let replay = ReplayTransport::from_reader(TRACE.as_bytes()).unwrap();
+        let api = KeyboardApi::from_transport(replay.clone()).unwrap();
+        let err = api.get_key(0, 0, 0).unwrap_err();
+        let Error::ReplayMismatch {
+            index,
+            expected,
+            actual,
+        } = err
+        else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert_eq!(index, 0);
+        assert_eq!(expected, Some(vec![0x11, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(actual, Some(vec![0x04, 0, 0, 0, 0, 0, 0, 0]));
+        assert!(matches!(
+            replay.finish(),
+            Err(Error::ReplayMismatch { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn replays_recorded_trace() {
+        let path =
+            std::env::temp_dir().join(format!("qmk-via-api-replay-{}.jsonl", std::process::id()));
+        let keyboard = VirtualKeyboard::default();
+        let api = KeyboardApi::from_transport(keyboard.clone()).unwrap();
+        api.start_trace(path.to_str().unwrap()).unwrap();
+        api.set_key(1, 2, 3, 0x0004).unwrap();
+        api.set_macro_bytes(vec![0x61, 0x62, 0x00]).unwrap();
+        let layer_count = api.get_layer_count().unwrap();
+        let macros = api.get_macro_bytes().unwrap();
+        api.stop_trace();
+
+        let replay = ReplayTransport::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let replayed = KeyboardApi::from_transport(replay.clone()).unwrap();
+        assert_eq!(replayed.get_report_size(), api.get_report_size());
+        replayed.set_key(1, 2, 3, 0x0004).unwrap();
+        replayed.set_macro_bytes(vec![0x61, 0x62, 0x00]).unwrap();
+        assert_eq!(replayed.get_layer_count().unwrap(), layer_count);
+        assert_eq!(replayed.get_macro_bytes().unwrap(), macros);
+        replay.finish().unwrap();
+    }
+}
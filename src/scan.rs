@@ -1,14 +1,18 @@
+use crate::api::KeyboardApi;
+use crate::api_commands::ViaChannelId;
 use crate::Result;
-use hidapi::HidApi;
+use hidapi::{DeviceInfo, HidApi};
 
 #[cfg(feature = "python")]
 use pyo3::prelude::*;
 
-const VIA_USAGE_PAGE: u16 = 0xff60;
+/// Usage page of the raw HID interface used by VIA.
+pub const VIA_USAGE_PAGE: u16 = 0xff60;
 
 /// Information about a connected VIA-compatible keyboard.
 #[cfg_attr(feature = "python", pyclass(get_all, from_py_object))]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct KeyboardDeviceInfo {
     /// USB vendor ID
     pub vendor_id: u16,
@@ -16,29 +20,344 @@ pub struct KeyboardDeviceInfo {
     pub product_id: u16,
     /// HID usage page (expected to be 0xFF60 for VIA)
     pub usage_page: u16,
+    /// HID usage (expected to be 0x61 for VIA)
+    pub usage: u16,
     /// Optional manufacturer string
     pub manufacturer: Option<String>,
     /// Optional product string
     pub product: Option<String>,
     /// Optional serial number string
     pub serial_number: Option<String>,
+    /// Platform specific path of the HID interface, unique among connected devices
+    pub path: String,
+    /// USB interface number, or -1 if unknown
+    pub interface_number: i32,
 }
 
-/// Scan for connected VIA keyboards.
-#[cfg_attr(feature = "python", pyfunction)]
-pub fn scan_keyboards() -> Result<Vec<KeyboardDeviceInfo>> {
-    let api = HidApi::new()?;
-
-    Ok(api
-        .device_list()
-        .filter(|d| d.usage_page() == VIA_USAGE_PAGE)
-        .map(|d| KeyboardDeviceInfo {
+impl From<&DeviceInfo> for KeyboardDeviceInfo {
+    fn from(d: &DeviceInfo) -> Self {
+        KeyboardDeviceInfo {
             vendor_id: d.vendor_id(),
             product_id: d.product_id(),
             usage_page: d.usage_page(),
+            usage: d.usage(),
             manufacturer: d.manufacturer_string().map(|s| s.to_string()),
             product: d.product_string().map(|s| s.to_string()),
             serial_number: d.serial_number().map(|s| s.to_string()),
+            path: d.path().to_string_lossy().into_owned(),
+            interface_number: d.interface_number(),
+        }
+    }
+}
+
+/// Features supported by a VIA keyboard, see [`KeyboardApi::get_capabilities`].
+#[cfg_attr(feature = "python", pyclass(get_all, from_py_object))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeyboardCapabilities {
+    /// VIA protocol version
+    pub protocol_version: u16,
+    /// Number of dynamic keymap layers
+    pub layer_count: u8,
+    /// Number of macros, 0 if macros are not supported
+    pub macro_count: u8,
+    /// Size of the macro buffer in bytes
+    pub macro_buffer_size: u16,
+    /// Whether encoders can be mapped
+    pub has_encoders: bool,
+    /// Lighting channels that respond to commands (protocol V3 only)
+    pub lighting_channels: Vec<ViaChannelId>,
+    /// Whether the keyboard runs Vial firmware
+    pub vial: bool,
+    /// Firmware version (protocol V3 only)
+    pub firmware_version: Option<u32>,
+}
+
+/// A keyboard found by [`probe_keyboards`].
+#[cfg_attr(feature = "python", pyclass(get_all, from_py_object))]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProbedKeyboard {
+    pub device: KeyboardDeviceInfo,
+    /// Capabilities of the keyboard, or None if probing failed
+    pub capabilities: Option<KeyboardCapabilities>,
+    /// Reason why the keyboard could not be opened or probed
+    pub error: Option<String>,
+}
+
+impl KeyboardDeviceInfo {
+    /// Returns whether both interfaces belong to the same physical device.
+    ///
+    /// Interfaces are only attributed to the same device if they share a path, or if they are
+    /// different interfaces with the same non-empty serial number. Many firmwares use a constant
+    /// serial number, so equal interfaces of identical keyboards are never merged.
+    fn is_same_device(&self, other: &KeyboardDeviceInfo) -> bool {
+        self.path == other.path
+            || (self.vendor_id == other.vendor_id
+                && self.product_id == other.product_id
+                && self.serial_number.as_deref().is_some_and(|s| !s.is_empty())
+                && self.serial_number == other.serial_number
+                && self.interface_number != other.interface_number)
+    }
+}
+
+/// Builder for a filtered scan of connected keyboards.
+///
+/// By default all devices with the VIA usage page are found and only the first matching
+/// interface of each physical device is reported.
+///
+/// ```no_run
+/// use qmk_via_api::scan::KeyboardScanner;
+///
+/// let macropads = KeyboardScanner::new()
+///     .vendor_id(0x4653)
+///     .product("macropad")
+///     .scan()?;
+/// # Ok::<(), qmk_via_api::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct KeyboardScanner {
+    vendor_id: Option<u16>,
+    product_id: Option<u16>,
+    product: Option<String>,
+    serial_number: Option<String>,
+    usage_page: u16,
+    usage: Option<u16>,
+    deduplicate: bool,
+}
+
+impl Default for KeyboardScanner {
+    fn default() -> Self {
+        KeyboardScanner {
+            vendor_id: None,
+            product_id: None,
+            product: None,
+            serial_number: None,
+            usage_page: VIA_USAGE_PAGE,
+            usage: None,
+            deduplicate: true,
+        }
+    }
+}
+
+impl KeyboardScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only finds devices with the given USB vendor ID.
+    pub fn vendor_id(mut self, vendor_id: u16) -> Self {
+        self.vendor_id = Some(vendor_id);
+        self
+    }
+
+    /// Only finds devices with the given USB product ID.
+    pub fn product_id(mut self, product_id: u16) -> Self {
+        self.product_id = Some(product_id);
+        self
+    }
+
+    /// Only finds devices whose product string contains the given text, ignoring case.
+    pub fn product(mut self, product: &str) -> Self {
+        self.product = Some(product.to_lowercase());
+        self
+    }
+
+    /// Only finds devices with the given serial number.
+    pub fn serial_number(mut self, serial_number: &str) -> Self {
+        self.serial_number = Some(serial_number.to_string());
+        self
+    }
+
+    /// Finds devices with the given HID usage page instead of [`VIA_USAGE_PAGE`].
+    pub fn usage_page(mut self, usage_page: u16) -> Self {
+        self.usage_page = usage_page;
+        self
+    }
+
+    /// Only finds devices with the given HID usage.
+    pub fn usage(mut self, usage: u16) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    /// Sets whether only the first matching interface of each physical device is reported.
+    pub fn deduplicate(mut self, deduplicate: bool) -> Self {
+        self.deduplicate = deduplicate;
+        self
+    }
+
+    fn matches(&self, device: &KeyboardDeviceInfo) -> bool {
+        device.usage_page == self.usage_page
+            && self.usage.is_none_or(|usage| device.usage == usage)
+            && self.vendor_id.is_none_or(|vid| device.vendor_id == vid)
+            && self.product_id.is_none_or(|pid| device.product_id == pid)
+            && self.product.as_ref().is_none_or(|product| {
+                device
+                    .product
+                    .as_ref()
+                    .is_some_and(|name| name.to_lowercase().contains(product))
+            })
+            && self
+                .serial_number
+                .as_ref()
+                .is_none_or(|serial| device.serial_number.as_ref() == Some(serial))
+    }
+
+    /// Scans for connected devices matching all filters.
+    pub fn scan(&self) -> Result<Vec<KeyboardDeviceInfo>> {
+        let api = HidApi::new()?;
+
+        let mut devices: Vec<KeyboardDeviceInfo> = Vec::new();
+        for device in api.device_list().map(KeyboardDeviceInfo::from) {
+            if !self.matches(&device)
+                || (self.deduplicate && devices.iter().any(|d| d.is_same_device(&device)))
+            {
+                continue;
+            }
+            devices.push(device);
+        }
+        Ok(devices)
+    }
+}
+
+/// Scan for connected VIA keyboards.
+pub fn scan_keyboards() -> Result<Vec<KeyboardDeviceInfo>> {
+    KeyboardScanner::new().scan()
+}
+
+/// Scan for connected keyboards, optionally filtered by the given criteria.
+#[cfg(feature = "python")]
+#[pyfunction]
+#[pyo3(
+    name = "scan_keyboards",
+    signature = (vendor_id=None, product_id=None, product=None, serial_number=None, usage_page=VIA_USAGE_PAGE, usage=None, deduplicate=true)
+)]
+pub fn py_scan_keyboards(
+    vendor_id: Option<u16>,
+    product_id: Option<u16>,
+    product: Option<&str>,
+    serial_number: Option<&str>,
+    usage_page: u16,
+    usage: Option<u16>,
+    deduplicate: bool,
+) -> Result<Vec<KeyboardDeviceInfo>> {
+    let mut scanner = KeyboardScanner::new()
+        .usage_page(usage_page)
+        .deduplicate(deduplicate);
+    if let Some(vendor_id) = vendor_id {
+        scanner = scanner.vendor_id(vendor_id);
+    }
+    if let Some(product_id) = product_id {
+        scanner = scanner.product_id(product_id);
+    }
+    if let Some(product) = product {
+        scanner = scanner.product(product);
+    }
+    if let Some(serial_number) = serial_number {
+        scanner = scanner.serial_number(serial_number);
+    }
+    if let Some(usage) = usage {
+        scanner = scanner.usage(usage);
+    }
+    scanner.scan()
+}
+
+/// Scan for connected VIA keyboards and query the capabilities of each.
+///
+/// Every keyboard is opened in turn. Keyboards that cannot be opened or probed, e.g. because
+/// another application is using them, are still reported along with the error.
+#[cfg_attr(feature = "python", pyfunction)]
+pub fn probe_keyboards() -> Result<Vec<ProbedKeyboard>> {
+    Ok(scan_keyboards()?
+        .into_iter()
+        .map(|device| {
+            match KeyboardApi::from_device(&device).and_then(|api| api.get_capabilities()) {
+                Ok(capabilities) => ProbedKeyboard {
+                    device,
+                    capabilities: Some(capabilities),
+                    error: None,
+                },
+                Err(err) => ProbedKeyboard {
+                    device,
+                    capabilities: None,
+                    error: Some(err.to_string()),
+                },
+            }
         })
         .collect())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(
+        path: &str,
+        serial_number: Option<&str>,
+        interface_number: i32,
+    ) -> KeyboardDeviceInfo {
+        KeyboardDeviceInfo {
+            vendor_id: 0x4653,
+            product_id: 0x0001,
+            usage_page: VIA_USAGE_PAGE,
+            usage: 0x61,
+            manufacturer: None,
+            product: Some("Macropad".to_string()),
+            serial_number: serial_number.map(str::to_string),
+            path: path.to_string(),
+            interface_number,
+        }
+    }
+
+    #[test]
+    fn same_device() {
+        let keyboard = device("a", Some("123"), 1);
+        assert!(keyboard.is_same_device(&keyboard));
+        assert!(keyboard.is_same_device(&device("b", Some("123"), 2)));
+
+        // Identical keyboards with a serial number built into the firmware
+        assert!(!keyboard.is_same_device(&device("b", Some("123"), 1)));
+        // Keyboards without serial numbers cannot be told apart
+        assert!(!device("a", None, 1).is_same_device(&device("b", None, 2)));
+        assert!(!device("a", Some(""), 1).is_same_device(&device("b", Some(""), 2)));
+        assert!(!keyboard.is_same_device(&device("b", Some("456"), 2)));
+        assert!(!keyboard.is_same_device(&KeyboardDeviceInfo {
+            product_id: 0x0002,
+            ..device("b", Some("123"), 2)
+        }));
+    }
+
+    #[test]
+    fn matches_filters() {
+        let keyboard = device("a", Some("123"), 1);
+        assert!(KeyboardScanner::new().matches(&keyboard));
+        assert!(KeyboardScanner::new()
+            .vendor_id(0x4653)
+            .product_id(0x0001)
+            .product("MACRO")
+            .serial_number("123")
+            .usage(0x61)
+            .matches(&keyboard));
+
+        assert!(!KeyboardScanner::new().vendor_id(0x1234).matches(&keyboard));
+        assert!(!KeyboardScanner::new().product_id(0x0002).matches(&keyboard));
+        assert!(!KeyboardScanner::new()
+            .product("keyboard")
+            .matches(&keyboard));
+        assert!(!KeyboardScanner::new()
+            .serial_number("12")
+            .matches(&keyboard));
+        assert!(!KeyboardScanner::new().usage(0x62).matches(&keyboard));
+        assert!(!KeyboardScanner::new().usage_page(0xff00).matches(&keyboard));
+        assert!(!KeyboardScanner::new()
+            .product("macro")
+            .matches(&KeyboardDeviceInfo {
+                product: None,
+                ..keyboard.clone()
+            }));
+        assert!(!KeyboardScanner::new()
+            .serial_number("123")
+            .matches(&device("a", None, 1)));
+    }
+}
@@ -0,0 +1,384 @@
+use crate::api_commands::ViaCommandId;
+use crate::{Error, Result};
+use std::cell::Cell;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+thread_local! {
+    static CURRENT_CALL: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+/// Wraps a public API call in a `keyboard_api` span with the method name until dropped, if the
+/// `tracing` feature is enabled.
+pub(crate) struct SpanGuard {
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
+}
+
+pub(crate) fn enter_span(_name: &'static str) -> SpanGuard {
+    SpanGuard {
+        #[cfg(feature = "tracing")]
+        _span: tracing::debug_span!("keyboard_api", method = _name).entered(),
+    }
+}
+
+/// Marks the public API call that is currently executing on this thread until dropped.
+///
+/// Nested calls, e.g. `set_key` issued by `write_raw_matrix`, are attributed to the outermost call.
+/// Every call, including nested ones, is also wrapped in a span, see [`enter_span`]. Calls that
+/// do not exchange reports only need the span.
+pub(crate) struct CallGuard {
+    outermost: bool,
+    _span: SpanGuard,
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        if self.outermost {
+            CURRENT_CALL.with(|call| call.set(None));
+        }
+    }
+}
+
+pub(crate) fn enter_call(name: &'static str) -> CallGuard {
+    let outermost = CURRENT_CALL.with(|call| {
+        if call.get().is_none() {
+            call.set(Some(name));
+            true
+        } else {
+            false
+        }
+    });
+    CallGuard {
+        outermost,
+        _span: enter_span(name),
+    }
+}
+
+/// Returns the public API call currently executing on this thread.
+pub(crate) fn current_call() -> Option<&'static str> {
+    CURRENT_CALL.with(|call| call.get())
+}
+
+/// Direction of a traced report.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceDirection {
+    /// Report sent from the host to the keyboard
+    Out,
+    /// Report received from the keyboard
+    In,
+}
+
+impl TraceDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TraceDirection::Out => "out",
+            TraceDirection::In => "in",
+        }
+    }
+}
+
+/// Records every exchanged HID report as a line of JSON.
+///
+/// The first line describes the traced session with the following fields:
+///
+/// - `time_us`: microseconds since the Unix epoch
+/// - `protocol_version`: VIA protocol version of the keyboard
+/// - `report_size`: size of raw HID reports in bytes
+///
+/// Every following line is an object with the following fields:
+///
+/// - `time_us`: microseconds since the Unix epoch
+/// - `direction`: `"out"` for reports sent to the keyboard, `"in"` for received reports
+/// - `command`: name of the [`ViaCommandId`] in the first byte of the report, or `null`
+/// - `call`: name of the `KeyboardApi` method that caused the exchange, or `null`
+/// - `report`: hex encoded report without the report ID
+pub struct TraceRecorder {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl TraceRecorder {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        TraceRecorder {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Creates a recorder writing to the given file, replacing any existing content.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(File::create(path)?))
+    }
+
+    /// Writes the session description that starts a trace.
+    pub fn record_session(&self, protocol_version: u16, report_size: usize) -> Result<()> {
+        self.write_line(&format!(
+            "{{\"time_us\":{},\"protocol_version\":{},\"report_size\":{}}}\n",
+            now_us(),
+            protocol_version,
+            report_size
+        ))
+    }
+
+    /// Writes a single report to the trace.
+    pub fn record(&self, direction: TraceDirection, report: &[u8]) -> Result<()> {
+        let command = report
+            .first()
+            .and_then(|byte| ViaCommandId::try_from(*byte).ok())
+            .map_or("null".to_string(), |command| {
+                json_string(&format!("{:?}", command))
+            });
+        let call = current_call().map_or("null".to_string(), json_string);
+        self.write_line(&format!(
+            "{{\"time_us\":{},\"direction\":\"{}\",\"command\":{},\"call\":{},\"report\":\"{}\"}}\n",
+            now_us(),
+            direction.as_str(),
+            command,
+            call,
+            encode_hex(report)
+        ))
+    }
+
+    fn write_line(&self, line: &str) -> Result<()> {
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        writer.write_all(line.as_bytes())?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A line read back from a trace written by [`TraceRecorder`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceEntry {
+    Session {
+        protocol_version: u16,
+        report_size: usize,
+    },
+    Report {
+        direction: TraceDirection,
+        report: Vec<u8>,
+    },
+}
+
+impl TraceEntry {
+    /// Parses a single line of a trace.
+    pub fn parse(line: &str) -> Result<TraceEntry> {
+        if let Some(protocol_version) = json_field(line, "protocol_version") {
+            let report_size = json_field(line, "report_size").ok_or(invalid_line())?;
+            return Ok(TraceEntry::Session {
+                protocol_version: protocol_version.parse().map_err(|_| invalid_line())?,
+                report_size: report_size.parse().map_err(|_| invalid_line())?,
+            });
+        }
+
+        let direction = match json_field(line, "direction") {
+            Some("\"out\"") => TraceDirection::Out,
+            Some("\"in\"") => TraceDirection::In,
+            _ => return Err(invalid_line()),
+        };
+        let report = json_field(line, "report")
+            .and_then(|report| report.strip_prefix('"')?.strip_suffix('"'))
+            .and_then(decode_hex)
+            .ok_or(invalid_line())?;
+        Ok(TraceEntry::Report { direction, report })
+    }
+}
+
+fn invalid_line() -> Error {
+    Error::InvalidArgument("malformed trace line")
+}
+
+/// Encodes a string as a JSON string literal.
+fn json_string(value: &str) -> String {
+    let mut string = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            '\n' => string.push_str("\\n"),
+            '\r' => string.push_str("\\r"),
+            '\t' => string.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(string, "\\u{:04x}", c as u32);
+            }
+            c => string.push(c),
+        }
+    }
+    string.push('"');
+    string
+}
+
+/// Returns the raw value of a field in a flat JSON object as written by [`TraceRecorder`].
+///
+/// String values are returned with their quotes and escape sequences.
+fn json_field<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let key = json_string(name);
+    let rest = line[line.find(&key)? + key.len()..]
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start();
+    let end = if let Some(string) = rest.strip_prefix('"') {
+        // Find the closing quote, skipping escaped characters
+        let mut chars = string.char_indices();
+        loop {
+            match chars.next()? {
+                (_, '\\') => {
+                    chars.next()?;
+                }
+                (idx, '"') => break idx + 2,
+                _ => {}
+            }
+        }
+    } else {
+        rest.find([',', '}'])?
+    };
+    Some(rest[..end].trim())
+}
+
+fn now_us() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_micros())
+}
+
+pub(crate) fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// A writer whose output can be inspected after it was moved into a recorder.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn lines(&self) -> Vec<String> {
+            let buffer = self.0.lock().unwrap();
+            String::from_utf8(buffer.clone())
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("get_key"), "\"get_key\"");
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("\n\t\u{1}"), "\"\\n\\t\\u0001\"");
+    }
+
+    #[test]
+    fn json_field_values() {
+        let line = r#"{"a":1,"b":"x\"y","c": null , "d":"\\","e":"\"e\":2"}"#;
+        assert_eq!(json_field(line, "a"), Some("1"));
+        assert_eq!(json_field(line, "b"), Some(r#""x\"y""#));
+        assert_eq!(json_field(line, "c"), Some("null"));
+        assert_eq!(json_field(line, "d"), Some(r#""\\""#));
+        assert_eq!(json_field(line, "e"), Some(r#""\"e\":2""#));
+        assert_eq!(json_field(line, "f"), None);
+        assert_eq!(json_field(r#"{"a":"open"#, "a"), None);
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let bytes = [0x00, 0x0f, 0xa5, 0xff];
+        assert_eq!(encode_hex(&bytes), "000fa5ff");
+        assert_eq!(decode_hex("000fA5ff"), Some(bytes.to_vec()));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+
+    #[test]
+    fn recorded_lines_parse() {
+        let buffer = SharedBuffer::default();
+        let recorder = TraceRecorder::new(buffer.clone());
+        recorder.record_session(11, 32).unwrap();
+        {
+            let _call = enter_call("get_key");
+            recorder
+                .record(TraceDirection::Out, &[0x04, 0x00, 0x01, 0x02])
+                .unwrap();
+        }
+        recorder.record(TraceDirection::In, &[0xff, 0x00]).unwrap();
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].contains(r#""command":"DynamicKeymapGetKeycode","call":"get_key""#));
+        assert!(lines[2].contains(r#""command":null,"call":null"#));
+
+        let entries: Vec<_> = lines
+            .iter()
+            .map(|line| TraceEntry::parse(line).unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                TraceEntry::Session {
+                    protocol_version: 11,
+                    report_size: 32
+                },
+                TraceEntry::Report {
+                    direction: TraceDirection::Out,
+                    report: vec![0x04, 0x00, 0x01, 0x02]
+                },
+                TraceEntry::Report {
+                    direction: TraceDirection::In,
+                    report: vec![0xff, 0x00]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_lines() {
+        for line in [
+            "",
+            "{}",
+            r#"{"protocol_version":11}"#,
+            r#"{"protocol_version":"x","report_size":32}"#,
+            r#"{"direction":"up","report":"00"}"#,
+            r#"{"direction":"in","report":"0"}"#,
+            r#"{"direction":"in"}"#,
+        ] {
+            assert!(
+                matches!(TraceEntry::parse(line), Err(Error::InvalidArgument(_))),
+                "{}",
+                line
+            );
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use crate::{utils, Result};
+
+/// A raw HID connection to a VIA keyboard.
+///
+/// Reports follow the hidapi conventions: the first byte passed to [`Transport::send_report`] is
+/// the report ID (always `0x00` for VIA), while reports returned by [`Transport::read_report`]
+/// do not contain a report ID.
+pub trait Transport: Send {
+    /// Writes a single report to the device and returns the number of bytes written.
+    fn send_report(&self, data: &[u8]) -> Result<usize>;
+
+    /// Reads a single report into `buffer` and returns the number of bytes read.
+    ///
+    /// Waits at most `timeout_ms` milliseconds for a report to arrive, or blocks indefinitely
+    /// if `timeout_ms` is negative. Returns 0 if no report arrived in time.
+    fn read_report(&self, buffer: &mut [u8], timeout_ms: i32) -> Result<usize>;
+
+    /// Returns the size of reports exchanged with the device, excluding the report ID, if known.
+    fn report_size(&self) -> Result<Option<usize>> {
+        Ok(None)
+    }
+}
+
+impl Transport for hidapi::HidDevice {
+    fn send_report(&self, data: &[u8]) -> Result<usize> {
+        Ok(self.write(data)?)
+    }
+
+    fn read_report(&self, buffer: &mut [u8], timeout_ms: i32) -> Result<usize> {
+        Ok(self.read_timeout(buffer, timeout_ms)?)
+    }
+
+    fn report_size(&self) -> Result<Option<usize>> {
+        let mut descriptor = vec![0; hidapi::MAX_REPORT_DESCRIPTOR_SIZE];
+        // Some platforms and backends cannot read the descriptor, leave the size unknown then
+        match self.get_report_descriptor(&mut descriptor) {
+            Ok(length) => Ok(utils::parse_report_size(&descriptor[..length])),
+            Err(_) => Ok(None),
+        }
+    }
+}
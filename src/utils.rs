@@ -41,3 +41,112 @@ pub fn shift_buffer_from_16_bit(buffer: &[u16]) -> Vec<u8> {
     }
     flattened
 }
+
+/// Determines the size of raw HID reports in bytes from a HID report descriptor.
+///
+/// Returns the size of the first output report, or of the first input report if the
+/// descriptor does not declare any output reports.
+pub fn parse_report_size(descriptor: &[u8]) -> Option<usize> {
+    const REPORT_SIZE: u8 = 0x74;
+    const REPORT_COUNT: u8 = 0x94;
+    const PUSH: u8 = 0xa4;
+    const POP: u8 = 0xb4;
+    const INPUT: u8 = 0x80;
+    const OUTPUT: u8 = 0x90;
+    const LONG_ITEM: u8 = 0xfe;
+
+    let mut globals = (0u32, 0u32);
+    let mut stack = Vec::new();
+    let mut input_size = None;
+    let mut idx = 0;
+    while idx < descriptor.len() {
+        let prefix = descriptor[idx];
+        if prefix == LONG_ITEM {
+            idx += 3 + *descriptor.get(idx + 1)? as usize;
+            continue;
+        }
+
+        let length = match prefix & 0x03 {
+            3 => 4,
+            length => length as usize,
+        };
+        let data = descriptor.get(idx + 1..idx + 1 + length)?;
+        let value = data
+            .iter()
+            .rev()
+            .fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+
+        let (report_size, report_count) = globals;
+        let size = (report_size as usize * report_count as usize).div_ceil(8);
+        match prefix & 0xfc {
+            REPORT_SIZE => globals.0 = value,
+            REPORT_COUNT => globals.1 = value,
+            PUSH => stack.push(globals),
+            POP => globals = stack.pop()?,
+            OUTPUT if size > 0 => return Some(size),
+            INPUT if size > 0 => input_size = input_size.or(Some(size)),
+            _ => {}
+        }
+        idx += 1 + length;
+    }
+    input_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the raw HID descriptor of QMK's `usb_descriptor.c` with the given report size,
+    /// optionally inserting items before the collection.
+    fn raw_hid_descriptor(report_size: u8, prefix: &[u8]) -> Vec<u8> {
+        let mut descriptor = vec![0x06, 0x60, 0xff, 0x09, 0x61];
+        descriptor.extend(prefix);
+        descriptor.extend([0xa1, 0x01]);
+        // Data to host
+        descriptor.extend([0x09, 0x62, 0x15, 0x00, 0x26, 0xff, 0x00]);
+        descriptor.extend([0x95, report_size, 0x75, 0x08, 0x81, 0x02]);
+        // Data from host
+        descriptor.extend([0x09, 0x63, 0x15, 0x00, 0x26, 0xff, 0x00]);
+        descriptor.extend([0x95, report_size, 0x75, 0x08, 0x91, 0x82]);
+        descriptor.push(0xc0);
+        descriptor
+    }
+
+    #[test]
+    fn parse_report_size_of_qmk_descriptor() {
+        assert_eq!(parse_report_size(&raw_hid_descriptor(32, &[])), Some(32));
+        assert_eq!(parse_report_size(&raw_hid_descriptor(64, &[])), Some(64));
+    }
+
+    #[test]
+    fn parse_report_size_ignores_report_id() {
+        let descriptor = raw_hid_descriptor(32, &[0x85, 0x01]);
+        assert_eq!(parse_report_size(&descriptor), Some(32));
+    }
+
+    #[test]
+    fn parse_report_size_skips_long_items() {
+        let descriptor = raw_hid_descriptor(64, &[0xfe, 0x03, 0xf0, 0x91, 0x02, 0x75]);
+        assert_eq!(parse_report_size(&descriptor), Some(64));
+    }
+
+    #[test]
+    fn parse_report_size_prefers_output_report() {
+        let mut descriptor = raw_hid_descriptor(32, &[]);
+        // Grow the output report to 48 bytes
+        descriptor[28] = 48;
+        assert_eq!(parse_report_size(&descriptor), Some(48));
+
+        let input_only = &descriptor[..20];
+        assert_eq!(parse_report_size(input_only), Some(32));
+    }
+
+    #[test]
+    fn parse_report_size_of_truncated_descriptor() {
+        let descriptor = raw_hid_descriptor(32, &[]);
+        // Cut inside the logical maximum of the output report
+        assert_eq!(parse_report_size(&descriptor[..26]), None);
+        assert_eq!(parse_report_size(&[0xfe, 0x10, 0x00]), None);
+        assert_eq!(parse_report_size(&[]), None);
+    }
+}
@@ -0,0 +1,934 @@
+//! Vial extensions of the VIA protocol.
+//!
+//! Vial firmware handles the sub-commands in [`VialCommandId`] behind
+//! [`ViaCommandId::VialPrefix`]. Unlike VIA, Vial does not echo the request but fills the whole
+//! response report, so its requests do not [echo](Request::ECHOES). Vial is detected once by
+//! sending [`GetKeyboardId`], which keyboards without Vial answer with
+//! [`protocol::ID_UNHANDLED`]. Vial requests to such keyboards fail with
+//! [`Error::UnsupportedCommand`].
+//!
+//! Downloading the keyboard definition requires the `vial-definition` feature.
+
+use crate::api::KeyboardApi;
+#[cfg(feature = "vial-definition")]
+use crate::api::MatrixInfo;
+use crate::api_commands::{ViaCommandId, VialCommandId, VialDynamicEntryOp};
+use crate::keycodes::Keycode;
+use crate::protocol::{self, Request};
+use crate::{trace, Error, Result};
+
+#[cfg(feature = "python")]
+use pyo3::prelude::*;
+
+/// Number of definition bytes returned per page by [`GetDefinition`].
+pub const DEFINITION_PAGE_SIZE: usize = 32;
+/// First Vial protocol version with dynamic entries, i.e. tap dance, combos and key overrides.
+pub const VIAL_PROTOCOL_DYNAMIC: u32 = 4;
+
+/// Returns `len` bytes of a Vial response as a little endian number.
+pub(crate) fn le_value(report: &[u8], start: usize, len: usize) -> Result<u32> {
+    Ok(protocol::bytes(report, start, len)?
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | *byte as u32))
+}
+
+/// Returns `N` little endian 16 bit values of a Vial response starting at `start`.
+fn le_words<const N: usize>(report: &[u8], start: usize) -> Result<[u16; N]> {
+    let bytes = protocol::bytes(report, start, N * 2)?;
+    Ok(core::array::from_fn(|i| {
+        u16::from_le_bytes([bytes[2 * i], bytes[2 * i + 1]])
+    }))
+}
+
+/// Returns the [`Keycode`] of a 16 bit keycode, or None if there is no variant for it.
+fn keycode(raw: u16) -> Option<Keycode> {
+    Keycode::try_from(raw).ok()
+}
+
+/// Returns the bytes preceding the entry index of a dynamic entry operation.
+fn dynamic_entry_op(op: VialDynamicEntryOp) -> Vec<u8> {
+    vec![VialCommandId::DynamicEntryOp as u8, op as u8]
+}
+
+/// Checks the status the firmware returns in the first byte for dynamic entry operations.
+fn dynamic_entry_status(report: &[u8]) -> Result<()> {
+    match protocol::byte(report, 0)? {
+        0 => Ok(()),
+        _ => Err(Error::InvalidArgument("Vial dynamic entry index")),
+    }
+}
+
+/// Vial protocol version and keyboard id.
+#[cfg_attr(feature = "python", pyclass(get_all, from_py_object))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VialKeyboardId {
+    /// Version of the Vial protocol implemented by the firmware
+    pub protocol_version: u32,
+    /// Unique id of the keyboard, used by Vial to match saved layouts
+    pub uid: u64,
+    /// Whether the firmware supports the VialRGB lighting protocol
+    pub vialrgb: bool,
+}
+
+/// Returns the Vial protocol version and keyboard id.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetKeyboardId;
+
+impl Request for GetKeyboardId {
+    type Response = VialKeyboardId;
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![VialCommandId::GetKeyboardId as u8]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<VialKeyboardId> {
+        let uid = protocol::bytes(report, 4, 8)?;
+        Ok(VialKeyboardId {
+            protocol_version: le_value(report, 0, 4)?,
+            uid: u64::from_le_bytes(uid.try_into().expect("8 bytes")),
+            // Older firmware leaves the byte zeroed
+            vialrgb: report.get(12) == Some(&1),
+        })
+    }
+}
+
+/// Returns the size of the LZMA compressed keyboard definition in bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetSize;
+
+impl Request for GetSize {
+    type Response = u32;
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        vec![VialCommandId::GetSize as u8]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<u32> {
+        le_value(report, 0, 4)
+    }
+}
+
+/// Returns a page of [`DEFINITION_PAGE_SIZE`] bytes of the LZMA compressed keyboard definition.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetDefinition {
+    pub page: u16,
+}
+
+impl Request for GetDefinition {
+    type Response = Vec<u8>;
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        let [lo, hi] = self.page.to_le_bytes();
+        vec![VialCommandId::GetDefinition as u8, lo, hi]
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<Vec<u8>> {
+        protocol::bytes(report, 0, DEFINITION_PAGE_SIZE).map(|page| page.to_vec())
+    }
+}
+
+/// Number of dynamic entries of each kind the firmware has room for.
+#[cfg_attr(feature = "python", pyclass(get_all, from_py_object))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VialEntryCounts {
+    pub tap_dance: u8,
+    pub combo: u8,
+    pub key_override: u8,
+}
+
+/// Returns the number of dynamic entries of each kind.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetEntryCounts;
+
+impl Request for GetEntryCounts {
+    type Response = VialEntryCounts;
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        dynamic_entry_op(VialDynamicEntryOp::GetNumberOfEntries)
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<VialEntryCounts> {
+        Ok(VialEntryCounts {
+            tap_dance: protocol::byte(report, 0)?,
+            combo: protocol::byte(report, 1)?,
+            key_override: protocol::byte(report, 2)?,
+        })
+    }
+}
+
+/// A tap dance entry, triggered by the `TD(index)` keycode.
+///
+/// Actions are keycodes, `KC_NO` leaves an action unassigned. Keyboards often assign composite
+/// keycodes like `MO(1)` that [`Keycode`] has no variant for, these are read with the `raw_*`
+/// accessors and set with [`TapDance::from_raw`].
+///
+/// ```
+/// use qmk_via_api::keycodes::Keycode;
+/// use qmk_via_api::vial::TapDance;
+///
+/// let tap_dance = TapDance::new(
+///     Keycode::KC_ESCAPE,
+///     Keycode::KC_NO,
+///     Keycode::KC_CAPS_LOCK,
+///     Keycode::KC_NO,
+///     200,
+/// );
+/// assert_eq!(tap_dance.on_tap(), Some(Keycode::KC_ESCAPE));
+/// assert_eq!(tap_dance.raw_on_double_tap(), 0x0039);
+/// ```
+#[cfg_attr(feature = "python", pyclass(get_all, set_all, from_py_object))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TapDance {
+    on_tap: u16,
+    on_hold: u16,
+    on_double_tap: u16,
+    on_tap_hold: u16,
+    /// Time in milliseconds telling taps from holds
+    pub tapping_term: u16,
+}
+
+impl TapDance {
+    /// Creates a tap dance from keycodes.
+    pub fn new(
+        on_tap: Keycode,
+        on_hold: Keycode,
+        on_double_tap: Keycode,
+        on_tap_hold: Keycode,
+        tapping_term: u16,
+    ) -> Self {
+        Self::from_raw(
+            on_tap.into(),
+            on_hold.into(),
+            on_double_tap.into(),
+            on_tap_hold.into(),
+            tapping_term,
+        )
+    }
+
+    /// Creates a tap dance from 16 bit keycodes.
+    pub fn from_raw(
+        on_tap: u16,
+        on_hold: u16,
+        on_double_tap: u16,
+        on_tap_hold: u16,
+        tapping_term: u16,
+    ) -> Self {
+        TapDance {
+            on_tap,
+            on_hold,
+            on_double_tap,
+            on_tap_hold,
+            tapping_term,
+        }
+    }
+
+    /// Returns the keycode sent when the key is tapped once.
+    pub fn on_tap(&self) -> Option<Keycode> {
+        keycode(self.on_tap)
+    }
+
+    /// Returns the keycode held while the key is held down.
+    pub fn on_hold(&self) -> Option<Keycode> {
+        keycode(self.on_hold)
+    }
+
+    /// Returns the keycode sent when the key is tapped twice.
+    pub fn on_double_tap(&self) -> Option<Keycode> {
+        keycode(self.on_double_tap)
+    }
+
+    /// Returns the keycode held while the key is held down after a tap.
+    pub fn on_tap_hold(&self) -> Option<Keycode> {
+        keycode(self.on_tap_hold)
+    }
+
+    pub fn raw_on_tap(&self) -> u16 {
+        self.on_tap
+    }
+
+    pub fn raw_on_hold(&self) -> u16 {
+        self.on_hold
+    }
+
+    pub fn raw_on_double_tap(&self) -> u16 {
+        self.on_double_tap
+    }
+
+    pub fn raw_on_tap_hold(&self) -> u16 {
+        self.on_tap_hold
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        [
+            self.on_tap,
+            self.on_hold,
+            self.on_double_tap,
+            self.on_tap_hold,
+            self.tapping_term,
+        ]
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+    }
+
+    fn decode(report: &[u8], start: usize) -> Result<Self> {
+        let [on_tap, on_hold, on_double_tap, on_tap_hold, tapping_term] = le_words(report, start)?;
+        Ok(Self::from_raw(
+            on_tap,
+            on_hold,
+            on_double_tap,
+            on_tap_hold,
+            tapping_term,
+        ))
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl TapDance {
+    #[new]
+    #[pyo3(signature = (on_tap=0, on_hold=0, on_double_tap=0, on_tap_hold=0, tapping_term=0))]
+    fn py_new(
+        on_tap: u16,
+        on_hold: u16,
+        on_double_tap: u16,
+        on_tap_hold: u16,
+        tapping_term: u16,
+    ) -> Self {
+        TapDance::from_raw(on_tap, on_hold, on_double_tap, on_tap_hold, tapping_term)
+    }
+}
+
+/// Returns a tap dance entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetTapDance {
+    pub index: u8,
+}
+
+impl Request for GetTapDance {
+    type Response = TapDance;
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = dynamic_entry_op(VialDynamicEntryOp::TapDanceGet);
+        bytes.push(self.index);
+        bytes
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<TapDance> {
+        dynamic_entry_status(report)?;
+        TapDance::decode(report, 1)
+    }
+}
+
+/// Sets a tap dance entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetTapDance {
+    pub index: u8,
+    pub tap_dance: TapDance,
+}
+
+impl Request for SetTapDance {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = dynamic_entry_op(VialDynamicEntryOp::TapDanceSet);
+        bytes.push(self.index);
+        bytes.extend(self.tap_dance.encode());
+        bytes
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<()> {
+        dynamic_entry_status(report)
+    }
+}
+
+/// A combo entry, sending `output` while all trigger keys are held down together.
+///
+/// Unused trigger keys are set to `KC_NO`. Keycodes without a [`Keycode`] variant are handled as
+/// in [`TapDance`].
+///
+/// ```
+/// use qmk_via_api::keycodes::Keycode;
+/// use qmk_via_api::vial::Combo;
+///
+/// let combo = Combo::new(&[Keycode::KC_J, Keycode::KC_K], Keycode::KC_ESCAPE);
+/// assert_eq!(combo.raw_keys(), [0x000d, 0x000e, 0, 0]);
+/// assert_eq!(combo.output(), Some(Keycode::KC_ESCAPE));
+/// ```
+#[cfg_attr(feature = "python", pyclass(get_all, set_all, from_py_object))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Combo {
+    keys: [u16; COMBO_KEY_COUNT],
+    output: u16,
+}
+
+/// Maximum number of trigger keys of a [`Combo`].
+pub const COMBO_KEY_COUNT: usize = 4;
+
+impl Combo {
+    /// Creates a combo from up to [`COMBO_KEY_COUNT`] trigger keycodes, ignoring any further keys.
+    pub fn new(keys: &[Keycode], output: Keycode) -> Self {
+        let keys: Vec<u16> = keys.iter().cloned().map(u16::from).collect();
+        Self::from_raw(&keys, output.into())
+    }
+
+    /// Creates a combo from up to [`COMBO_KEY_COUNT`] trigger keycodes given as 16 bit values.
+    pub fn from_raw(keys: &[u16], output: u16) -> Self {
+        let mut combo = Combo {
+            output,
+            ..Default::default()
+        };
+        for (key, keycode) in combo.keys.iter_mut().zip(keys) {
+            *key = *keycode;
+        }
+        combo
+    }
+
+    /// Returns the trigger keycodes, including unused `KC_NO` keys.
+    pub fn keys(&self) -> [Option<Keycode>; COMBO_KEY_COUNT] {
+        self.keys.map(keycode)
+    }
+
+    /// Returns the keycode sent instead of the trigger keys.
+    pub fn output(&self) -> Option<Keycode> {
+        keycode(self.output)
+    }
+
+    pub fn raw_keys(&self) -> [u16; COMBO_KEY_COUNT] {
+        self.keys
+    }
+
+    pub fn raw_output(&self) -> u16 {
+        self.output
+    }
+
+    /// Returns whether no trigger keys are assigned, which disables the combo.
+    pub fn is_empty(&self) -> bool {
+        self.keys.iter().all(|key| *key == 0)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        self.keys
+            .iter()
+            .chain([&self.output])
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    fn decode(report: &[u8], start: usize) -> Result<Self> {
+        let [key1, key2, key3, key4, output] = le_words(report, start)?;
+        Ok(Combo {
+            keys: [key1, key2, key3, key4],
+            output,
+        })
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl Combo {
+    #[new]
+    #[pyo3(signature = (keys=vec![], output=0))]
+    fn py_new(keys: Vec<u16>, output: u16) -> Self {
+        Combo::from_raw(&keys, output)
+    }
+
+    #[pyo3(name = "is_empty")]
+    fn py_is_empty(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+/// Returns a combo entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetCombo {
+    pub index: u8,
+}
+
+impl Request for GetCombo {
+    type Response = Combo;
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = dynamic_entry_op(VialDynamicEntryOp::ComboGet);
+        bytes.push(self.index);
+        bytes
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<Combo> {
+        dynamic_entry_status(report)?;
+        Combo::decode(report, 1)
+    }
+}
+
+/// Sets a combo entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetCombo {
+    pub index: u8,
+    pub combo: Combo,
+}
+
+impl Request for SetCombo {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = dynamic_entry_op(VialDynamicEntryOp::ComboSet);
+        bytes.push(self.index);
+        bytes.extend(self.combo.encode());
+        bytes
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<()> {
+        dynamic_entry_status(report)
+    }
+}
+
+/// A key override entry, sending `replacement` instead of `trigger` while modifiers are held.
+///
+/// Modifier masks have one bit per modifier, from left control in bit 0 over left shift, left
+/// alt, left GUI and right control to right GUI in bit 7, see the `MOD_*` constants. `layers`
+/// has one bit per layer the override is active on. Keycodes without a [`Keycode`] variant are
+/// handled as in [`TapDance`].
+///
+/// ```
+/// use qmk_via_api::keycodes::Keycode;
+/// use qmk_via_api::vial::KeyOverride;
+///
+/// // Shift + Backspace sends Delete
+/// let mut key_override = KeyOverride::new(Keycode::KC_BACKSPACE, Keycode::KC_DELETE);
+/// key_override.trigger_mods = KeyOverride::MOD_LSFT | KeyOverride::MOD_RSFT;
+/// key_override.suppressed_mods = KeyOverride::MOD_LSFT | KeyOverride::MOD_RSFT;
+/// assert!(key_override.validate().is_ok());
+/// assert_eq!(key_override.replacement(), Some(Keycode::KC_DELETE));
+/// ```
+#[cfg_attr(feature = "python", pyclass(get_all, set_all, from_py_object))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeyOverride {
+    trigger: u16,
+    replacement: u16,
+    /// Layers the override is active on, one bit per layer
+    pub layers: u16,
+    /// Modifiers of which at least one must be held to activate the override
+    pub trigger_mods: u8,
+    /// Modifiers that prevent the override while held
+    pub negative_mod_mask: u8,
+    /// Modifiers released while the override is active
+    pub suppressed_mods: u8,
+    /// Option flags, see the `OPTION_*` constants
+    pub options: u8,
+}
+
+impl KeyOverride {
+    pub const MOD_LCTL: u8 = 1 << 0;
+    pub const MOD_LSFT: u8 = 1 << 1;
+    pub const MOD_LALT: u8 = 1 << 2;
+    pub const MOD_LGUI: u8 = 1 << 3;
+    pub const MOD_RCTL: u8 = 1 << 4;
+    pub const MOD_RSFT: u8 = 1 << 5;
+    pub const MOD_RALT: u8 = 1 << 6;
+    pub const MOD_RGUI: u8 = 1 << 7;
+
+    /// Activate when the trigger key is pressed down.
+    pub const OPTION_ACTIVATION_TRIGGER_DOWN: u8 = 1 << 0;
+    /// Activate when a required modifier is pressed down.
+    pub const OPTION_ACTIVATION_REQUIRED_MOD_DOWN: u8 = 1 << 1;
+    /// Activate when a negative modifier is released.
+    pub const OPTION_ACTIVATION_NEGATIVE_MOD_UP: u8 = 1 << 2;
+    /// Activate only if exactly one of the trigger modifiers is held.
+    pub const OPTION_ONE_MOD: u8 = 1 << 3;
+    /// Do not register the trigger again when the override deactivates.
+    pub const OPTION_NO_REREGISTER_TRIGGER: u8 = 1 << 4;
+    /// Keep the replacement registered when another key is pressed.
+    pub const OPTION_NO_UNREGISTER_ON_OTHER_KEY_DOWN: u8 = 1 << 5;
+    /// Whether the override is enabled.
+    pub const OPTION_ENABLED: u8 = 1 << 7;
+
+    /// Option flags QMK uses by default: all activations.
+    pub const OPTIONS_DEFAULT: u8 = Self::OPTION_ACTIVATION_TRIGGER_DOWN
+        | Self::OPTION_ACTIVATION_REQUIRED_MOD_DOWN
+        | Self::OPTION_ACTIVATION_NEGATIVE_MOD_UP;
+
+    /// Creates an enabled override on all layers with the default options and no modifiers.
+    pub fn new(trigger: Keycode, replacement: Keycode) -> Self {
+        Self::from_raw(trigger.into(), replacement.into())
+    }
+
+    /// Creates an override like [`KeyOverride::new`] from 16 bit keycodes.
+    pub fn from_raw(trigger: u16, replacement: u16) -> Self {
+        KeyOverride {
+            trigger,
+            replacement,
+            layers: u16::MAX,
+            options: Self::OPTION_ENABLED | Self::OPTIONS_DEFAULT,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the keycode that triggers the override, or `KC_NO` to trigger on the modifiers
+    /// alone.
+    pub fn trigger(&self) -> Option<Keycode> {
+        keycode(self.trigger)
+    }
+
+    /// Returns the keycode sent instead of the trigger.
+    pub fn replacement(&self) -> Option<Keycode> {
+        keycode(self.replacement)
+    }
+
+    pub fn raw_trigger(&self) -> u16 {
+        self.trigger
+    }
+
+    pub fn raw_replacement(&self) -> u16 {
+        self.replacement
+    }
+
+    /// Returns whether the override is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.options & Self::OPTION_ENABLED != 0
+    }
+
+    /// Checks that the firmware can store the override and that an enabled override can activate.
+    pub fn validate(&self) -> Result<()> {
+        // Bit 6 is not assigned
+        if self.options & 1 << 6 != 0 {
+            return Err(Error::InvalidArgument("key override options"));
+        }
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        if self.layers == 0 {
+            return Err(Error::InvalidArgument("key override without layers"));
+        }
+        if self.trigger == 0 && self.trigger_mods == 0 {
+            return Err(Error::InvalidArgument(
+                "key override without trigger key and modifiers",
+            ));
+        }
+        if self.trigger_mods & self.negative_mod_mask != 0 {
+            return Err(Error::InvalidArgument(
+                "key override modifiers both required and negative",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl KeyOverride {
+    #[new]
+    #[pyo3(signature = (trigger=0, replacement=0))]
+    fn py_new(trigger: u16, replacement: u16) -> Self {
+        KeyOverride::from_raw(trigger, replacement)
+    }
+
+    #[pyo3(name = "is_enabled")]
+    fn py_is_enabled(&self) -> bool {
+        self.is_enabled()
+    }
+
+    #[pyo3(name = "validate")]
+    fn py_validate(&self) -> Result<()> {
+        self.validate()
+    }
+}
+
+impl KeyOverride {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = [self.trigger, self.replacement, self.layers]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        bytes.extend([
+            self.trigger_mods,
+            self.negative_mod_mask,
+            self.suppressed_mods,
+            self.options,
+        ]);
+        bytes
+    }
+
+    fn decode(report: &[u8], start: usize) -> Result<Self> {
+        let [trigger, replacement, layers] = le_words(report, start)?;
+        let [trigger_mods, negative_mod_mask, suppressed_mods, options] =
+            protocol::bytes(report, start + 6, 4)?
+                .try_into()
+                .expect("4 bytes");
+        Ok(KeyOverride {
+            trigger,
+            replacement,
+            layers,
+            trigger_mods,
+            negative_mod_mask,
+            suppressed_mods,
+            options,
+        })
+    }
+}
+
+/// Returns a key override entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetKeyOverride {
+    pub index: u8,
+}
+
+impl Request for GetKeyOverride {
+    type Response = KeyOverride;
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = dynamic_entry_op(VialDynamicEntryOp::KeyOverrideGet);
+        bytes.push(self.index);
+        bytes
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<KeyOverride> {
+        dynamic_entry_status(report)?;
+        KeyOverride::decode(report, 1)
+    }
+}
+
+/// Sets a key override entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetKeyOverride {
+    pub index: u8,
+    pub key_override: KeyOverride,
+}
+
+impl Request for SetKeyOverride {
+    type Response = ();
+    const COMMAND: ViaCommandId = ViaCommandId::VialPrefix;
+    const ECHOES: bool = false;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = dynamic_entry_op(VialDynamicEntryOp::KeyOverrideSet);
+        bytes.push(self.index);
+        bytes.extend(self.key_override.encode());
+        bytes
+    }
+
+    fn decode(&self, report: &[u8]) -> Result<()> {
+        dynamic_entry_status(report)
+    }
+}
+
+/// Decompresses and parses a keyboard definition as stored by Vial firmware.
+#[cfg(feature = "vial-definition")]
+pub fn parse_definition(compressed: &[u8]) -> Result<serde_json::Value> {
+    let mut json = Vec::new();
+    lzma_rs::lzma_decompress(&mut &compressed[..], &mut json)
+        .map_err(|err| Error::InvalidVialDefinition(err.to_string()))?;
+    serde_json::from_slice(&json).map_err(|err| Error::InvalidVialDefinition(err.to_string()))
+}
+
+/// Reads the matrix size from a keyboard definition.
+#[cfg(feature = "vial-definition")]
+pub fn definition_matrix_info(definition: &serde_json::Value) -> Result<MatrixInfo> {
+    let dimension = |name: &str| {
+        definition["matrix"][name]
+            .as_u64()
+            .and_then(|value| u8::try_from(value).ok())
+            .ok_or_else(|| Error::InvalidVialDefinition(format!("missing matrix.{}", name)))
+    };
+    Ok(MatrixInfo {
+        rows: dimension("rows")?,
+        cols: dimension("cols")?,
+    })
+}
+
+#[cfg_attr(feature = "python", pymethods)]
+impl KeyboardApi {
+    /// Returns the Vial protocol version and keyboard id.
+    ///
+    /// Fails with an unsupported command error if the keyboard does not run Vial.
+    pub fn get_vial_keyboard_id(&self) -> Result<VialKeyboardId> {
+        let _call = trace::enter_call("get_vial_keyboard_id");
+        self.vial_keyboard_id()?.ok_or(Error::UnsupportedCommand {
+            command: GetKeyboardId::COMMAND,
+            channel: None,
+        })
+    }
+
+    /// Returns the LZMA compressed keyboard definition stored in Vial firmware.
+    pub fn get_vial_definition_compressed(&self) -> Result<Vec<u8>> {
+        let _call = trace::enter_call("get_vial_definition_compressed");
+        let size = self.request(&GetSize)? as usize;
+        let page_count = size.div_ceil(DEFINITION_PAGE_SIZE);
+        if page_count > u16::MAX as usize + 1 {
+            return Err(Error::size_mismatch(
+                "Vial definition too large",
+                (u16::MAX as usize + 1) * DEFINITION_PAGE_SIZE,
+                size,
+            ));
+        }
+
+        let mut compressed = Vec::with_capacity(page_count * DEFINITION_PAGE_SIZE);
+        for page in 0..page_count {
+            compressed.extend(self.request(&GetDefinition { page: page as u16 })?);
+            #[cfg(feature = "tracing")]
+            tracing::debug!(page, page_count, "read Vial definition page");
+        }
+        compressed.truncate(size);
+        Ok(compressed)
+    }
+
+    /// Returns the number of dynamic entries of each kind.
+    ///
+    /// Fails with an unsupported feature error if the Vial protocol predates dynamic entries.
+    pub fn get_vial_entry_counts(&self) -> Result<VialEntryCounts> {
+        let _call = trace::enter_call("get_vial_entry_counts");
+        if self.get_vial_keyboard_id()?.protocol_version < VIAL_PROTOCOL_DYNAMIC {
+            return Err(Error::UnsupportedFeature("Vial dynamic entries"));
+        }
+        self.request(&GetEntryCounts)
+    }
+
+    /// Returns the number of tap dance entries.
+    pub fn get_tap_dance_count(&self) -> Result<u8> {
+        let _call = trace::enter_call("get_tap_dance_count");
+        Ok(self.get_vial_entry_counts()?.tap_dance)
+    }
+
+    /// Returns the tap dance entry at the given index.
+    pub fn get_tap_dance(&self, index: u8) -> Result<TapDance> {
+        let _call = trace::enter_call("get_tap_dance");
+        self.request(&GetTapDance { index })
+    }
+
+    /// Returns all tap dance entries.
+    pub fn get_tap_dances(&self) -> Result<Vec<TapDance>> {
+        let _call = trace::enter_call("get_tap_dances");
+        (0..self.get_tap_dance_count()?)
+            .map(|index| self.request(&GetTapDance { index }))
+            .collect()
+    }
+
+    /// Sets the tap dance entry at the given index.
+    pub fn set_tap_dance(&self, index: u8, tap_dance: TapDance) -> Result<()> {
+        let _call = trace::enter_call("set_tap_dance");
+        self.request(&SetTapDance { index, tap_dance })
+    }
+
+    /// Returns the number of combo entries.
+    pub fn get_combo_count(&self) -> Result<u8> {
+        let _call = trace::enter_call("get_combo_count");
+        Ok(self.get_vial_entry_counts()?.combo)
+    }
+
+    /// Returns the combo entry at the given index.
+    pub fn get_combo(&self, index: u8) -> Result<Combo> {
+        let _call = trace::enter_call("get_combo");
+        self.request(&GetCombo { index })
+    }
+
+    /// Returns all combo entries, including empty ones.
+    pub fn get_combos(&self) -> Result<Vec<Combo>> {
+        let _call = trace::enter_call("get_combos");
+        (0..self.get_combo_count()?)
+            .map(|index| self.request(&GetCombo { index }))
+            .collect()
+    }
+
+    /// Sets the combo entry at the given index.
+    pub fn set_combo(&self, index: u8, combo: Combo) -> Result<()> {
+        let _call = trace::enter_call("set_combo");
+        self.request(&SetCombo { index, combo })
+    }
+
+    /// Disables the combo entry at the given index by clearing its keys.
+    pub fn clear_combo(&self, index: u8) -> Result<()> {
+        let _call = trace::enter_call("clear_combo");
+        self.set_combo(index, Combo::default())
+    }
+
+    /// Returns the number of key override entries.
+    pub fn get_key_override_count(&self) -> Result<u8> {
+        let _call = trace::enter_call("get_key_override_count");
+        Ok(self.get_vial_entry_counts()?.key_override)
+    }
+
+    /// Returns the key override entry at the given index.
+    pub fn get_key_override(&self, index: u8) -> Result<KeyOverride> {
+        let _call = trace::enter_call("get_key_override");
+        self.request(&GetKeyOverride { index })
+    }
+
+    /// Returns all key override entries, including disabled ones.
+    pub fn get_key_overrides(&self) -> Result<Vec<KeyOverride>> {
+        let _call = trace::enter_call("get_key_overrides");
+        (0..self.get_key_override_count()?)
+            .map(|index| self.request(&GetKeyOverride { index }))
+            .collect()
+    }
+
+    /// Validates and sets the key override entry at the given index.
+    ///
+    /// Besides [`KeyOverride::validate`], enabled overrides must be active on at least one of the
+    /// layers of the keyboard.
+    pub fn set_key_override(&self, index: u8, key_override: KeyOverride) -> Result<()> {
+        let _call = trace::enter_call("set_key_override");
+        key_override.validate()?;
+        let keyboard_layers = (1u32 << self.get_layer_count()?.min(16)) - 1;
+        if key_override.is_enabled() && key_override.layers as u32 & keyboard_layers == 0 {
+            return Err(Error::InvalidArgument("key override layers"));
+        }
+        self.request(&SetKeyOverride {
+            index,
+            key_override,
+        })
+    }
+
+    /// Returns the matrix size from the keyboard definition stored in Vial firmware.
+    #[cfg(feature = "vial-definition")]
+    pub fn get_vial_matrix_info(&self) -> Result<MatrixInfo> {
+        let _call = trace::enter_call("get_vial_matrix_info");
+        definition_matrix_info(&self.get_vial_definition()?)
+    }
+}
+
+impl KeyboardApi {
+    /// Downloads, decompresses and parses the keyboard definition stored in Vial firmware.
+    ///
+    /// The definition is the JSON keyboard description also used by VIA, including the matrix
+    /// size and the layout.
+    #[cfg(feature = "vial-definition")]
+    pub fn get_vial_definition(&self) -> Result<serde_json::Value> {
+        let _call = trace::enter_call("get_vial_definition");
+        parse_definition(&self.get_vial_definition_compressed()?)
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl KeyboardApi {
+    /// Downloads, decompresses and parses the keyboard definition stored in Vial firmware.
+    #[pyo3(name = "get_vial_definition")]
+    fn py_get_vial_definition<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let definition = self.get_vial_definition()?;
+        py.import("json")?
+            .call_method1("loads", (definition.to_string(),))
+    }
+}